@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use shared::requests::{Filter, IdMapping, ImageFilter, IoLimit, Resources, SortKey};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -9,6 +10,14 @@ use clap::{Parser, Subcommand};
     disable_help_subcommand = true
 )]
 pub struct ClapCli {
+    #[arg(
+        long,
+        global = true,
+        env = "J1407B_SOCKET",
+        help = "The socket of the daemon [default: $XDG_RUNTIME_DIR/j1407b.sock]"
+    )]
+    pub socket: Option<String>,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -20,4 +29,409 @@ pub enum Commands {
         #[arg(index = 1, help = "The image to pull")]
         image: String,
     },
+
+    #[command(about = "Create a container from an image and start a command in it")]
+    Run {
+        #[arg(long, help = "The name of the container [default: its short id]")]
+        name: Option<String>,
+
+        #[arg(long, help = "The hostname of the container [default: its short id]")]
+        hostname: Option<String>,
+
+        #[arg(
+            short,
+            long = "env",
+            value_name = "KEY=VALUE",
+            help = "Set an environment variable"
+        )]
+        env: Vec<String>,
+
+        #[arg(
+            long = "uidmap",
+            value_name = "CONTAINER:HOST:SIZE",
+            help = "Map user ids of the container to user ids of the host [default: root to the daemon user]"
+        )]
+        uid_map: Vec<IdMapping>,
+
+        #[arg(
+            long = "gidmap",
+            value_name = "CONTAINER:HOST:SIZE",
+            help = "Map group ids of the container to group ids of the host [default: root to the daemon group]"
+        )]
+        gid_map: Vec<IdMapping>,
+
+        #[arg(
+            short,
+            long = "label",
+            value_name = "KEY=VALUE",
+            value_parser = parse_label,
+            help = "Set a label on the container"
+        )]
+        labels: Vec<(String, String)>,
+
+        #[arg(
+            short,
+            long = "volume",
+            value_name = "PATH",
+            help = "Mount a new volume at an absolute path of the container"
+        )]
+        volumes: Vec<String>,
+
+        #[command(flatten)]
+        limits: Box<Limits>,
+
+        #[arg(
+            index = 1,
+            help = "The image to create the container from, by reference, digest or id prefix"
+        )]
+        image: String,
+
+        #[arg(
+            index = 2,
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            help = "The command to run and its arguments"
+        )]
+        command: Vec<String>,
+    },
+
+    #[command(about = "List containers")]
+    Ps {
+        #[arg(short, long, help = "List exited containers too")]
+        all: bool,
+
+        #[arg(
+            short,
+            long = "filter",
+            value_name = "KIND=VALUE",
+            help = "Only list containers matching status=, label=, image= or name="
+        )]
+        filters: Vec<Filter>,
+
+        #[arg(
+            long,
+            value_name = "FIELD",
+            help = "Sort by created, name, image or status [default: newest first]"
+        )]
+        sort: Option<SortKey>,
+
+        #[arg(long, help = "Reverse the order")]
+        reverse: bool,
+    },
+
+    #[command(about = "List images")]
+    Images {
+        #[arg(
+            short,
+            long = "filter",
+            value_name = "KIND=VALUE",
+            help = "Only list images matching dangling=, reference= or label="
+        )]
+        filters: Vec<ImageFilter>,
+
+        #[arg(long, help = "Show the digests of the images")]
+        digests: bool,
+
+        #[arg(long, help = "Show the full image ids")]
+        no_trunc: bool,
+    },
+
+    #[command(about = "Tag an image with another reference")]
+    Tag {
+        #[arg(index = 1, help = "The image by reference, digest or id prefix")]
+        source: String,
+
+        #[arg(
+            index = 2,
+            help = "The new reference, e.g. registry.local/team/app:1.2"
+        )]
+        target: String,
+    },
+
+    #[command(about = "Remove a tag from an image")]
+    Untag {
+        #[arg(index = 1, help = "The tag to remove")]
+        reference: String,
+    },
+
+    #[command(about = "Stop a running container")]
+    Stop {
+        #[arg(
+            short,
+            long,
+            help = "The signal sent to the container, e.g. SIGINT [default: the daemon's stop signal]"
+        )]
+        signal: Option<String>,
+
+        #[arg(
+            short,
+            long = "time",
+            value_name = "SECONDS",
+            help = "The time the container gets to exit before it is killed [default: the daemon's stop timeout]"
+        )]
+        timeout: Option<u64>,
+
+        #[arg(index = 1, help = "The container by id, id prefix or name")]
+        container: String,
+    },
+
+    #[command(about = "Remove containers")]
+    Rm {
+        #[arg(short, long, help = "Stop running containers before removing them")]
+        force: bool,
+
+        #[arg(short, long, help = "Remove the volumes of the containers too")]
+        volumes: bool,
+
+        #[arg(
+            index = 1,
+            required = true,
+            help = "The containers by id, id prefix or name"
+        )]
+        containers: Vec<String>,
+    },
+
+    #[command(about = "Show the output of a container")]
+    Logs {
+        #[arg(
+            short,
+            long,
+            help = "Keep showing new output until the container exits"
+        )]
+        follow: bool,
+
+        #[arg(
+            short = 'n',
+            long,
+            value_name = "LINES",
+            help = "Only show the last lines [default: all]"
+        )]
+        tail: Option<usize>,
+
+        #[arg(
+            long,
+            value_name = "TIME",
+            help = "Only show output written since an RFC 3339 time, a unix timestamp or a duration ago like 10m"
+        )]
+        since: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "TIME",
+            help = "Only show output written before an RFC 3339 time, a unix timestamp or a duration ago like 10m"
+        )]
+        until: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Prefix every line with the time it was written at"
+        )]
+        timestamps: bool,
+
+        #[arg(index = 1, help = "The container by id, id prefix or name")]
+        container: String,
+    },
+
+    #[command(about = "Show the resource usage of containers")]
+    Stats {
+        #[arg(long, help = "Keep sampling until the containers exit")]
+        stream: bool,
+
+        #[arg(
+            long,
+            value_name = "MILLISECONDS",
+            help = "The time between two samples when streaming [default: 1000]"
+        )]
+        interval: Option<u64>,
+
+        #[arg(
+            index = 1,
+            help = "The containers by id, id prefix or name [default: all running containers]"
+        )]
+        containers: Vec<String>,
+    },
+
+    #[command(about = "Show or change the log level of the daemon")]
+    LogLevel {
+        #[arg(
+            index = 1,
+            value_parser = ["off", "error", "warn", "info", "debug", "trace"],
+            help = "The new log level, kept until the daemon reloads its configuration"
+        )]
+        level: Option<String>,
+    },
+}
+
+/// The resource limits of `run`.
+#[derive(Args, Debug)]
+pub struct Limits {
+    #[arg(
+        short,
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Limit the memory, in bytes or with a k, m or g suffix"
+    )]
+    memory: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = parse_cpus,
+        help = "Limit the CPU time to as many CPUs, e.g. 1.5"
+    )]
+    cpus: Option<f64>,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..=10000),
+        help = "The share of CPU time relative to other containers [default: 100]"
+    )]
+    cpu_weight: Option<u64>,
+
+    #[arg(long, value_name = "N", help = "Limit the number of processes")]
+    pids_limit: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..=10000),
+        help = "The share of IO relative to other containers [default: 100]"
+    )]
+    io_weight: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "DEVICE:SIZE",
+        value_parser = parse_device_size,
+        help = "Limit the bytes read from a device per second"
+    )]
+    device_read_bps: Vec<(String, u64)>,
+
+    #[arg(
+        long,
+        value_name = "DEVICE:SIZE",
+        value_parser = parse_device_size,
+        help = "Limit the bytes written to a device per second"
+    )]
+    device_write_bps: Vec<(String, u64)>,
+
+    #[arg(
+        long,
+        value_name = "DEVICE:N",
+        value_parser = parse_device_count,
+        help = "Limit the read operations on a device per second"
+    )]
+    device_read_iops: Vec<(String, u64)>,
+
+    #[arg(
+        long,
+        value_name = "DEVICE:N",
+        value_parser = parse_device_count,
+        help = "Limit the write operations on a device per second"
+    )]
+    device_write_iops: Vec<(String, u64)>,
+}
+
+/// The CPU period `--cpus` is applied over, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+impl Limits {
+    /// The limits as requested from the daemon.
+    pub fn resources(&self) -> Resources {
+        let mut io_limits = Vec::new();
+        for (device, rate) in &self.device_read_bps {
+            io_limit(&mut io_limits, device).read_bps = Some(*rate);
+        }
+        for (device, rate) in &self.device_write_bps {
+            io_limit(&mut io_limits, device).write_bps = Some(*rate);
+        }
+        for (device, rate) in &self.device_read_iops {
+            io_limit(&mut io_limits, device).read_iops = Some(*rate);
+        }
+        for (device, rate) in &self.device_write_iops {
+            io_limit(&mut io_limits, device).write_iops = Some(*rate);
+        }
+
+        Resources {
+            memory: self.memory,
+            cpu_weight: self.cpu_weight,
+            cpu_quota: self
+                .cpus
+                .map(|cpus| (cpus * CPU_PERIOD as f64).round() as u64),
+            cpu_period: self.cpus.map(|_| CPU_PERIOD),
+            pids: self.pids_limit,
+            io_weight: self.io_weight,
+            io_limits,
+        }
+    }
+}
+
+/// The limits of `device`, added unless some are set already.
+fn io_limit<'a>(limits: &'a mut Vec<IoLimit>, device: &str) -> &'a mut IoLimit {
+    let index = match limits.iter().position(|limit| limit.device == device) {
+        Some(index) => index,
+        None => {
+            limits.push(IoLimit {
+                device: device.to_string(),
+                ..Default::default()
+            });
+            limits.len() - 1
+        }
+    };
+    &mut limits[index]
+}
+
+/// Parse a `KEY=VALUE` label. The value may be left out.
+fn parse_label(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value.split_once('=').unwrap_or((value, ""));
+    match key.is_empty() {
+        true => Err("a label needs a key".to_string()),
+        false => Ok((key.to_string(), value.to_string())),
+    }
+}
+
+/// Parse a size in bytes, optionally with a binary `k`, `m` or `g` suffix.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("{} is not a size", value);
+    let lower = value.to_ascii_lowercase();
+    let (number, unit) = match lower.trim_end_matches('b').char_indices().last() {
+        Some((index, 'k')) => (&lower[..index], 1 << 10),
+        Some((index, 'm')) => (&lower[..index], 1 << 20),
+        Some((index, 'g')) => (&lower[..index], 1 << 30),
+        _ => (lower.as_str(), 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(invalid)
+}
+
+fn parse_cpus(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => Ok(cpus),
+        _ => Err(format!("{} is not a positive number of CPUs", value)),
+    }
+}
+
+/// Split `DEVICE:VALUE`, the device may be given as `MAJOR:MINOR` itself.
+fn split_device(value: &str) -> Result<(String, &str), String> {
+    match value.rsplit_once(':') {
+        Some((device, rate)) if !device.is_empty() => Ok((device.to_string(), rate)),
+        _ => Err(format!("{} is not a DEVICE:VALUE pair", value)),
+    }
+}
+
+fn parse_device_size(value: &str) -> Result<(String, u64), String> {
+    let (device, rate) = split_device(value)?;
+    Ok((device, parse_size(rate)?))
+}
+
+fn parse_device_count(value: &str) -> Result<(String, u64), String> {
+    let (device, rate) = split_device(value)?;
+    let rate = rate
+        .parse::<u64>()
+        .map_err(|_| format!("{} is not a number", rate))?;
+    Ok((device, rate))
 }
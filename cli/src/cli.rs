@@ -3,78 +3,457 @@ use crate::{
     error::CliError,
 };
 use clap::Parser;
-use nix::{
-    sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, UnixAddr},
-    unistd::write,
-};
 use shared::{
+    client::{Client, Event},
     error::SharedError,
-    protocol::Protocol,
-    protocol::{Command, Type},
-    requests::PullRequest,
+    protocol::Output,
+    reference::Reference,
+    requests::{
+        ContainerStats, ContainerSummary, ImagesRequest, LogsRequest, PsRequest, RmRequest,
+        RunRequest, StatsRequest, StatsResponse, StopRequest, TagRequest, UntagRequest,
+    },
+    utils,
+};
+use std::{
+    io::{self, IsTerminal, Write},
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::os::fd::{AsRawFd, OwnedFd};
 
 pub struct Cli {
     pub cli: ClapCli,
-    pub socket_fd: OwnedFd,
+    pub client: Client,
 }
 
-const DAEMON_SOCKET: &str = "/tmp/j1047b.sock";
-
 impl Cli {
     /// create a new Cli instance
     pub fn new() -> Result<Self, CliError> {
-        // create a new socket address (sockaddr_un)
-        let socket_addr = UnixAddr::new(DAEMON_SOCKET.as_bytes())
-            .map_err(|e| SharedError::CreateUnixAddr { errno: e })?;
-
-        println!("Socket address: {:?}", socket_addr);
-
-        // create a new socket
-        let socket_fd = socket(
-            AddressFamily::Unix,
-            SockType::Stream,
-            SockFlag::empty(),
-            None,
-        )
-        .map_err(|e| SharedError::CreateSocket { errno: e })?;
+        let cli = ClapCli::parse();
 
-        println!("Socket file descriptor: {:?}", socket_fd.as_raw_fd());
+        // connect to the daemon and negotiate the protocol.
+        let socket = match &cli.socket {
+            Some(socket) => socket.clone(),
+            None => utils::default_socket_path(),
+        };
+        let client = Client::connect(socket)?;
 
-        // connect to the socket fd.
-        connect(socket_fd.as_raw_fd(), &socket_addr).map_err(|e| CliError::ConnectSocket {
-            addr: socket_addr,
-            errno: e,
-        })?;
-
-        Ok(Cli {
-            cli: ClapCli::parse(),
-            socket_fd: socket_fd,
-        })
+        Ok(Cli { cli, client })
     }
 
     /// execute a command based on the parsed CLI arguments.
     pub fn execute(&mut self) -> Result<(), CliError> {
         match &self.cli.command {
             Some(Commands::Pull { image }) => self.pull(image.clone()),
+            Some(Commands::Run {
+                name,
+                hostname,
+                env,
+                uid_map,
+                gid_map,
+                labels,
+                volumes,
+                limits,
+                image,
+                command,
+            }) => {
+                let request = RunRequest {
+                    image: image.clone(),
+                    command: command.clone(),
+                    name: name.clone(),
+                    hostname: hostname.clone(),
+                    env: env.clone(),
+                    uid_map: uid_map.clone(),
+                    gid_map: gid_map.clone(),
+                    resources: limits.resources(),
+                    labels: labels.iter().cloned().collect(),
+                    volumes: volumes.clone(),
+                };
+                self.run(request)
+            }
+            Some(Commands::Ps {
+                all,
+                filters,
+                sort,
+                reverse,
+            }) => {
+                let request = PsRequest {
+                    all: *all,
+                    filters: filters.clone(),
+                    sort: *sort,
+                    reverse: *reverse,
+                };
+                self.ps(request)
+            }
+            Some(Commands::Images {
+                filters,
+                digests,
+                no_trunc,
+            }) => {
+                let request = ImagesRequest {
+                    filters: filters.clone(),
+                };
+                self.images(request, *digests, *no_trunc)
+            }
+            Some(Commands::Tag { source, target }) => {
+                let request = TagRequest {
+                    source: source.clone(),
+                    target: target.clone(),
+                };
+                self.tag(request)
+            }
+            Some(Commands::Untag { reference }) => {
+                let request = UntagRequest {
+                    reference: reference.clone(),
+                };
+                self.untag(request)
+            }
+            Some(Commands::Stop {
+                signal,
+                timeout,
+                container,
+            }) => {
+                let request = StopRequest {
+                    container: container.clone(),
+                    signal: signal.clone(),
+                    timeout: *timeout,
+                };
+                self.stop(request)
+            }
+            Some(Commands::Rm {
+                force,
+                volumes,
+                containers,
+            }) => {
+                let request = RmRequest {
+                    containers: containers.clone(),
+                    force: *force,
+                    volumes: *volumes,
+                };
+                self.rm(request)
+            }
+            Some(Commands::Stats {
+                stream,
+                interval,
+                containers,
+            }) => {
+                let request = StatsRequest {
+                    containers: containers.clone(),
+                    stream: *stream,
+                    interval: *interval,
+                };
+                self.stats(request)
+            }
+            Some(Commands::Logs {
+                follow,
+                tail,
+                since,
+                until,
+                timestamps,
+                container,
+            }) => {
+                let request = LogsRequest {
+                    container: container.clone(),
+                    follow: *follow,
+                    tail: *tail,
+                    since: since.clone(),
+                    until: until.clone(),
+                    timestamps: *timestamps,
+                };
+                self.logs(request)
+            }
+            Some(Commands::LogLevel { level }) => self.log_level(level.clone()),
             None => Ok(()),
         }
     }
 
-    /// `pull`: Send a pull command to the daemon over the socket connection
-    /// with the image name as the payload.
+    /// `pull`: Ask the daemon to pull an image from the registry and render
+    /// its progress while waiting.
     fn pull(&mut self, image: String) -> Result<(), CliError> {
-        let request = PullRequest { image };
-        let body_bytes = Protocol::write_body::<PullRequest>(request)?;
-        let header_bytes =
-            Protocol::write_header(Type::Request, Command::Pull, body_bytes.len() as u64)?;
-        let message = Protocol::write_message(header_bytes, body_bytes);
-        write(self.socket_fd.as_raw_fd(), &message).map_err(|e| CliError::WriteSocket {
-            fd: self.socket_fd.as_raw_fd(),
-            errno: e,
-        })?;
+        let response = self.client.pull(&image)?.wait(render)?;
+        println!("Pulled {}", response.image);
+        Ok(())
+    }
+
+    /// `run`: Ask the daemon to create and start a container and print its id.
+    fn run(&mut self, request: RunRequest) -> Result<(), CliError> {
+        let response = self.client.run(request)?;
+        println!("{}", response.id);
+        Ok(())
+    }
+
+    /// `ps`: Print the containers listed by the daemon as a table.
+    fn ps(&mut self, request: PsRequest) -> Result<(), CliError> {
+        let response = self.client.ps(request)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        println!(
+            "{:<12}  {:<16}  {:<24}  {:<16}  {:<24}  NAME",
+            "CONTAINER ID", "IMAGE", "COMMAND", "CREATED", "STATUS"
+        );
+        for container in &response.containers {
+            println!(
+                "{:<12}  {:<16}  {:<24}  {:<16}  {:<24}  {}",
+                &container.id[..container.id.len().min(12)],
+                container.image,
+                truncate(&format!("\"{}\"", container.command.join(" ")), 24),
+                format!("{} ago", duration(now.saturating_sub(container.created))),
+                status(container, now),
+                container.name
+            );
+        }
+        Ok(())
+    }
+
+    /// `images`: Print the images listed by the daemon as a table, one row per tag.
+    fn images(
+        &mut self,
+        request: ImagesRequest,
+        digests: bool,
+        no_trunc: bool,
+    ) -> Result<(), CliError> {
+        let response = self.client.images(request)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let digest_column = match digests {
+            true => format!("{:<71}  ", "DIGEST"),
+            false => String::new(),
+        };
+        println!(
+            "{:<32}  {:<16}  {}{:<12}  {:<16}  SIZE",
+            "REPOSITORY", "TAG", digest_column, "IMAGE ID", "CREATED"
+        );
+        for image in &response.images {
+            let hex = image
+                .digest
+                .strip_prefix("sha256:")
+                .unwrap_or(&image.digest);
+            let id = match no_trunc {
+                true => image.digest.clone(),
+                false => hex[..hex.len().min(12)].to_string(),
+            };
+            let digest = match digests {
+                true => format!("{:<71}  ", image.digest),
+                false => String::new(),
+            };
+            let mut references = image
+                .tags
+                .iter()
+                .map(|tag| split_reference(tag))
+                .collect::<Vec<_>>();
+            if references.is_empty() {
+                references.push(("<none>".to_string(), "<none>".to_string()));
+            }
+            for (repository, tag) in references {
+                println!(
+                    "{:<32}  {:<16}  {}{:<12}  {:<16}  {}",
+                    repository,
+                    tag,
+                    digest,
+                    id,
+                    format!("{} ago", duration(now.saturating_sub(image.created))),
+                    format_size(image.size)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// `tag`: Ask the daemon to tag an image and print the new reference.
+    fn tag(&mut self, request: TagRequest) -> Result<(), CliError> {
+        let response = self.client.tag(request)?;
+        println!("Tagged {} as {}", response.digest, response.reference);
+        Ok(())
+    }
+
+    /// `untag`: Ask the daemon to remove a tag from an image.
+    fn untag(&mut self, request: UntagRequest) -> Result<(), CliError> {
+        let response = self.client.untag(request)?;
+        println!("Untagged {} from {}", response.reference, response.digest);
+        Ok(())
+    }
+
+    /// `stop`: Ask the daemon to stop a container and print how it exited.
+    fn stop(&mut self, request: StopRequest) -> Result<(), CliError> {
+        let response = self.client.stop(request)?;
+        match response.killed {
+            true => println!(
+                "{} was killed after the timeout, exit code {}",
+                response.id, response.code
+            ),
+            false => println!("{} exited with code {}", response.id, response.code),
+        }
+        Ok(())
+    }
+
+    /// `rm`: Ask the daemon to remove containers. Prints the id of every removed one
+    /// and the error of every other one.
+    fn rm(&mut self, request: RmRequest) -> Result<(), CliError> {
+        let response = self.client.rm(request)?;
+        let mut failed = 0;
+        for result in response.results {
+            match (result.id, result.error) {
+                (Some(id), _) => println!("{}", id),
+                (None, error) => {
+                    eprintln!("Error: {}: {}", result.container, error.unwrap_or_default());
+                    failed += 1;
+                }
+            }
+        }
+        match failed {
+            0 => Ok(()),
+            count => Err(CliError::Remove { count }),
+        }
+    }
+
+    /// `stats`: Print the resource usage of containers as a table. When streaming, every
+    /// sample replaces the previous one on a terminal.
+    fn stats(&mut self, request: StatsRequest) -> Result<(), CliError> {
+        let clear = request.stream && io::stdout().is_terminal();
+        let mut stream = self.client.stats(request)?;
+        while let Some(event) = stream.next_event()? {
+            if let Event::Stats(sample) = event {
+                print_stats(&sample, clear);
+            }
+        }
+        print_stats(&stream.finish()?, clear);
+        Ok(())
+    }
 
+    /// `logs`: Print the output of a container to the stream it was written to.
+    fn logs(&mut self, request: LogsRequest) -> Result<(), CliError> {
+        self.client.logs(request)?.wait(render)?;
         Ok(())
     }
+
+    /// `log-level`: Show the log level of the daemon, or change it if a level is given.
+    fn log_level(&mut self, level: Option<String>) -> Result<(), CliError> {
+        let response = self.client.log_level(level.as_deref())?;
+        println!("{}", response.level);
+        Ok(())
+    }
+}
+
+/// The status of a container as shown by `ps`.
+fn status(container: &ContainerSummary, now: u64) -> String {
+    let since = |time: Option<u64>| duration(now.saturating_sub(time.unwrap_or(now)));
+    match (container.status.as_str(), container.exit_code) {
+        ("running", _) => format!("Up {}", since(container.started)),
+        ("exited", Some(code)) => format!("Exited ({}) {} ago", code, since(container.finished)),
+        (status, _) => status.to_string(),
+    }
+}
+
+/// Split a tag of an image into the repository, as it is usually written, and the tag.
+fn split_reference(reference: &str) -> (String, String) {
+    match reference.parse::<Reference>() {
+        Ok(parsed) => (
+            parsed.short_name(),
+            parsed.tag.unwrap_or_else(|| "<none>".to_string()),
+        ),
+        Err(_) => (reference.to_string(), "<none>".to_string()),
+    }
+}
+
+/// A duration in milliseconds in its largest whole unit, e.g. `3 minutes`.
+fn duration(milliseconds: u64) -> String {
+    let seconds = milliseconds / 1000;
+    let (count, unit) = match seconds {
+        0..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    match count {
+        1 => format!("1 {}", unit),
+        _ => format!("{} {}s", count, unit),
+    }
+}
+
+/// Shorten `text` to at most `length` characters.
+fn truncate(text: &str, length: usize) -> String {
+    match text.chars().count() > length {
+        true => format!("{}…", text.chars().take(length - 1).collect::<String>()),
+        false => text.to_string(),
+    }
+}
+
+/// Print a sample of `stats`, after clearing the terminal if `clear`.
+fn print_stats(sample: &StatsResponse, clear: bool) {
+    if clear {
+        print!("\x1b[2J\x1b[H");
+    }
+    println!(
+        "{:<12}  {:<20}  {:>10}  {:>21}  {:>21}  {:>6}",
+        "ID", "NAME", "CPU", "MEMORY / LIMIT", "IO READ / WRITE", "PIDS"
+    );
+    for container in &sample.containers {
+        println!(
+            "{:<12}  {:<20}  {:>10}  {:>21}  {:>21}  {:>6}",
+            &container.id[..container.id.len().min(12)],
+            container.name,
+            container.cpu_usage.map_or("-".to_string(), |usage| format!(
+                "{:.2}s",
+                usage as f64 / 1e6
+            )),
+            pair(container.memory, container.memory_limit),
+            pair(container.io_read_bytes, container.io_write_bytes),
+            pids(container),
+        );
+    }
+}
+
+/// Two sizes separated by a slash, `-` for unknown ones.
+fn pair(first: Option<u64>, second: Option<u64>) -> String {
+    let size = |size: Option<u64>| size.map_or("-".to_string(), format_size);
+    format!("{} / {}", size(first), size(second))
+}
+
+fn pids(container: &ContainerStats) -> String {
+    match (container.pids, container.pids_limit) {
+        (Some(pids), Some(limit)) => format!("{}/{}", pids, limit),
+        (Some(pids), None) => pids.to_string(),
+        (None, _) => "-".to_string(),
+    }
+}
+
+/// A size in bytes with a binary unit.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", size),
+        _ => format!("{:.1}{}", value, UNITS[unit]),
+    }
+}
+
+/// Render an event streamed by the daemon. Output goes to the matching stream of
+/// the cli, progress updates to stderr and samples are printed as a table.
+fn render(event: Event) {
+    let written = match event {
+        Event::Output(Output::Stdout, data) => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&data).and_then(|_| stdout.flush())
+        }
+        Event::Output(Output::Stderr, data) => {
+            let mut stderr = io::stderr().lock();
+            stderr.write_all(&data).and_then(|_| stderr.flush())
+        }
+        Event::Progress(progress) => {
+            eprintln!("{}", progress);
+            Ok(())
+        }
+        Event::Stats(sample) => {
+            print_stats(&sample, false);
+            Ok(())
+        }
+    };
+    if let Err(err) = written {
+        eprintln!("Error: {}", SharedError::IO { source: err });
+    }
 }
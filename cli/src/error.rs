@@ -1,6 +1,4 @@
-use nix::sys::socket::UnixAddr;
-use shared::error::SharedError;
-use std::os::fd::RawFd;
+use shared::error::{ClientError, SharedError};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -8,17 +6,9 @@ pub enum CliError {
     #[error("Shared Errror: {0}")]
     Shared(#[from] SharedError),
 
-    #[error("Failed to connect to socket at {addr}: {errno}")]
-    ConnectSocket {
-        addr: UnixAddr,
-        #[source]
-        errno: nix::errno::Errno,
-    },
+    #[error("{0}")]
+    Client(#[from] ClientError),
 
-    #[error("Failed to write to socket descriptor {fd}: {errno}")]
-    WriteSocket {
-        fd: RawFd,
-        #[source]
-        errno: nix::errno::Errno,
-    },
+    #[error("Failed to remove {count} container(s)")]
+    Remove { count: usize },
 }
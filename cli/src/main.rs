@@ -4,14 +4,19 @@ mod error;
 
 use cli::Cli;
 use error::CliError;
+use shared::error::ClientError;
 
-fn main() -> Result<(), CliError> {
-    let mut cli = Cli::new()?;
-    match cli.execute() {
+fn main() {
+    match Cli::new().and_then(|mut cli| cli.execute()) {
         Ok(_) => {}
         Err(err) => {
-            eprintln!("Error: {}", err.to_string());
+            eprintln!("Error: {}", err);
+            if let CliError::Client(ClientError::Daemon(response)) = &err {
+                for cause in &response.causes {
+                    eprintln!("  Caused by: {}", cause);
+                }
+            }
+            std::process::exit(1);
         }
     }
-    Ok(())
 }
@@ -0,0 +1,62 @@
+//! The audit trail of the daemon. Every command sent by a client is recorded as a
+//! JSON line together with the identity of the client and whether it was allowed.
+
+use crate::auth::Peer;
+use log::info;
+use serde::Serialize;
+use shared::protocol::{Command, Header};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A single entry of the audit trail.
+/// - `time` is the number of seconds since the unix epoch.
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    pub time: u64,
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<&'a str>,
+    pub command: Command,
+    pub request_id: u32,
+    pub allowed: bool,
+}
+
+impl<'a> Record<'a> {
+    /// Create the record of a request sent by `peer`.
+    pub fn new(peer: &'a Peer, request: &Header, allowed: bool) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        Record {
+            time,
+            pid: peer.pid,
+            uid: peer.uid.as_raw(),
+            gid: peer.gid.as_raw(),
+            user: peer.user.as_deref(),
+            command: request.command,
+            request_id: request.request_id,
+            allowed,
+        }
+    }
+
+    /// Append the record to the audit log at `path`, or log it if there is none.
+    pub fn write(&self, path: Option<&str>) -> io::Result<()> {
+        let line = serde_json::to_string(self)?;
+        match path {
+            Some(path) => {
+                // a single append keeps concurrent records from interleaving.
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                file.write_all(format!("{}\n", line).as_bytes())
+            }
+            None => {
+                info!(target: "audit", "{}", line);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Authorization of the clients talking to the daemon. The identity of a client is
+//! taken from its connection (`SO_PEERCRED`) and checked against the `Authorization`
+//! policy of the daemon config for every command it sends.
+
+use crate::{config::Authorization, error::DaemonError};
+use nix::{
+    sys::socket::{getsockopt, sockopt},
+    unistd::{geteuid, getgrouplist, Gid, Group, Uid, User},
+};
+use shared::protocol::Command;
+use std::{ffi::CString, fmt, os::unix::net::UnixStream};
+
+/// The level of access a command requires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    /// The command only inspects the daemon.
+    Read,
+    /// The command changes images or containers.
+    Write,
+}
+
+impl Access {
+    /// The access required to run a command.
+    pub fn required(command: Command) -> Access {
+        match command {
+            Command::Ps | Command::Images | Command::Logs | Command::Stats | Command::Handshake => {
+                Access::Read
+            }
+            Command::Pull
+            | Command::Run
+            | Command::Stop
+            | Command::Rm
+            | Command::Exec
+            | Command::Tag
+            | Command::Untag
+            | Command::LogLevel => Access::Write,
+        }
+    }
+}
+
+/// The identity of the process on the other end of a connection.
+/// - `user` is the name of the user, if it has one.
+/// - `groups` are the primary and supplementary groups of the user.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub pid: i32,
+    pub uid: Uid,
+    pub gid: Gid,
+    pub user: Option<String>,
+    pub groups: Vec<Gid>,
+}
+
+impl Peer {
+    /// Read the credentials of the peer of a connection.
+    pub fn from_stream(stream: &UnixStream) -> Result<Self, DaemonError> {
+        let credentials = getsockopt(stream, sockopt::PeerCredentials)
+            .map_err(|e| DaemonError::PeerCredentials { errno: e })?;
+        let uid = Uid::from_raw(credentials.uid());
+        let gid = Gid::from_raw(credentials.gid());
+
+        // a user missing from the user database only has its primary group.
+        let user = User::from_uid(uid).ok().flatten().map(|user| user.name);
+        let groups = user
+            .as_ref()
+            .and_then(|user| CString::new(user.as_str()).ok())
+            .and_then(|user| getgrouplist(&user, gid).ok())
+            .unwrap_or_else(|| vec![gid]);
+
+        Ok(Peer {
+            pid: credentials.pid(),
+            uid,
+            gid,
+            user,
+            groups,
+        })
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{} (uid {}, pid {})", user, self.uid, self.pid),
+            None => write!(f, "uid {} (pid {})", self.uid, self.pid),
+        }
+    }
+}
+
+impl Authorization {
+    /// The highest level of access granted to a peer, if any.
+    pub fn access(&self, peer: &Peer) -> Option<Access> {
+        if peer.uid.is_root() || peer.uid == geteuid() {
+            return Some(Access::Write);
+        }
+        if Self::matches(peer, &self.users, &self.groups) {
+            return Some(Access::Write);
+        }
+        if Self::matches(peer, &self.read_only_users, &self.read_only_groups) {
+            return Some(Access::Read);
+        }
+        None
+    }
+
+    /// Check whether a peer may run a command.
+    pub fn allows(&self, peer: &Peer, command: Command) -> bool {
+        self.access(peer)
+            .is_some_and(|access| access >= Access::required(command))
+    }
+
+    /// Whether the peer is one of `users` or a member of one of `groups`.
+    fn matches(peer: &Peer, users: &[String], groups: &[String]) -> bool {
+        let user = users.iter().any(|user| match user.parse::<u32>() {
+            Ok(uid) => peer.uid.as_raw() == uid,
+            Err(_) => peer.user.as_deref() == Some(user.as_str()),
+        });
+        let group = groups.iter().any(|group| {
+            let gid = match group.parse::<u32>() {
+                Ok(gid) => Some(Gid::from_raw(gid)),
+                Err(_) => Group::from_name(group)
+                    .ok()
+                    .flatten()
+                    .map(|group| group.gid),
+            };
+            gid.is_some_and(|gid| peer.groups.contains(&gid))
+        });
+        user || group
+    }
+}
@@ -0,0 +1,430 @@
+//! The cgroups of containers on the cgroup v2 hierarchy. Every container gets a
+//! cgroup named after its id under the configured parent. Its limits are written
+//! before the container init is moved into it, so nothing runs unlimited.
+//!
+//! The controllers are enabled on the way down from the root to the parent as far
+//! as they are available. A limit fails if its controller cannot be enabled.
+//!
+//! cgroup v2 only enables controllers for the children of a cgroup without processes
+//! of its own, the root cgroup aside. The default parent is below the cgroup of the
+//! daemon, so the daemon first moves itself into a `daemon` leaf next to it.
+//!
+//! A container without limits runs without a cgroup if its cgroup cannot be created,
+//! e.g. by a rootless daemon that was not delegated one.
+
+use crate::{config::Cgroups, error::DaemonError};
+use log::{debug, info, warn};
+use nix::{
+    errno::Errno,
+    sys::signal::{kill, Signal},
+    unistd::{getpid, Pid},
+};
+use serde::{Deserialize, Serialize};
+use shared::requests::{ContainerStats, IoLimit, Resources};
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// The controllers enabled for containers.
+const CONTROLLERS: [&str; 4] = ["cpu", "io", "memory", "pids"];
+
+/// The period of a CPU quota unless one is given, in microseconds.
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+/// The cgroup containers are created in below the cgroup of the daemon by default.
+const DEFAULT_PARENT: &str = "j1407b";
+
+/// The leaf the daemon moves itself into when containers are created below its cgroup.
+const DAEMON_LEAF: &str = "daemon";
+
+/// How often removing a cgroup is tried while its killed processes exit.
+const REMOVE_ATTEMPTS: usize = 100;
+
+/// The cgroup of a container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+/// Where the cgroup v2 hierarchy is mounted. `None` on hosts without one.
+pub fn root(config: &Cgroups) -> Option<PathBuf> {
+    if let Some(root) = &config.root {
+        return Some(PathBuf::from(root));
+    }
+    // the filesystem type follows the separator of the optional fields.
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        match filesystem.split(' ').next() {
+            Some("cgroup2") => mount.split(' ').nth(4).map(PathBuf::from),
+            _ => None,
+        }
+    })
+}
+
+/// The cgroup the cgroups of containers are created in, relative to the root of the
+/// hierarchy.
+pub fn parent(config: &Cgroups) -> String {
+    match &config.parent {
+        Some(parent) => parent.clone(),
+        None => format!("{}/{}", own_cgroup().trim_end_matches('/'), DEFAULT_PARENT),
+    }
+}
+
+/// The cgroup v2 path the daemon was started in. Once the daemon moved into its leaf,
+/// that is the parent of the leaf.
+fn own_cgroup() -> String {
+    let current = current_cgroup();
+    match current
+        .strip_suffix(DAEMON_LEAF)
+        .and_then(|own| own.strip_suffix('/'))
+    {
+        Some("") => "/".to_string(),
+        Some(own) => own.to_string(),
+        None => current,
+    }
+}
+
+/// The cgroup v2 path of the daemon from `/proc/self/cgroup`, `/` if it has none.
+fn current_cgroup() -> String {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .unwrap_or("/")
+        .to_string()
+}
+
+/// Move the daemon out of its own cgroup into a leaf below it, so its own cgroup can
+/// enable controllers for the default parent. The root cgroup needs no leaf.
+fn leave_own_cgroup(root: &Path) {
+    let own = own_cgroup();
+    if own == "/" || current_cgroup() != own {
+        return;
+    }
+    let leaf = root.join(own.trim_start_matches('/')).join(DAEMON_LEAF);
+    let moved = fs::create_dir_all(&leaf)
+        .and_then(|_| fs::write(leaf.join("cgroup.procs"), getpid().to_string()));
+    match moved {
+        Ok(()) => info!("Moved the daemon into cgroup {}", leaf.display()),
+        Err(err) => warn!(
+            "Failed to move the daemon into cgroup {}, containers may not be limited: {}",
+            leaf.display(),
+            err
+        ),
+    }
+}
+
+impl Cgroup {
+    /// Create the cgroup of container `id` and apply `resources`. `None` if no limits
+    /// are requested and the host has no cgroup v2 hierarchy or the cgroup cannot be
+    /// created.
+    pub fn create(
+        config: &Cgroups,
+        id: &str,
+        resources: &Resources,
+    ) -> Result<Option<Self>, DaemonError> {
+        let Some(root) = root(config) else {
+            return match *resources == Resources::default() {
+                true => Ok(None),
+                false => Err(DaemonError::CgroupUnavailable {
+                    reason: "no cgroup v2 hierarchy is mounted".to_string(),
+                }),
+            };
+        };
+        let limited = *resources != Resources::default();
+        if config.parent.is_none() {
+            leave_own_cgroup(&root);
+        }
+        let parent = root.join(parent(config).trim_start_matches('/'));
+        let cgroup = Cgroup {
+            path: parent.join(id),
+        };
+        let created = fs::create_dir_all(&parent)
+            .map_err(|e| (&parent, e))
+            .map(|_| enable_controllers(&root, &parent))
+            .and_then(|failed| {
+                fs::create_dir(&cgroup.path)
+                    .map(|_| failed)
+                    .map_err(|e| (&cgroup.path, e))
+            });
+        let failed = match created {
+            Ok(failed) => failed,
+            // e.g. a rootless daemon without a delegated cgroup, unlimited containers
+            // can still run.
+            Err((path, err)) if !limited => {
+                warn!(
+                    "Failed to create cgroup {}, running container {} without one: {}",
+                    path.display(),
+                    id,
+                    err
+                );
+                return Ok(None);
+            }
+            Err((path, err)) => {
+                return Err(DaemonError::CreateCgroup {
+                    path: path.display().to_string(),
+                    source: err,
+                })
+            }
+        };
+        if let Err(err) = cgroup.limit(resources, &failed) {
+            let _ = cgroup.remove();
+            return Err(err);
+        }
+        Ok(Some(cgroup))
+    }
+
+    /// Move a process into the cgroup.
+    pub fn add(&self, pid: Pid) -> Result<(), DaemonError> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+
+    /// Read the resource usage of the cgroup. Counters whose controller is not enabled
+    /// are `None`, as are limits set to `max`.
+    pub fn stats(&self) -> ContainerStats {
+        let read = |file: &str| fs::read_to_string(self.path.join(file)).ok();
+        let value = |file: &str| read(file).and_then(|value| value.trim().parse().ok());
+        let cpu = read("cpu.stat").unwrap_or_default();
+        let io = read("io.stat");
+        let io_total = |key: &str| io.as_deref().map(|io| io_total(io, key));
+        ContainerStats {
+            memory: value("memory.current"),
+            memory_limit: value("memory.max"),
+            cpu_usage: cpu_stat(&cpu, "usage_usec"),
+            cpu_user: cpu_stat(&cpu, "user_usec"),
+            cpu_system: cpu_stat(&cpu, "system_usec"),
+            io_read_bytes: io_total("rbytes"),
+            io_write_bytes: io_total("wbytes"),
+            io_reads: io_total("rios"),
+            io_writes: io_total("wios"),
+            pids: value("pids.current"),
+            pids_limit: value("pids.max"),
+            ..Default::default()
+        }
+    }
+
+    /// Kill the processes left in the cgroup and remove it.
+    pub fn remove(&self) -> Result<(), DaemonError> {
+        self.kill();
+        let mut attempts = 0;
+        loop {
+            match fs::remove_dir(&self.path) {
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                    attempts += 1;
+                    if attempts == REMOVE_ATTEMPTS {
+                        return Err(self.remove_error(err));
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(self.remove_error(err)),
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    fn remove_error(&self, err: io::Error) -> DaemonError {
+        DaemonError::RemoveCgroup {
+            path: self.path.display().to_string(),
+            source: err,
+        }
+    }
+
+    /// Kill every process of the cgroup. `cgroup.kill` does so atomically, older
+    /// kernels have to kill the processes one by one.
+    pub fn kill(&self) {
+        if self.write("cgroup.kill", "1").is_ok() {
+            return;
+        }
+        let procs = fs::read_to_string(self.path.join("cgroup.procs")).unwrap_or_default();
+        for pid in procs.lines().filter_map(|pid| pid.parse().ok()) {
+            match kill(Pid::from_raw(pid), Signal::SIGKILL) {
+                Ok(()) | Err(Errno::ESRCH) => {}
+                Err(errno) => debug!("Failed to kill process {}: {}", pid, errno),
+            }
+        }
+    }
+
+    /// Apply `resources`. `failed` are the controllers that could not be enabled, with
+    /// the reason.
+    fn limit(&self, resources: &Resources, failed: &[(&str, String)]) -> Result<(), DaemonError> {
+        let invalid = |reason: String| DaemonError::InvalidRequest { reason };
+        let weight = |name: &str, weight: u64| match weight {
+            1..=10000 => Ok(()),
+            _ => Err(invalid(format!("the {} must be from 1 to 10000", name))),
+        };
+
+        let write_limit =
+            |controller, file, value: &str| self.write_limit(controller, file, value, failed);
+        if let Some(memory) = resources.memory {
+            write_limit("memory", "memory.max", &memory.to_string())?;
+        }
+        if let Some(cpu_weight) = resources.cpu_weight {
+            weight("CPU weight", cpu_weight)?;
+            write_limit("cpu", "cpu.weight", &cpu_weight.to_string())?;
+        }
+        if resources.cpu_quota.is_some() || resources.cpu_period.is_some() {
+            let quota = resources
+                .cpu_quota
+                .map_or("max".to_string(), |quota| quota.to_string());
+            let period = resources.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+            write_limit("cpu", "cpu.max", &format!("{} {}", quota, period))?;
+        }
+        if let Some(pids) = resources.pids {
+            write_limit("pids", "pids.max", &pids.to_string())?;
+        }
+        if let Some(io_weight) = resources.io_weight {
+            weight("IO weight", io_weight)?;
+            write_limit("io", "io.weight", &format!("default {}", io_weight))?;
+        }
+        for limit in &resources.io_limits {
+            write_limit("io", "io.max", &io_max(limit)?)?;
+        }
+        Ok(())
+    }
+
+    /// Write a limit, failing clearly if its controller is not enabled.
+    fn write_limit(
+        &self,
+        controller: &str,
+        file: &str,
+        value: &str,
+        failed: &[(&str, String)],
+    ) -> Result<(), DaemonError> {
+        if !self.path.join(file).exists() {
+            let reason = match failed.iter().find(|(name, _)| *name == controller) {
+                Some((_, reason)) => format!(
+                    "the {} controller could not be enabled for {}: {}",
+                    controller,
+                    self.path.display(),
+                    reason
+                ),
+                None => format!(
+                    "the {} controller is not enabled for {}",
+                    controller,
+                    self.path.display()
+                ),
+            };
+            return Err(DaemonError::CgroupUnavailable { reason });
+        }
+        self.write(file, value)
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), DaemonError> {
+        let path = self.path.join(file);
+        fs::write(&path, value).map_err(|e| DaemonError::WriteCgroup {
+            path: path.display().to_string(),
+            source: e,
+        })
+    }
+}
+
+/// Enable the controllers for the children of every cgroup from `root` down to
+/// `parent`. Controllers the host does not delegate are left out. Returns the
+/// controllers that could not be enabled, with the reason.
+fn enable_controllers(root: &Path, parent: &Path) -> Vec<(&'static str, String)> {
+    let mut failed = Vec::new();
+    let Ok(relative) = parent.strip_prefix(root) else {
+        return failed;
+    };
+    let mut cgroup = root.to_path_buf();
+    let mut cgroups = vec![cgroup.clone()];
+    for component in relative.components() {
+        cgroup.push(component);
+        cgroups.push(cgroup.clone());
+    }
+    for cgroup in cgroups {
+        let read = |file: &str| fs::read_to_string(cgroup.join(file)).unwrap_or_default();
+        let available = read("cgroup.controllers");
+        let enabled = read("cgroup.subtree_control");
+        for controller in CONTROLLERS {
+            let listed = |list: &str| list.split_whitespace().any(|name| name == controller);
+            if !listed(&available) || listed(&enabled) {
+                continue;
+            }
+            let control = cgroup.join("cgroup.subtree_control");
+            if let Err(err) = fs::write(&control, format!("+{}", controller)) {
+                let reason = match err.raw_os_error() {
+                    Some(libc::EBUSY) => {
+                        format!("{}, {} has processes of its own", err, cgroup.display())
+                    }
+                    _ => err.to_string(),
+                };
+                warn!(
+                    "Failed to enable the {} controller in {}: {}",
+                    controller,
+                    cgroup.display(),
+                    reason
+                );
+                failed.push((controller, reason));
+            }
+        }
+    }
+    failed
+}
+
+/// A counter of `cpu.stat`, which has a `KEY VALUE` line per counter.
+fn cpu_stat(content: &str, key: &str) -> Option<u64> {
+    content.lines().find_map(|line| match line.split_once(' ') {
+        Some((name, value)) if name == key => value.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// A counter of `io.stat` added up over all devices. Every line holds the
+/// `KEY=VALUE` counters of a device after its `MAJOR:MINOR`.
+fn io_total(content: &str, key: &str) -> u64 {
+    content
+        .lines()
+        .flat_map(|line| line.split_whitespace().skip(1))
+        .filter_map(|counter| match counter.split_once('=') {
+            Some((name, value)) if name == key => value.parse::<u64>().ok(),
+            _ => None,
+        })
+        .sum()
+}
+
+/// The `io.max` line of a device.
+fn io_max(limit: &IoLimit) -> Result<String, DaemonError> {
+    let mut line = device_number(&limit.device)?;
+    let rates = [
+        ("rbps", limit.read_bps),
+        ("wbps", limit.write_bps),
+        ("riops", limit.read_iops),
+        ("wiops", limit.write_iops),
+    ];
+    for (key, rate) in rates {
+        if let Some(rate) = rate {
+            line.push_str(&format!(" {}={}", key, rate));
+        }
+    }
+    Ok(line)
+}
+
+/// The `MAJOR:MINOR` of a block device given by path or by number.
+fn device_number(device: &str) -> Result<String, DaemonError> {
+    let invalid = || DaemonError::InvalidRequest {
+        reason: format!("{} is not a block device", device),
+    };
+    if let Some((major, minor)) = device.split_once(':') {
+        return match major.parse::<u32>().is_ok() && minor.parse::<u32>().is_ok() {
+            true => Ok(device.to_string()),
+            false => Err(invalid()),
+        };
+    }
+    let metadata = fs::metadata(device).map_err(|_| invalid())?;
+    if !metadata.file_type().is_block_device() {
+        return Err(invalid());
+    }
+    let rdev = metadata.rdev();
+    Ok(format!(
+        "{}:{}",
+        nix::sys::stat::major(rdev),
+        nix::sys::stat::minor(rdev)
+    ))
+}
@@ -1,5 +1,7 @@
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
-use shared::config::DefaultConfig;
+use shared::{config::DefaultConfig, protocol::DEFAULT_MAX_BODY_SIZE, utils};
+use std::path::Path;
 
 pub const CONFIG_FILE_NAME: &str = "daemon";
 
@@ -7,38 +9,338 @@ pub const CONFIG_FILE_NAME: &str = "daemon";
 pub struct Config {
     pub images_dir: String,
     pub containers_dir: String,
+    #[serde(default)]
+    pub volumes_dir: Option<String>,
     pub registry: Registry,
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: u64,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default = "default_stop_signal")]
+    pub stop_signal: String,
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    #[serde(default)]
+    pub socket: Socket,
+    #[serde(default)]
+    pub connections: Connections,
+    #[serde(default = "default_pid_file")]
+    pub pid_file: String,
+    #[serde(default)]
+    pub authorization: Authorization,
+    #[serde(default)]
+    pub logging: Logging,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub cgroups: Cgroups,
 }
 
+impl Config {
+    /// The directory volumes are kept in, `volumes` next to `containers_dir` unless
+    /// `volumes_dir` is set.
+    pub fn volumes_dir(&self) -> String {
+        match &self.volumes_dir {
+            Some(volumes_dir) => volumes_dir.clone(),
+            None => Path::new(&self.containers_dir)
+                .with_file_name("volumes")
+                .display()
+                .to_string(),
+        }
+    }
+}
+
+/// The default maximum length of a request body.
+fn default_max_body_size() -> u64 {
+    DEFAULT_MAX_BODY_SIZE
+}
+
+/// The default number of seconds in-flight requests get to finish on shutdown.
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+/// The signal `stop` sends to the init of a container unless the request names one.
+fn default_stop_signal() -> String {
+    "SIGTERM".to_string()
+}
+
+/// The default number of seconds a stopped container gets to exit before it is killed.
+fn default_stop_timeout() -> u64 {
+    10
+}
+
+/// What happens to the running containers when the daemon shuts down.
+/// - `containers` is `leave` to keep them running for the next daemon to adopt, or
+///   `stop` to stop them the way the `stop` command does, with the stop signal.
+/// - `timeout` is the number of seconds stopped containers get to exit before they are
+///   killed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Shutdown {
+    #[serde(default)]
+    pub containers: ShutdownContainers,
+    #[serde(default = "default_stop_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            containers: ShutdownContainers::default(),
+            timeout: default_stop_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownContainers {
+    Stop,
+    #[default]
+    Leave,
+}
+
+/// The pid file is locked while the daemon runs, which keeps a second daemon from starting.
+fn default_pid_file() -> String {
+    format!("{}/j1407b.pid", utils::runtime_dir())
+}
+
+/// The unix socket the daemon listens on.
+/// - `path` defaults to `$XDG_RUNTIME_DIR/j1407b.sock`, or `/tmp/j1407b-<uid>/j1407b.sock`
+///   without a runtime directory.
+/// - `mode` is the permission of the socket file, e.g. `0o660`.
+/// - `group` optionally owns the socket file so its members can connect.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Socket {
+    #[serde(default = "utils::default_socket_path")]
+    pub path: String,
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+    pub group: Option<String>,
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        Socket {
+            path: utils::default_socket_path(),
+            mode: default_socket_mode(),
+            group: None,
+        }
+    }
+}
+
+/// By default only the owner of the daemon may connect.
+fn default_socket_mode() -> u32 {
+    0o600
+}
+
+/// How many clients the daemon serves at once, and how long it waits for them.
+/// - `max` is the number of connections served at once. Further clients wait in the
+///   listen backlog until a connection closes.
+/// - `max_requests` is the number of requests handled at once on a connection with the
+///   `MULTIPLEX` capability. Further requests are not read until one of them finishes.
+/// - `read_timeout` is the number of seconds a client gets to send its handshake, and
+///   the rest of a message once it started sending it.
+/// - `idle_timeout` is the number of seconds a connection without requests in flight
+///   is kept open.
+/// - Either timeout is disabled with `0`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Connections {
+    #[serde(default = "default_max_connections")]
+    pub max: usize,
+    #[serde(default = "default_max_requests")]
+    pub max_requests: usize,
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections {
+            max: default_max_connections(),
+            max_requests: default_max_requests(),
+            read_timeout: default_read_timeout(),
+            idle_timeout: default_idle_timeout(),
+        }
+    }
+}
+
+fn default_max_connections() -> usize {
+    64
+}
+
+fn default_max_requests() -> usize {
+    16
+}
+
+fn default_read_timeout() -> u64 {
+    10
+}
+
+/// Idle connections are closed after 5 minutes by default.
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+/// Who may run which commands. Root and the user running the daemon may always run
+/// every command.
+/// - `users` and `groups` may run every command.
+/// - `read_only_users` and `read_only_groups` may only run commands that do not change
+///   anything, such as `ps` and `images`.
+/// - Users and groups are given by name or by numeric id.
+/// - `audit_log` is a file every command is recorded in as a JSON line. Without it the
+///   records are printed along with the other logs.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Authorization {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub read_only_users: Vec<String>,
+    #[serde(default)]
+    pub read_only_groups: Vec<String>,
+    pub audit_log: Option<String>,
+}
+
+/// How the daemon logs. The level can also be changed at runtime with the `LogLevel`
+/// command, until the configuration is reloaded.
+/// - `level` is one of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+/// - `format` is either `text` or `json`, one record per line.
+/// - `file` is the log file. Without it the records are written to stderr.
+/// - `max_size` is the size in bytes after which the log file is rotated, `0` never
+///   rotates it.
+/// - `max_files` is the number of rotated files (`<file>.1`, `<file>.2`, ...) kept.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Registry {
-    pub url: String,
-    pub token: String,
-    pub https: bool,
+pub struct Logging {
+    #[serde(default = "default_log_level")]
+    pub level: LevelFilter,
+    #[serde(default)]
+    pub format: LogFormat,
+    pub file: Option<String>,
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_log_max_files")]
+    pub max_files: u32,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: default_log_level(),
+            format: LogFormat::default(),
+            file: None,
+            max_size: default_log_max_size(),
+            max_files: default_log_max_files(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
 }
 
-impl Registry {
-    /// Get the URL of the registry.
-    pub fn get_url(&self) -> String {
-        if self.https {
-            format!("https://{}", self.url)
-        } else {
-            format!("http://{}", self.url)
+/// Log files are rotated after 10 MiB by default.
+fn default_log_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_max_files() -> u32 {
+    5
+}
+
+/// How the root filesystems of containers are built from the layers of their image.
+/// - `driver` is `overlay` (an overlayfs mount), `fuse-overlayfs` (the same overlay
+///   mounted without privileges, e.g. by a rootless daemon) or `copy` (a copy of the
+///   layers per container, for hosts without overlayfs).
+/// - `fuse_overlayfs` is the `fuse-overlayfs` binary, looked up in `PATH` by default.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Storage {
+    #[serde(default)]
+    pub driver: StorageDriver,
+    #[serde(default = "default_fuse_overlayfs")]
+    pub fuse_overlayfs: String,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            driver: StorageDriver::default(),
+            fuse_overlayfs: default_fuse_overlayfs(),
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageDriver {
+    #[default]
+    Overlay,
+    FuseOverlayfs,
+    Copy,
+}
+
+fn default_fuse_overlayfs() -> String {
+    "fuse-overlayfs".to_string()
+}
+
+/// The cgroups containers run in, on the cgroup v2 hierarchy.
+/// - `root` is where the hierarchy is mounted, found in `/proc/self/mountinfo` by default.
+/// - `parent` is the cgroup the cgroups of containers are created in, relative to
+///   `root`. By default it is `j1407b` below the cgroup of the daemon itself, which is
+///   the one delegated to a rootless daemon by its service manager. The daemon then
+///   moves itself into a `daemon` leaf next to it, since a cgroup with processes of its
+///   own cannot enable controllers for its children. A configured parent must be one
+///   the daemon may enable controllers in, e.g. a delegated cgroup without processes.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Cgroups {
+    pub root: Option<String>,
+    pub parent: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Registry {
+    pub url: String,
+    pub token: String,
+    pub https: bool,
+}
+
 impl DefaultConfig for Config {
     // we have access to the path here.
     fn default(path: &str) -> Self {
         Config {
             images_dir: format!("{}/images", path),
             containers_dir: format!("{}/containers", path),
+            volumes_dir: Some(format!("{}/volumes", path)),
             registry: Registry {
                 url: "hub.docker.com".to_string(),
                 token: "".to_string(),
                 https: true,
             },
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            shutdown_timeout: default_shutdown_timeout(),
+            stop_signal: default_stop_signal(),
+            stop_timeout: default_stop_timeout(),
+            shutdown: Shutdown::default(),
+            socket: Socket::default(),
+            connections: Connections::default(),
+            pid_file: default_pid_file(),
+            authorization: Authorization::default(),
+            logging: Logging::default(),
+            storage: Storage::default(),
+            cgroups: Cgroups::default(),
         }
     }
 }
@@ -0,0 +1,372 @@
+//! Containers managed by the daemon. Every container has a directory under
+//! `containers_dir` named after its id, holding its root filesystem built from the
+//! layers of the image it was created from (see `storage`).
+//!
+//! The state of every container is kept in `state.json` in its directory, so the
+//! daemon knows its containers again after a restart.
+//!
+//! A removed container is renamed out of the way before it is torn down, so it is
+//! either still complete or gone. Removals that were interrupted are finished when
+//! the daemon starts.
+
+use crate::{
+    cgroup::Cgroup,
+    config::{Storage, StorageDriver},
+    error::DaemonError,
+    logs, storage,
+    volume::Volume,
+};
+use log::{info, warn};
+use nix::{
+    errno::Errno,
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The length of a container id in bytes, it is printed as twice as many hex digits.
+const ID_LENGTH: usize = 32;
+
+/// The length of the short form of a container id.
+pub const SHORT_ID_LENGTH: usize = 12;
+
+/// The prefix of container directories being removed.
+const REMOVING_PREFIX: &str = ".removing-";
+
+/// The file the state of a container is kept in, in its directory.
+const STATE_FILE: &str = "state.json";
+
+/// The exit code recorded for containers that exited while the daemon was not running.
+pub const UNKNOWN_EXIT_CODE: i32 = 255;
+
+/// The lifecycle of a container.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    Exited { code: i32 },
+}
+
+impl Status {
+    /// The name of the status, without the exit code.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Created => "created",
+            Status::Running => "running",
+            Status::Exited { .. } => "exited",
+        }
+    }
+}
+
+/// A container and the process running in it.
+/// - `pid` is the pid of the container init as seen by the daemon.
+/// - `driver` built its root filesystem and tears it down again.
+/// - `cgroup` limits the container, unless the host has no cgroup v2 hierarchy.
+/// - `created`, `started` and `finished` are milliseconds since the unix epoch.
+/// - `volumes` are mounted into the container and kept when it is removed, unless they
+///   are removed along with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Container {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub command: Vec<String>,
+    pub driver: StorageDriver,
+    pub cgroup: Option<Cgroup>,
+    #[serde(serialize_with = "serialize_pid", deserialize_with = "deserialize_pid")]
+    pub pid: Option<Pid>,
+    pub status: Status,
+    pub created: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+}
+
+impl Container {
+    /// Create a container from the `layers` of `image`. Its directory is removed
+    /// again if it cannot be set up.
+    pub fn create(
+        containers_dir: &str,
+        storage: &Storage,
+        name: Option<String>,
+        image: &str,
+        layers: &[PathBuf],
+        command: Vec<String>,
+    ) -> Result<Self, DaemonError> {
+        let id = generate_id()?;
+        let container = Container {
+            name: name.unwrap_or_else(|| id[..SHORT_ID_LENGTH].to_string()),
+            id,
+            image: image.to_string(),
+            command,
+            driver: storage.driver,
+            cgroup: None,
+            pid: None,
+            status: Status::Created,
+            created: now(),
+            started: None,
+            finished: None,
+            labels: BTreeMap::new(),
+            volumes: Vec::new(),
+        };
+
+        let dir = container.dir(containers_dir);
+        fs::create_dir_all(&dir).map_err(|e| DaemonError::CreateContainer {
+            path: dir.display().to_string(),
+            source: e,
+        })?;
+        if let Err(err) = storage::create(storage, layers, &dir) {
+            let _ = container.remove(containers_dir);
+            return Err(err);
+        }
+        Ok(container)
+    }
+
+    /// The short form of the id.
+    pub fn short_id(&self) -> &str {
+        &self.id[..SHORT_ID_LENGTH]
+    }
+
+    /// The directory of the container.
+    pub fn dir(&self, containers_dir: &str) -> PathBuf {
+        Path::new(containers_dir).join(&self.id)
+    }
+
+    /// The root filesystem of the container.
+    pub fn rootfs(&self, containers_dir: &str) -> PathBuf {
+        self.dir(containers_dir).join("rootfs")
+    }
+
+    /// The log of the output of the container.
+    pub fn log(&self, containers_dir: &str) -> PathBuf {
+        self.dir(containers_dir).join(logs::LOG_FILE)
+    }
+
+    /// Record that the init of the container started as `pid`.
+    pub fn start(&mut self, pid: Pid) {
+        self.pid = Some(pid);
+        self.status = Status::Running;
+        self.started = Some(now());
+    }
+
+    /// Record that the init of the container exited with `code`.
+    pub fn exit(&mut self, code: i32) {
+        self.status = Status::Exited { code };
+        self.finished = Some(now());
+    }
+
+    /// Write the state of the container to its directory. The state is replaced in one
+    /// step, so it is never read half written.
+    pub fn save(&self, containers_dir: &str) -> Result<(), DaemonError> {
+        let path = self.dir(containers_dir).join(STATE_FILE);
+        let temporary = path.with_extension("json.tmp");
+        let save_error = |source: io::Error| DaemonError::SaveContainer {
+            path: path.display().to_string(),
+            source,
+        };
+        let state = serde_json::to_vec_pretty(self)
+            .map_err(io::Error::from)
+            .map_err(save_error)?;
+        fs::write(&temporary, state)
+            .and_then(|_| fs::rename(&temporary, &path))
+            .map_err(save_error)
+    }
+
+    /// Whether the init of the container is still alive, for containers that are not
+    /// children of the daemon.
+    pub fn is_alive(&self) -> bool {
+        let Some(pid) = self.pid else {
+            return false;
+        };
+        // a zombie has exited and only waits for its new parent to reap it.
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        match stat.rsplit_once(") ") {
+            Some((_, fields)) => !fields.starts_with(['Z', 'X']),
+            None => false,
+        }
+    }
+
+    /// Send `signal` to the init of the container. An init that already exited is
+    /// not an error.
+    pub fn signal(&self, signal: Signal) -> Result<(), DaemonError> {
+        let Some(pid) = self.pid else {
+            return Ok(());
+        };
+        match kill(pid, signal) {
+            Ok(()) | Err(Errno::ESRCH) => Ok(()),
+            Err(errno) => Err(DaemonError::SignalContainer {
+                id: self.id.clone(),
+                errno,
+            }),
+        }
+    }
+
+    /// Kill every process of the container. Without a cgroup only the init is killed,
+    /// which takes the rest of its pid namespace with it.
+    pub fn kill(&self) -> Result<(), DaemonError> {
+        match &self.cgroup {
+            Some(cgroup) => {
+                cgroup.kill();
+                Ok(())
+            }
+            None => self.signal(Signal::SIGKILL),
+        }
+    }
+
+    /// Remove the container. Its directory is renamed first, once renamed the container
+    /// is gone. Its cgroup, root filesystem and files are then torn down from the
+    /// renamed directory, what fails to be torn down is retried by `remove_leftovers`
+    /// the next time the daemon starts.
+    pub fn remove(&self, containers_dir: &str) -> Result<(), DaemonError> {
+        let dir = self.dir(containers_dir);
+        let removing = Path::new(containers_dir).join(format!("{}{}", REMOVING_PREFIX, self.id));
+        match fs::rename(&dir, &removing) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(remove_error(&dir, err)),
+        }
+        if let Err(err) = self.tear_down(&removing) {
+            warn!("Failed to tear down container {}: {}", self.id, err);
+        }
+        Ok(())
+    }
+
+    /// Remove the cgroup of the container, tear down its root filesystem and delete
+    /// `dir`, its renamed directory. The files are only deleted once the root
+    /// filesystem is unmounted, deleting them through the mount would reach its layers.
+    fn tear_down(&self, dir: &Path) -> Result<(), DaemonError> {
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.remove()?;
+        }
+        storage::release(self.driver, dir)?;
+        match fs::remove_dir_all(dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(remove_error(dir, err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn remove_error(path: &Path, source: io::Error) -> DaemonError {
+    DaemonError::RemoveContainer {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// Read the state of the container in `dir`.
+fn read_state(dir: &Path) -> Result<Container, String> {
+    let path = dir.join(STATE_FILE);
+    fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|state| serde_json::from_slice(&state).map_err(|e| e.to_string()))
+        .map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Load the state of the containers in `containers_dir`. Directories without a
+/// readable state are skipped.
+pub fn load(containers_dir: &str) -> Vec<Container> {
+    let Ok(entries) = fs::read_dir(containers_dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            !entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(REMOVING_PREFIX)
+        })
+        .filter_map(|entry| match read_state(&entry.path()) {
+            Ok(container) => Some(container),
+            Err(err) => {
+                warn!("Failed to load the state of container {}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Finish the removals that were interrupted: tear down the containers whose
+/// directories were renamed but not deleted, the way `Container::remove` does.
+pub fn remove_leftovers(containers_dir: &str) {
+    let Ok(entries) = fs::read_dir(containers_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(REMOVING_PREFIX)
+        {
+            continue;
+        }
+        let path = entry.path();
+        let torn_down = match read_state(&path) {
+            Ok(container) => container.tear_down(&path),
+            // without a state there is nothing known to tear down but the files.
+            Err(err) => {
+                warn!("Failed to load the state of a removed container {}", err);
+                fs::remove_dir_all(&path).map_err(|e| remove_error(&path, e))
+            }
+        };
+        match torn_down {
+            Ok(()) => info!("Removed leftover container directory {}", path.display()),
+            Err(err) => warn!(
+                "Failed to remove leftover container directory {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+/// Parse a signal given by name, with or without the `SIG` prefix, or by number.
+pub fn parse_signal(signal: &str) -> Result<Signal, DaemonError> {
+    let invalid = || DaemonError::InvalidSignal {
+        signal: signal.to_string(),
+    };
+    if let Ok(number) = signal.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| invalid());
+    }
+    let name = signal.to_uppercase();
+    match name.starts_with("SIG") {
+        true => name.parse(),
+        false => format!("SIG{}", name).parse(),
+    }
+    .map_err(|_| invalid())
+}
+
+/// The current time in milliseconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn serialize_pid<S: Serializer>(pid: &Option<Pid>, serializer: S) -> Result<S::Ok, S::Error> {
+    pid.map(Pid::as_raw).serialize(serializer)
+}
+
+fn deserialize_pid<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Pid>, D::Error> {
+    Ok(Option::<i32>::deserialize(deserializer)?.map(Pid::from_raw))
+}
+
+/// Generate a random id, of a container or a volume.
+pub fn generate_id() -> Result<String, DaemonError> {
+    let mut bytes = [0u8; ID_LENGTH];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .map_err(|e| DaemonError::GenerateId { source: e })?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
@@ -1,60 +1,310 @@
 use crate::{
-    config::{Config, CONFIG_FILE_NAME},
+    audit::Record,
+    auth::Peer,
+    cgroup::{self, Cgroup},
+    config::{Config, ShutdownContainers, Socket, StorageDriver, CONFIG_FILE_NAME},
+    container::{self, Container, Status},
     error::DaemonError,
+    idmap::IdMaps,
+    image::{self, Image, Index},
+    logger::{self, Context},
+    logs::{self, Capture, Reader},
+    pidfile::PidFile,
+    process::{self, Spec},
+    volume::{self, Volume},
 };
+use log::{debug, error, info, warn, LevelFilter};
 use nix::{
-    sys::socket::{
-        accept, bind, listen, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType,
-        UnixAddr,
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        signal::{SigSet, Signal},
+        signalfd::{SfdFlags, SignalFd},
+        socket::{
+            accept, bind, getsockopt, listen, setsockopt, socket, sockopt, AddressFamily, SockFlag,
+            SockType, UnixAddr,
+        },
+        stat::{self, Mode},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
     },
-    unistd::close,
+    unistd::{chown, geteuid, getpid, Group},
 };
+use serde::Serialize;
 use shared::{
     config::ConfigHolder,
     error::SharedError,
-    protocol::{self, Header, Protocol},
-    requests::PullRequest,
+    protocol::{
+        Capabilities, Command, Frame, Handshake, Header, Protocol, Response, SharedWriter,
+        StreamWriter, Type,
+    },
+    requests::{
+        ContainerStats, ContainerSummary, Filter, ImageFilter, ImageSummary, ImagesRequest,
+        ImagesResponse, LogLevelRequest, LogLevelResponse, LogsRequest, PsRequest, PsResponse,
+        PullRequest, PullResponse, RmRequest, RmResponse, RmResult, RunRequest, RunResponse,
+        SortKey, StatsRequest, StatsResponse, StopRequest, StopResponse, TagRequest, TagResponse,
+        UntagRequest, UntagResponse,
+    },
+    utils,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    fs::{self, File, Permissions},
+    io::{self, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
-use std::os::fd::{AsRawFd, OwnedFd};
 
 pub struct Daemon {
-    pub config: ConfigHolder<Config>,
+    pub config: RwLock<ConfigHolder<Config>>,
     pub socket_fd: OwnedFd,
+    /// The path of the socket file, unless the socket was passed by a service manager.
+    pub socket_path: Option<String>,
+    pid_file: PidFile,
+    connections: Mutex<HashMap<u64, UnixStream>>,
+    next_connection: AtomicU64,
+    drained: Condvar,
+    /// Set once the daemon shuts down, so streaming requests finish.
+    stopping: AtomicBool,
+    /// The containers created by the daemon, by id.
+    containers: Mutex<HashMap<String, Container>>,
+    /// Notified whenever containers were reaped.
+    exited: Condvar,
+    /// The images of the store.
+    images: Mutex<Index>,
+    /// The threads collecting the output of containers, by id.
+    collectors: Mutex<HashMap<String, JoinHandle<()>>>,
 }
 
-const DAEMON_SOCKET: &str = "/tmp/j1047b.sock";
+/// The first file descriptor passed by a service manager.
+const LISTEN_FDS_START: RawFd = 3;
+
+const LISTEN_PID_VARIABLE: &str = "LISTEN_PID";
+const LISTEN_FDS_VARIABLE: &str = "LISTEN_FDS";
+const LISTEN_FDNAMES_VARIABLE: &str = "LISTEN_FDNAMES";
+
+/// The number of pending connections the kernel queues before refusing new ones.
+const LISTEN_BACKLOG: usize = 128;
+
+/// How long to wait before accepting again after running out of resources.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often containers that are not children of the daemon are checked for exiting.
+const REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a killed container gets to be reaped.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a followed log is checked for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The time between two samples of a streaming `stats` request, in milliseconds.
+const DEFAULT_STATS_INTERVAL: u64 = 1000;
 
 impl Daemon {
     /// Create a new Daemon instance
     pub fn new() -> Result<Self, DaemonError> {
+        // claim a socket passed by a service manager before any other file is opened.
+        let inherited = Self::inherited_socket()?;
         let config = ConfigHolder::<Config>::new(CONFIG_FILE_NAME)?;
+        logger::init(&config.config.logging)?;
+        if !geteuid().is_root() && config.config.storage.driver == StorageDriver::Overlay {
+            warn!("Only root may mount overlays, rootless daemons should use the fuse-overlayfs or copy storage driver");
+        }
+        if cgroup::root(&config.config.cgroups).is_none() {
+            warn!("No cgroup v2 hierarchy is mounted, containers cannot be limited");
+        }
+
+        // refuse to start before touching the socket if another daemon is running.
+        let pid_file = PidFile::lock(&config.config.pid_file)?;
+
+        // the containers are only touched once no other daemon can be using them.
+        container::remove_leftovers(&config.config.containers_dir);
+        let containers = Self::restore(&config.config.containers_dir);
+        let images = Self::index(&config.config.images_dir);
+
+        let (socket_fd, socket_path) = match inherited {
+            Some(socket_fd) => {
+                info!(
+                    "Listening on socket {} passed by the service manager",
+                    socket_fd.as_raw_fd()
+                );
+                (socket_fd, None)
+            }
+            None => {
+                let settings = &config.config.socket;
+                (Self::bind(settings)?, Some(settings.path.clone()))
+            }
+        };
+
+        Ok(Daemon {
+            config: RwLock::new(config),
+            socket_fd,
+            socket_path,
+            pid_file,
+            connections: Mutex::new(HashMap::new()),
+            next_connection: AtomicU64::new(0),
+            drained: Condvar::new(),
+            stopping: AtomicBool::new(false),
+            containers: Mutex::new(containers),
+            exited: Condvar::new(),
+            images: Mutex::new(images),
+            collectors: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Load the image index, importing the images unpacked while the daemon was not
+    /// running.
+    fn index(images_dir: &str) -> Index {
+        let mut index = Index::load(images_dir);
+        if index.scan(images_dir) {
+            if let Err(err) = index.save(images_dir) {
+                warn!("{}", err);
+            }
+        }
+        index
+    }
+
+    /// Restore the containers of a previous run. Containers whose init exited while
+    /// the daemon was not running, or that were never started, are marked as exited
+    /// with an unknown exit code.
+    fn restore(containers_dir: &str) -> HashMap<String, Container> {
+        let mut containers = HashMap::new();
+        for mut container in container::load(containers_dir) {
+            if container.status == Status::Created
+                || (container.status == Status::Running && !container.is_alive())
+            {
+                container.exit(container::UNKNOWN_EXIT_CODE);
+                info!(
+                    "Container {} ({}) exited while the daemon was not running",
+                    container.short_id(),
+                    container.name
+                );
+                if let Err(err) = container.save(containers_dir) {
+                    error!("{}", err);
+                }
+            }
+            containers.insert(container.id.clone(), container);
+        }
+        if !containers.is_empty() {
+            info!("Restored {} container(s)", containers.len());
+        }
+        containers
+    }
+
+    /// Take over the listening socket passed by a service manager through the
+    /// `LISTEN_PID`/`LISTEN_FDS` protocol, if there is one.
+    fn inherited_socket() -> Result<Option<OwnedFd>, DaemonError> {
+        let pid = env::var(LISTEN_PID_VARIABLE).ok();
+        let fds = env::var(LISTEN_FDS_VARIABLE).ok();
+
+        // the variables are meant for this process only, not for its children.
+        for variable in [
+            LISTEN_PID_VARIABLE,
+            LISTEN_FDS_VARIABLE,
+            LISTEN_FDNAMES_VARIABLE,
+        ] {
+            env::remove_var(variable);
+        }
+
+        let (Some(pid), Some(fds)) = (pid, fds) else {
+            return Ok(None);
+        };
+        if pid.parse::<i32>().ok() != Some(getpid().as_raw()) {
+            return Ok(None);
+        }
+        match fds.parse::<i32>() {
+            Ok(0) => return Ok(None),
+            Ok(1) => {}
+            _ => {
+                return Err(DaemonError::SocketActivation {
+                    reason: format!("expected a single socket, got LISTEN_FDS={}", fds),
+                })
+            }
+        }
+
+        // the service manager hands the socket over, it is owned from here on.
+        fcntl(LISTEN_FDS_START, FcntlArg::F_GETFD).map_err(|_| DaemonError::SocketActivation {
+            reason: format!("file descriptor {} is not open", LISTEN_FDS_START),
+        })?;
+        let socket_fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+        let listening = getsockopt(&socket_fd, sockopt::SockType) == Ok(SockType::Stream)
+            && getsockopt(&socket_fd, sockopt::AcceptConn) == Ok(true);
+        if !listening {
+            return Err(DaemonError::SocketActivation {
+                reason: "the passed socket is not a listening stream socket".to_string(),
+            });
+        }
 
+        // accepting never blocks, the socket is polled for new connections.
+        let flags = OFlag::from_bits_truncate(
+            fcntl(socket_fd.as_raw_fd(), FcntlArg::F_GETFL)
+                .map_err(|e| DaemonError::SetSocketFlags { errno: e })?,
+        );
+        fcntl(
+            socket_fd.as_raw_fd(),
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )
+        .map_err(|e| DaemonError::SetSocketFlags { errno: e })?;
+        fcntl(socket_fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| DaemonError::SetSocketFlags { errno: e })?;
+
+        Ok(Some(socket_fd))
+    }
+
+    /// Create the listening socket at the configured path.
+    fn bind(settings: &Socket) -> Result<OwnedFd, DaemonError> {
         // create a new socket address (sockaddr_un)
-        let socket_addr = UnixAddr::new(DAEMON_SOCKET.as_bytes())
+        let socket_addr = UnixAddr::new(settings.path.as_str())
             .map_err(|e| SharedError::CreateUnixAddr { errno: e })?;
 
-        println!("[Info] Socket address: {:?}", socket_addr);
-
         // create a new socket
         let socket_fd = socket(
             AddressFamily::Unix,
             SockType::Stream,
-            SockFlag::empty(),
+            // accepting never blocks, the socket is polled for new connections.
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
             None,
         )
         .map_err(|e| SharedError::CreateSocket { errno: e })?;
 
-        println!("[INFO] Socket file descriptor: {:?}", socket_fd.as_raw_fd());
+        debug!("Created socket {}", socket_fd.as_raw_fd());
 
-        let _ = std::fs::remove_file(DAEMON_SOCKET);
-        // bind the socket to the address
-        bind(socket_fd.as_raw_fd(), &socket_addr).map_err(|e| DaemonError::BindSocket {
+        // the pid file lock is held, so a socket file left behind is stale.
+        if let Some(parent) = Path::new(&settings.path).parent() {
+            utils::ensure_private_directory(parent)?;
+        }
+        let _ = fs::remove_file(&settings.path);
+
+        // bind the socket to the address. the umask keeps the socket file restricted
+        // to `mode` from the moment it is created.
+        let umask = stat::umask(Mode::from_bits_truncate(!settings.mode & 0o777));
+        let bound = bind(socket_fd.as_raw_fd(), &socket_addr);
+        stat::umask(umask);
+        bound.map_err(|e| DaemonError::BindSocket {
             fd: socket_fd.as_raw_fd(),
             addr: socket_addr,
             errno: e,
         })?;
+        Self::set_socket_permissions(settings)?;
 
-        println!("[INFO] Socket bound to address: {:?}", socket_addr);
+        debug!(
+            "Bound socket {} to {}",
+            socket_fd.as_raw_fd(),
+            settings.path
+        );
 
         // set SO_REUSEADDR option
         setsockopt(&socket_fd, sockopt::ReuseAddr, &true).map_err(|e| {
@@ -65,69 +315,1447 @@ impl Daemon {
         })?;
 
         // listen for incoming connections
-        listen(&socket_fd, 5).map_err(|e| DaemonError::ListenSocket {
+        listen(&socket_fd, LISTEN_BACKLOG).map_err(|e| DaemonError::ListenSocket {
             fd: socket_fd.as_raw_fd(),
             errno: e,
         })?;
 
-        println!("[INFO] Listening for incoming connections");
+        info!("Listening on {}", settings.path);
 
-        Ok(Daemon {
-            config: config,
-            socket_fd: socket_fd,
+        Ok(socket_fd)
+    }
+
+    /// Apply the configured mode and owning group to the socket file.
+    fn set_socket_permissions(socket: &Socket) -> Result<(), DaemonError> {
+        fs::set_permissions(&socket.path, Permissions::from_mode(socket.mode)).map_err(|e| {
+            DaemonError::SocketPermissions {
+                path: socket.path.clone(),
+                source: e,
+            }
+        })?;
+
+        let Some(group) = &socket.group else {
+            return Ok(());
+        };
+        let gid = match Group::from_name(group) {
+            Ok(Some(group)) => group.gid,
+            Ok(None) => {
+                return Err(DaemonError::UnknownGroup {
+                    group: group.clone(),
+                })
+            }
+            Err(e) => {
+                return Err(DaemonError::LookupGroup {
+                    group: group.clone(),
+                    errno: e,
+                })
+            }
+        };
+        chown(socket.path.as_str(), None, Some(gid)).map_err(|e| DaemonError::SocketOwner {
+            path: socket.path.clone(),
+            group: group.clone(),
+            errno: e,
         })
     }
 
-    /// Run the daemon
-    pub fn run(&self) -> Result<(), DaemonError> {
+    /// Run the daemon until it is asked to stop. Every connection is served on its
+    /// own thread so a slow client (e.g. a long pull or a log follow) never blocks
+    /// the others. Once `connections.max` connections are open, new clients wait in
+    /// the listen backlog until one of them closes.
+    /// - `SIGTERM` and `SIGINT` shut the daemon down gracefully.
+    /// - `SIGHUP` reloads the configuration.
+    /// - `SIGCHLD` reaps the containers that exited.
+    pub fn run(self: &Arc<Self>) -> Result<(), DaemonError> {
+        // signals are handled synchronously through a signalfd. they are blocked before
+        // any thread is spawned so every thread inherits the mask.
+        let mut mask = SigSet::empty();
+        for signal in [
+            Signal::SIGTERM,
+            Signal::SIGINT,
+            Signal::SIGHUP,
+            Signal::SIGCHLD,
+        ] {
+            mask.add(signal);
+        }
+        mask.thread_block()
+            .map_err(|e| DaemonError::BlockSignals { errno: e })?;
+        let mut signals = SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC)
+            .map_err(|e| DaemonError::CreateSignalFd { errno: e })?;
+        self.resume_collecting();
+
         loop {
-            // accept a new connection (connection queue is 5)
-            let conn_fd = accept(self.socket_fd.as_raw_fd()).map_err(|e| {
-                DaemonError::AcceptSocketConnection {
+            let accepting = self.connections().len() < self.config().config.connections.max;
+            let mut fds = vec![PollFd::new(&signals, PollFlags::POLLIN)];
+            if accepting {
+                fds.push(PollFd::new(&self.socket_fd, PollFlags::POLLIN));
+            }
+            // connection threads cannot wake the poll, so a full daemon checks back
+            // regularly for a free slot.
+            let timeout = match accepting {
+                true => -1,
+                false => ACCEPT_BACKOFF.as_millis() as i32,
+            };
+            match poll(&mut fds, timeout) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(errno) => return Err(DaemonError::Poll { errno }),
+            }
+            let signal = fds[0].any().unwrap_or(false);
+            let connection = fds.get(1).and_then(|fd| fd.any()).unwrap_or(false);
+
+            if signal {
+                match signals.read_signal() {
+                    Ok(Some(info)) => match Signal::try_from(info.ssi_signo as i32) {
+                        Ok(Signal::SIGHUP) => self.reload(),
+                        Ok(Signal::SIGCHLD) => self.reap(),
+                        Ok(signal) => {
+                            info!("Received {}, shutting down", signal);
+                            break;
+                        }
+                        Err(_) => {}
+                    },
+                    Ok(None) => {}
+                    Err(errno) => return Err(DaemonError::ReadSignal { errno }),
+                }
+            }
+            if connection {
+                self.accept()?;
+            }
+        }
+
+        self.shutdown();
+        Ok(())
+    }
+
+    /// Accept a pending connection and serve it on a new thread.
+    fn accept(self: &Arc<Self>) -> Result<(), DaemonError> {
+        let conn_fd = match accept(self.socket_fd.as_raw_fd()) {
+            Ok(conn_fd) => conn_fd,
+            // the client went away before the connection was accepted.
+            Err(Errno::EAGAIN) => return Ok(()),
+            Err(errno) if Self::is_transient(errno) => {
+                warn!("Failed to accept connection: {}", errno);
+                // give the system a moment to free up resources.
+                if errno != Errno::EINTR && errno != Errno::ECONNABORTED {
+                    thread::sleep(ACCEPT_BACKOFF);
+                }
+                return Ok(());
+            }
+            Err(errno) => {
+                return Err(DaemonError::AcceptSocketConnection {
                     fd: self.socket_fd.as_raw_fd(),
-                    errno: e,
+                    errno,
+                })
+            }
+        };
+        let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        debug!("Accepted connection {} on socket {}", id, conn_fd);
+
+        // the stream owns the connection and closes it when dropped, also when the
+        // connection could not be tracked or the thread could not be spawned.
+        let stream = unsafe { UnixStream::from_raw_fd(conn_fd) };
+        let tracked = match stream.try_clone() {
+            Ok(tracked) => tracked,
+            Err(err) => {
+                error!("Failed to track connection {}: {}", id, err);
+                return Ok(());
+            }
+        };
+        let open = {
+            let mut connections = self.connections();
+            connections.insert(id, tracked);
+            connections.len()
+        };
+        let max = self.config().config.connections.max;
+        if open >= max {
+            warn!(
+                "Serving the maximum of {} connections, new clients wait until one closes",
+                max
+            );
+        }
+
+        let daemon = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name(format!("connection-{}", id))
+            .spawn(move || daemon.connection(id, stream));
+        if let Err(err) = spawned {
+            error!("Failed to spawn thread for connection {}: {}", id, err);
+            self.connections().remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Whether an `accept` error only affects a single connection or is caused by a
+    /// temporary shortage of resources, rather than a broken listening socket.
+    fn is_transient(errno: Errno) -> bool {
+        matches!(
+            errno,
+            Errno::EINTR
+                | Errno::ECONNABORTED
+                | Errno::EPROTO
+                | Errno::EMFILE
+                | Errno::ENFILE
+                | Errno::ENOBUFS
+                | Errno::ENOMEM
+        )
+    }
+
+    /// Serve a connection and report how it ended. A failing or panicking connection
+    /// never affects the others.
+    fn connection(&self, id: u64, stream: UnixStream) {
+        logger::with_context(
+            |context| context.connection = Some(id),
+            || match panic::catch_unwind(AssertUnwindSafe(|| self.serve(stream))) {
+                Ok(Ok(())) => debug!("Connection closed"),
+                Ok(Err(err)) => error!("Connection failed: {}", err),
+                Err(_) => error!("Connection panicked"),
+            },
+        );
+        self.connections().remove(&id);
+        self.drained.notify_all();
+    }
+
+    /// Shut the daemon down gracefully.
+    /// - The socket file is removed so no new clients can connect.
+    /// - Open connections stop reading requests while the requests in flight finish.
+    /// - Connections still busy after `shutdown_timeout` seconds are cancelled.
+    /// - Running containers are stopped or left running, see `shutdown.containers`.
+    /// - The pid file is cleared, its lock is held until the process exits.
+    fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // a socket passed by a service manager belongs to it.
+        if let Some(socket_path) = &self.socket_path {
+            if let Err(err) = fs::remove_file(socket_path) {
+                error!("Failed to remove socket file {}: {}", socket_path, err);
+            }
+        }
+
+        let timeout = Duration::from_secs(self.config().config.shutdown_timeout);
+        let connections = self.connections();
+        if !connections.is_empty() {
+            info!(
+                "Waiting up to {:?} for {} connection(s) to finish",
+                timeout,
+                connections.len()
+            );
+        }
+        for stream in connections.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let (connections, _) = self
+            .drained
+            .wait_timeout_while(connections, timeout, |connections| !connections.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // writes to a cancelled connection fail, which aborts the requests using it.
+        if !connections.is_empty() {
+            warn!(
+                "Cancelling {} connection(s) still busy after {:?}",
+                connections.len(),
+                timeout
+            );
+            for stream in connections.values() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        drop(connections);
+        self.stop_containers();
+        self.pid_file.remove();
+        info!("Shutdown complete");
+    }
+
+    /// Stop every running container on shutdown, all at once, unless they are to be
+    /// left running. Containers that cannot be stopped are left running.
+    fn stop_containers(&self) {
+        let (shutdown, stop_signal) = {
+            let config = self.config();
+            (
+                config.config.shutdown.clone(),
+                config.config.stop_signal.clone(),
+            )
+        };
+        let running = self
+            .containers()
+            .values()
+            .filter(|container| container.status == Status::Running)
+            .cloned()
+            .collect::<Vec<_>>();
+        if shutdown.containers == ShutdownContainers::Leave || running.is_empty() {
+            return;
+        }
+        let signal = match container::parse_signal(&stop_signal) {
+            Ok(signal) => signal,
+            Err(err) => {
+                error!("Not stopping containers: {}", err);
+                return;
+            }
+        };
+        let timeout = Duration::from_secs(shutdown.timeout);
+        info!("Stopping {} container(s)", running.len());
+        thread::scope(|scope| {
+            for container in &running {
+                scope.spawn(move || {
+                    if let Err(err) = self.stop_container(container, signal, timeout) {
+                        error!("Failed to stop container {}: {}", container.id, err);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Reload the configuration from disk. The current configuration is kept if the
+    /// new one cannot be read. The logging settings are applied again, which also resets
+    /// a log level changed at runtime.
+    fn reload(&self) {
+        match ConfigHolder::<Config>::new(CONFIG_FILE_NAME) {
+            Ok(config) => {
+                if config.config.socket != self.config().config.socket {
+                    warn!("Socket settings changed, restart the daemon to apply them");
                 }
-            })?;
-            println!("[INFO] Accepted new connection: {:?}", conn_fd);
+                if let Err(err) = logger::init(&config.config.logging) {
+                    error!("Failed to apply logging settings: {}", err);
+                }
+                *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+                info!("Reloaded configuration");
+            }
+            Err(err) => error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                err
+            ),
+        }
+    }
 
-            // read the header
-            let header = Protocol::read_header(self.socket_fd.as_raw_fd(), conn_fd)?;
-            if header._type != protocol::Type::Request {
-                println!("[ERROR] Invalid message type: {:?}", header._type);
+    /// Reap the containers whose init exited and record their exit code. Only the
+    /// pids of containers are waited for, other children are left to their owners.
+    /// Containers started by a previous run of the daemon are not its children, their
+    /// exit code is unknown.
+    fn reap(&self) {
+        let containers_dir = self.config().config.containers_dir.clone();
+        for container in self.containers().values_mut() {
+            let (Some(pid), Status::Running) = (container.pid, container.status) else {
                 continue;
+            };
+            let code = match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, code)) => code,
+                Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+                Ok(_) => continue,
+                Err(Errno::ECHILD) if !container.is_alive() => container::UNKNOWN_EXIT_CODE,
+                Err(Errno::ECHILD) => continue,
+                Err(errno) => {
+                    warn!("Failed to wait for container {}: {}", container.id, errno);
+                    continue;
+                }
+            };
+            container.exit(code);
+            info!(
+                "Container {} ({}) exited with code {}",
+                container.short_id(),
+                container.name,
+                code
+            );
+            if let Err(err) = container.save(&containers_dir) {
+                error!("{}", err);
             }
-            self.execute_command(header, conn_fd)?;
+        }
+        self.exited.notify_all();
+    }
 
-            // close the connection
-            close(conn_fd).map_err(|e| DaemonError::CloseSocketConnection {
-                socket_fd: self.socket_fd.as_raw_fd(),
-                conn_fd: conn_fd,
-                errno: e,
-            })?;
+    /// The current configuration.
+    pub fn config(&self) -> RwLockReadGuard<'_, ConfigHolder<Config>> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The open connections, by connection id.
+    fn connections(&self) -> MutexGuard<'_, HashMap<u64, UnixStream>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The containers created by the daemon, by id.
+    fn containers(&self) -> MutexGuard<'_, HashMap<String, Container>> {
+        self.containers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The collectors of the output of containers, locked.
+    fn collectors(&self) -> MutexGuard<'_, HashMap<String, JoinHandle<()>>> {
+        self.collectors
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The image index, locked and in line with the image store.
+    fn images(&self) -> Result<MutexGuard<'_, Index>, DaemonError> {
+        let images_dir = self.config().config.images_dir.clone();
+        let mut index = self.images.lock().unwrap_or_else(PoisonError::into_inner);
+        if index.scan(&images_dir) {
+            index.save(&images_dir)?;
+        }
+        Ok(index)
+    }
+
+    /// Serve a connection until the client closes it. Requests are read one after the
+    /// other; with the `MULTIPLEX` capability each one is handled on its own thread so
+    /// their responses interleave, otherwise they are answered in order.
+    pub fn serve(&self, stream: UnixStream) -> Result<(), DaemonError> {
+        // every request is authorized against the identity of the peer.
+        let peer = Peer::from_stream(&stream)?;
+        logger::with_context(
+            |context| context.uid = Some(peer.uid.as_raw()),
+            || self.serve_peer(stream, &peer),
+        )
+    }
+
+    /// Serve a connection from an identified peer.
+    /// - The handshake, and every message once it started, must arrive within
+    ///   `connections.read_timeout`.
+    /// - The connection is closed once it was idle for `connections.idle_timeout`
+    ///   without requests in flight.
+    /// - At most `connections.max_requests` requests are handled at once.
+    fn serve_peer(&self, mut stream: UnixStream, peer: &Peer) -> Result<(), DaemonError> {
+        info!("Connection from {}", peer);
+        let limits = self.config().config.connections.clone();
+        let read_timeout = Some(Duration::from_secs(limits.read_timeout)).filter(|t| !t.is_zero());
+        let idle_timeout = Duration::from_secs(limits.idle_timeout);
+        stream
+            .set_read_timeout(read_timeout)
+            .map_err(|e| SharedError::IO { source: e })?;
+        let timed_out = |err: DaemonError| match err {
+            DaemonError::Shared(SharedError::ReadConnection { source })
+                if matches!(
+                    source.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                DaemonError::ReadTimeout {
+                    timeout: read_timeout.unwrap_or_default(),
+                }
+            }
+            err => err,
+        };
+
+        // negotiate the protocol before reading any request.
+        let handshake = self.handshake(&mut stream).map_err(timed_out)?;
+        debug!(
+            "Negotiated protocol version {} with capabilities {}",
+            handshake.version, handshake.capabilities
+        );
+        let multiplex = handshake.capabilities.contains(Capabilities::MULTIPLEX);
+        let writer = SharedWriter::new(
+            stream
+                .try_clone()
+                .map_err(|e| SharedError::IO { source: e })?,
+        );
+
+        // requests handled on other threads are logged in the context of the connection.
+        let context = logger::context();
+
+        // requests still in flight are finished before the connection is dropped.
+        let in_flight = Slots::new(limits.max_requests);
+        thread::scope(|scope| loop {
+            if !self.wait_request(&stream, idle_timeout, &in_flight)? {
+                debug!("Closing connection idle for {:?}", idle_timeout);
+                return Ok(());
+            }
+            let frame = match Protocol::read_frame(&mut stream, self.max_body_size()) {
+                Ok(frame) => frame,
+                Err(SharedError::ConnectionClosed) => return Ok(()),
+                Err(err) => return Err(timed_out(err.into())),
+            };
+            let writer = writer.clone();
+            match multiplex {
+                true => {
+                    // no further request is read until a slot is free.
+                    let slot = in_flight.acquire();
+                    thread::Builder::new()
+                        .spawn_scoped(scope, move || {
+                            self.request(frame, writer, &handshake, peer, context);
+                            drop(slot);
+                        })
+                        .map_err(|e| DaemonError::SpawnRequest { source: e })?;
+                }
+                false => self.request(frame, writer, &handshake, peer, context),
+            }
+        })
+    }
+
+    /// Wait until the next request starts to arrive on a connection. Returns `false`
+    /// once the connection was idle for `idle_timeout` without requests in flight.
+    fn wait_request(
+        &self,
+        stream: &UnixStream,
+        idle_timeout: Duration,
+        in_flight: &Slots,
+    ) -> Result<bool, DaemonError> {
+        let timeout = match idle_timeout.is_zero() {
+            true => -1,
+            false => idle_timeout.as_millis().min(i32::MAX as u128) as i32,
+        };
+        loop {
+            let mut fds = [PollFd::new(stream, PollFlags::POLLIN)];
+            match poll(&mut fds, timeout) {
+                // a closed connection is readable too, reading reports it.
+                Ok(0) if in_flight.used() == 0 => return Ok(false),
+                Ok(0) => {}
+                Ok(_) => return Ok(true),
+                Err(Errno::EINTR) => {}
+                Err(errno) => return Err(DaemonError::PollConnection { errno }),
+            }
         }
     }
 
-    /// Execute a command based on the parsed CLI arguments.
-    pub fn execute_command(&self, header: Header, conn_fd: i32) -> Result<(), DaemonError> {
-        match header.command {
-            protocol::Command::Pull => self.pull(header, conn_fd),
-            _ => Ok(()),
+    /// Handle a request read from a connection. If handling it panics, the request is
+    /// answered with an error so the client is not left waiting.
+    fn request(
+        &self,
+        frame: Frame,
+        writer: SharedWriter<UnixStream>,
+        protocol: &Handshake,
+        peer: &Peer,
+        context: Context,
+    ) {
+        let header = frame.header;
+        let update = |current: &mut Context| {
+            *current = Context {
+                command: Some(header.command),
+                request_id: Some(header.request_id),
+                ..context
+            }
+        };
+        logger::with_context(update, || {
+            debug!("Received {:?} message", header._type);
+            let request = StreamWriter::new(writer.clone(), &header, protocol);
+            if panic::catch_unwind(AssertUnwindSafe(|| self.handle(frame, request, peer))).is_err()
+            {
+                let writer = StreamWriter::new(writer, &header, protocol);
+                let error = DaemonError::Panicked {
+                    command: header.command,
+                };
+                if let Err(err) = self.respond::<(), _>(writer, Err(error)) {
+                    error!("Failed to answer request: {}", err);
+                }
+            }
+        })
+    }
+
+    /// Handle a single request read from a connection.
+    pub fn handle<W: Write>(&self, frame: Frame, writer: StreamWriter<W>, peer: &Peer) {
+        let result = match frame.header._type {
+            Type::Request if !self.authorize(peer, &frame.header) => {
+                let error = DaemonError::PermissionDenied {
+                    command: frame.header.command,
+                    peer: peer.to_string(),
+                };
+                self.respond::<(), _>(writer, Err(error))
+            }
+            Type::Request => self.execute_command(frame, writer),
+            _type => {
+                warn!("Invalid message type: {:?}", _type);
+                self.respond::<(), _>(writer, Err(DaemonError::InvalidMessageType { _type }))
+            }
+        };
+        if let Err(err) = result {
+            error!("Failed to answer request: {}", err);
         }
     }
 
+    /// Check whether the peer may run the requested command and record the decision
+    /// in the audit trail.
+    fn authorize(&self, peer: &Peer, request: &Header) -> bool {
+        let config = self.config();
+        let authorization = &config.config.authorization;
+        let allowed = authorization.allows(peer, request.command);
+        let record = Record::new(peer, request, allowed);
+        if let Err(err) = record.write(authorization.audit_log.as_deref()) {
+            error!("Failed to write audit record: {}", err);
+        }
+        allowed
+    }
+
+    /// The maximum accepted length of a message body.
+    pub fn max_body_size(&self) -> u64 {
+        self.config().config.max_body_size
+    }
+
+    /// Perform the protocol handshake on a freshly accepted connection. The daemon
+    /// always answers with its own handshake, also when the one of the client cannot
+    /// be read, so the client can report a mismatch.
+    pub fn handshake(&self, stream: &mut UnixStream) -> Result<Handshake, DaemonError> {
+        let remote = self.read_handshake(stream);
+        let local = Handshake::local();
+        let sent =
+            Protocol::write_handshake(local).and_then(|message| Protocol::send(stream, &message));
+        let remote = remote?;
+        sent?;
+        Ok(local.negotiate(&remote)?)
+    }
+
+    /// Read the handshake of a client.
+    fn read_handshake(&self, stream: &mut UnixStream) -> Result<Handshake, DaemonError> {
+        let header = Protocol::read_header(stream)?;
+        if header._type != Type::Handshake {
+            return Err(DaemonError::InvalidMessageType {
+                _type: header._type,
+            });
+        }
+        Ok(Protocol::read_body::<Handshake, _>(
+            stream,
+            &header,
+            self.max_body_size(),
+        )?)
+    }
+
+    /// Execute a command based on the parsed CLI arguments and write the
+    /// response back to the connection.
+    pub fn execute_command<W: Write>(
+        &self,
+        frame: Frame,
+        writer: StreamWriter<W>,
+    ) -> Result<(), DaemonError> {
+        match frame.header.command {
+            Command::Pull => self.respond(writer, self.pull(&frame)),
+            Command::Run => self.respond(writer, self.run_container(&frame)),
+            Command::Stop => self.respond(writer, self.stop(&frame)),
+            Command::Rm => self.respond(writer, self.rm(&frame)),
+            Command::Ps => self.respond(writer, self.ps(&frame)),
+            Command::Images => self.respond(writer, self.list_images(&frame)),
+            Command::Tag => self.respond(writer, self.tag(&frame)),
+            Command::Untag => self.respond(writer, self.untag(&frame)),
+            Command::LogLevel => self.respond(writer, self.log_level(&frame)),
+            Command::Logs => {
+                let mut writer = writer;
+                let result = self.logs(&frame, &mut writer);
+                self.respond(writer, result)
+            }
+            Command::Stats => {
+                let mut writer = writer;
+                let result = self.stats(&frame, &mut writer);
+                self.respond(writer, result)
+            }
+            command => self.respond::<(), _>(writer, Err(DaemonError::Unimplemented { command })),
+        }
+    }
+
+    /// Wrap the result of a command in a `Response` envelope and finish the response with it.
+    /// The response echoes the command and request id of the request.
+    pub fn respond<T: Serialize, W: Write>(
+        &self,
+        writer: StreamWriter<W>,
+        result: Result<T, DaemonError>,
+    ) -> Result<(), DaemonError> {
+        let response = match result {
+            Ok(payload) => Response::Success(payload),
+            Err(err) => {
+                error!("Command {:?} failed: {}", writer.command(), err);
+                Response::Error(err.to_response())
+            }
+        };
+        writer.end(response)?;
+        Ok(())
+    }
+
     /// The `pull` command
-    pub fn pull(&self, header: Header, conn_fd: i32) -> Result<(), DaemonError> {
+    pub fn pull(&self, frame: &Frame) -> Result<PullResponse, DaemonError> {
         // read the body
-        let body =
-            Protocol::read_body::<PullRequest>(self.socket_fd.as_raw_fd(), conn_fd, header.length)?;
+        let _body = frame.decode::<PullRequest>()?;
 
-        Ok(())
+        // fetching images from the registry is not implemented yet.
+        Err(DaemonError::Unimplemented {
+            command: Command::Pull,
+        })
+    }
+
+    /// The `run` command. Creates a container from an unpacked image and starts its
+    /// command in new namespaces. Answers with the id once the command is running.
+    pub fn run_container(&self, frame: &Frame) -> Result<RunResponse, DaemonError> {
+        let request = frame.decode::<RunRequest>()?;
+        if request.command.is_empty() {
+            return Err(DaemonError::InvalidRequest {
+                reason: "no command given".to_string(),
+            });
+        }
+        if let Some(variable) = request.env.iter().find(|variable| !variable.contains('=')) {
+            return Err(DaemonError::InvalidRequest {
+                reason: format!("{} is not a KEY=VALUE pair", variable),
+            });
+        }
+        for path in &request.volumes {
+            volume::check_path(path)?;
+        }
+        if let Some(name) = &request.name {
+            self.check_name(name)?;
+        }
+        let id_maps = IdMaps::new(&request.uid_map, &request.gid_map)?;
+        let (images_dir, containers_dir, volumes_dir, storage, cgroups) = {
+            let config = self.config();
+            (
+                config.config.images_dir.clone(),
+                config.config.containers_dir.clone(),
+                config.config.volumes_dir(),
+                config.config.storage.clone(),
+                config.config.cgroups.clone(),
+            )
+        };
+
+        let dir = self.images()?.resolve(&request.image)?.dir.clone();
+        let layers = image::layers(&images_dir, &dir)?;
+        let mut container = Container::create(
+            &containers_dir,
+            &storage,
+            request.name,
+            &request.image,
+            &layers,
+            request.command,
+        )?;
+        match Cgroup::create(&cgroups, &container.id, &request.resources) {
+            Ok(cgroup) => container.cgroup = cgroup,
+            Err(err) => {
+                self.discard(&container, &containers_dir);
+                return Err(err);
+            }
+        }
+        container.labels = request.labels;
+        let (uid, gid) = id_maps.root();
+        for path in &request.volumes {
+            match Volume::create(&volumes_dir, path, uid, gid) {
+                Ok(volume) => container.volumes.push(volume),
+                Err(err) => {
+                    self.discard(&container, &containers_dir);
+                    return Err(err);
+                }
+            }
+        }
+        if let Err(err) = container.save(&containers_dir) {
+            self.discard(&container, &containers_dir);
+            return Err(err);
+        }
+        let id = container.id.clone();
+        info!(
+            "Created container {} ({}) from {}",
+            container.short_id(),
+            container.name,
+            container.image
+        );
+
+        // the name is checked again now that the container takes it.
+        {
+            let mut containers = self.containers();
+            if let Some(other) = containers
+                .values()
+                .find(|other| other.name == container.name)
+            {
+                let error = DaemonError::NameInUse {
+                    name: container.name.clone(),
+                    id: other.id.clone(),
+                };
+                drop(containers);
+                self.discard(&container, &containers_dir);
+                return Err(error);
+            }
+            containers.insert(id.clone(), container.clone());
+        }
+
+        let hostname = request
+            .hostname
+            .unwrap_or_else(|| container.short_id().to_string());
+        let rootfs = container.rootfs(&containers_dir);
+        let (capture, [stdout, stderr]) = match logs::create(&container.dir(&containers_dir)) {
+            Ok(output) => output,
+            Err(err) => {
+                self.containers().remove(&id);
+                self.discard(&container, &containers_dir);
+                return Err(err);
+            }
+        };
+        let volumes = container
+            .volumes
+            .iter()
+            .map(|volume| (volume.dir(&volumes_dir), volume.path.clone()))
+            .collect::<Vec<_>>();
+        let spec = Spec {
+            rootfs: &rootfs,
+            volumes: &volumes,
+            hostname: &hostname,
+            command: &container.command,
+            env: &request.env,
+            id_maps: &id_maps,
+            cgroup: container.cgroup.as_ref(),
+            stdout: stdout.as_fd(),
+            stderr: stderr.as_fd(),
+        };
+        let pid = match process::spawn(&spec) {
+            Ok(pid) => pid,
+            Err(err) => {
+                self.containers().remove(&id);
+                self.discard(&container, &containers_dir);
+                return Err(err);
+            }
+        };
+        // the log ends once the processes of the container closed their fifos.
+        drop((stdout, stderr));
+        self.collect(&container, capture);
+        container.start(pid);
+        info!(
+            "Started container {} ({}) with pid {}",
+            container.short_id(),
+            container.name,
+            pid
+        );
+        if let Err(err) = container.save(&containers_dir) {
+            error!("{}", err);
+        }
+        self.containers().insert(id.clone(), container);
+
+        // the init may have exited before it was tracked.
+        self.reap();
+        Ok(RunResponse { id })
+    }
+
+    /// Collect the output of the containers that kept running while the daemon was not,
+    /// including what they wrote in the meantime.
+    fn resume_collecting(&self) {
+        let containers_dir = self.config().config.containers_dir.clone();
+        let running = self
+            .containers()
+            .values()
+            .filter(|container| container.status == Status::Running)
+            .cloned()
+            .collect::<Vec<_>>();
+        for container in running {
+            match logs::open(&container.dir(&containers_dir)) {
+                Ok(capture) => self.collect(&container, capture),
+                Err(err) => warn!(
+                    "Not collecting the output of container {}: {}",
+                    container.id, err
+                ),
+            }
+        }
+    }
+
+    /// Collect the output of `container` into its log on a thread of its own.
+    fn collect(&self, container: &Container, capture: Capture) {
+        let spawned = thread::Builder::new()
+            .name(format!("logs-{}", container.short_id()))
+            .spawn(move || logs::collect(capture));
+        match spawned {
+            Ok(collector) => {
+                self.collectors().insert(container.id.clone(), collector);
+            }
+            Err(err) => error!(
+                "Failed to spawn thread collecting the output of container {}: {}",
+                container.id, err
+            ),
+        }
+    }
+
+    /// Fail if a container already has the name.
+    fn check_name(&self, name: &str) -> Result<(), DaemonError> {
+        match self.containers().values().find(|other| other.name == name) {
+            Some(other) => Err(DaemonError::NameInUse {
+                name: name.to_string(),
+                id: other.id.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait up to `timeout` for container `id` to be reaped. Returns its exit code, or
+    /// `None` if it is still running. Containers of a previous run of the daemon do not
+    /// raise `SIGCHLD`, so they are polled.
+    fn wait_exited(&self, id: &str, timeout: Duration) -> Option<i32> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.reap();
+            let running = |containers: &mut HashMap<String, Container>| {
+                matches!(
+                    containers.get(id).map(|container| container.status),
+                    Some(Status::Running)
+                )
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (containers, _) = self
+                .exited
+                .wait_timeout_while(self.containers(), remaining.min(REAP_INTERVAL), running)
+                .unwrap_or_else(PoisonError::into_inner);
+            match containers.get(id).map(|container| container.status) {
+                Some(Status::Exited { code }) => return Some(code),
+                Some(Status::Running) if !remaining.is_zero() => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// Find a container by id, unique id prefix or name.
+    fn find(&self, container: &str) -> Result<Container, DaemonError> {
+        let containers = self.containers();
+        if let Some(found) = containers.get(container) {
+            return Ok(found.clone());
+        }
+        if let Some(found) = containers.values().find(|found| found.name == container) {
+            return Ok(found.clone());
+        }
+        let mut matches = containers
+            .values()
+            .filter(|found| !container.is_empty() && found.id.starts_with(container));
+        match (matches.next(), matches.next()) {
+            (Some(found), None) => Ok(found.clone()),
+            (Some(_), Some(_)) => Err(DaemonError::AmbiguousContainer {
+                container: container.to_string(),
+            }),
+            (None, _) => Err(DaemonError::ContainerNotFound {
+                container: container.to_string(),
+            }),
+        }
+    }
+
+    /// Remove a container that could not be started, along with its volumes.
+    fn discard(&self, container: &Container, containers_dir: &str) {
+        if let Err(err) = container.remove(containers_dir) {
+            error!("Failed to remove container {}: {}", container.id, err);
+        }
+        self.remove_volumes(container);
+    }
+
+    /// Remove the volumes of a container, logging the ones that cannot be removed.
+    fn remove_volumes(&self, container: &Container) {
+        let volumes_dir = self.config().config.volumes_dir();
+        for volume in &container.volumes {
+            if let Err(err) = volume.remove(&volumes_dir) {
+                error!("{}", err);
+            }
+        }
+    }
+
+    /// The `stop` command. Sends the stop signal to the init of the container and
+    /// waits for it to exit. Once the timeout has passed every process of the container
+    /// is killed. Answers with the exit code, a container that already exited answers
+    /// with the code it exited with.
+    pub fn stop(&self, frame: &Frame) -> Result<StopResponse, DaemonError> {
+        let request = frame.decode::<StopRequest>()?;
+        let (stop_signal, stop_timeout) = {
+            let config = self.config();
+            (
+                config.config.stop_signal.clone(),
+                config.config.stop_timeout,
+            )
+        };
+        let signal = container::parse_signal(request.signal.as_deref().unwrap_or(&stop_signal))?;
+        let timeout = Duration::from_secs(request.timeout.unwrap_or(stop_timeout));
+        let container = self.find(&request.container)?;
+        let response = |code: i32, killed: bool| StopResponse {
+            id: container.id.clone(),
+            code,
+            killed,
+        };
+        match container.status {
+            Status::Exited { code } => return Ok(response(code, false)),
+            Status::Created => {
+                return Err(DaemonError::NotRunning {
+                    id: container.id.clone(),
+                })
+            }
+            Status::Running => {}
+        }
+
+        let (code, killed) = self.stop_container(&container, signal, timeout)?;
+        Ok(response(code, killed))
+    }
+
+    /// Send `signal` to the init of a running container and wait up to `timeout` for it
+    /// to exit, then kill every process of the container. Returns the exit code and
+    /// whether the container had to be killed.
+    fn stop_container(
+        &self,
+        container: &Container,
+        signal: Signal,
+        timeout: Duration,
+    ) -> Result<(i32, bool), DaemonError> {
+        info!(
+            "Stopping container {} ({}) with {}",
+            container.short_id(),
+            container.name,
+            signal
+        );
+        container.signal(signal)?;
+        if let Some(code) = self.wait_exited(&container.id, timeout) {
+            return Ok((code, false));
+        }
+        warn!(
+            "Container {} ({}) did not exit within {:?}, killing it",
+            container.short_id(),
+            container.name,
+            timeout
+        );
+        let code = self.kill(container)?;
+        Ok((code, true))
+    }
+
+    /// Kill every process of a running container and wait for it to be reaped.
+    fn kill(&self, container: &Container) -> Result<i32, DaemonError> {
+        container.kill()?;
+        self.wait_exited(&container.id, KILL_TIMEOUT)
+            .ok_or_else(|| DaemonError::StopTimeout {
+                id: container.id.clone(),
+            })
     }
+
+    /// The `rm` command. Removes every requested container on its own, a container
+    /// that cannot be removed does not keep the others from being removed.
+    pub fn rm(&self, frame: &Frame) -> Result<RmResponse, DaemonError> {
+        let request = frame.decode::<RmRequest>()?;
+        if request.containers.is_empty() {
+            return Err(DaemonError::InvalidRequest {
+                reason: "no container given".to_string(),
+            });
+        }
+        let results = request
+            .containers
+            .iter()
+            .map(|container| match self.remove(container, &request) {
+                Ok(id) => RmResult {
+                    container: container.clone(),
+                    id: Some(id),
+                    error: None,
+                },
+                Err(err) => {
+                    warn!("Failed to remove container {}: {}", container, err);
+                    RmResult {
+                        container: container.clone(),
+                        id: None,
+                        error: Some(err.to_string()),
+                    }
+                }
+            })
+            .collect();
+        Ok(RmResponse { results })
+    }
+
+    /// Remove a container as `request` asks, stopping it first if it is forced. Its
+    /// record is only dropped once it is gone, so a failed removal can be tried again.
+    fn remove(&self, container: &str, request: &RmRequest) -> Result<String, DaemonError> {
+        let container = self.find(container)?;
+        match (container.status, request.force) {
+            (Status::Exited { .. }, _) => {}
+            (Status::Running, true) => {
+                let (stop_signal, stop_timeout) = {
+                    let config = self.config();
+                    (
+                        config.config.stop_signal.clone(),
+                        config.config.stop_timeout,
+                    )
+                };
+                let signal = container::parse_signal(&stop_signal)?;
+                self.stop_container(&container, signal, Duration::from_secs(stop_timeout))?;
+            }
+            (Status::Running, false) => {
+                return Err(DaemonError::ContainerRunning {
+                    id: container.id.clone(),
+                })
+            }
+            // its start is in progress and would continue in a removed directory.
+            (Status::Created, _) => {
+                return Err(DaemonError::ContainerStarting {
+                    id: container.id.clone(),
+                })
+            }
+        }
+        // the collector appends to the log until the container closed its fifos.
+        let collector = self.collectors().remove(&container.id);
+        if let Some(collector) = collector {
+            self.join_collector(&container, collector);
+        }
+        let containers_dir = self.config().config.containers_dir.clone();
+        container.remove(&containers_dir)?;
+        self.containers().remove(&container.id);
+        if request.volumes {
+            self.remove_volumes(&container);
+        }
+        info!(
+            "Removed container {} ({})",
+            container.short_id(),
+            container.name
+        );
+        Ok(container.id)
+    }
+
+    /// Wait for the collector of an exited container to finish. One still running after
+    /// `KILL_TIMEOUT`, e.g. because a process of the container escaped, is left behind.
+    fn join_collector(&self, container: &Container, collector: JoinHandle<()>) {
+        let start = Instant::now();
+        while !collector.is_finished() && start.elapsed() < KILL_TIMEOUT {
+            thread::sleep(FOLLOW_INTERVAL);
+        }
+        match collector.is_finished() {
+            true => {
+                let _ = collector.join();
+            }
+            false => warn!(
+                "Still collecting the output of container {}, removing its log anyway",
+                container.id
+            ),
+        }
+    }
+
+    /// The `ps` command. Lists the containers matching the filters of the request.
+    pub fn ps(&self, frame: &Frame) -> Result<PsResponse, DaemonError> {
+        let request = frame.decode::<PsRequest>()?;
+        for filter in &request.filters {
+            match filter {
+                Filter::Status(status)
+                    if !["created", "running", "exited"].contains(&status.as_str()) =>
+                {
+                    return Err(DaemonError::InvalidRequest {
+                        reason: format!(
+                            "unknown status {}, expected created, running or exited",
+                            status
+                        ),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        let matches = |container: &Container| {
+            // filters of the same kind are alternatives.
+            let mut kinds = HashMap::new();
+            for filter in &request.filters {
+                let (kind, matched) = match filter {
+                    Filter::Status(status) => ("status", container.status.name() == status),
+                    Filter::Label { key, value } => (
+                        "label",
+                        match value {
+                            Some(value) => container.labels.get(key) == Some(value),
+                            None => container.labels.contains_key(key),
+                        },
+                    ),
+                    Filter::Image(image) => ("image", &container.image == image),
+                    Filter::Name(name) => ("name", container.name.contains(name.as_str())),
+                };
+                *kinds.entry(kind).or_insert(false) |= matched;
+            }
+            let listed =
+                request.all || kinds.contains_key("status") || container.status == Status::Running;
+            listed && kinds.values().all(|matched| *matched)
+        };
+        let mut containers = self
+            .containers()
+            .values()
+            .filter(|container| matches(container))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // the id breaks ties so the order is stable.
+        containers.sort_by(|a, b| match request.sort {
+            None => (b.created, &b.id).cmp(&(a.created, &a.id)),
+            Some(SortKey::Created) => (a.created, &a.id).cmp(&(b.created, &b.id)),
+            Some(SortKey::Name) => a.name.cmp(&b.name),
+            Some(SortKey::Image) => (&a.image, &a.id).cmp(&(&b.image, &b.id)),
+            Some(SortKey::Status) => (a.status.name(), &a.id).cmp(&(b.status.name(), &b.id)),
+        });
+        if request.reverse {
+            containers.reverse();
+        }
+        Ok(PsResponse {
+            containers: containers
+                .into_iter()
+                .map(|container| ContainerSummary {
+                    exit_code: match container.status {
+                        Status::Exited { code } => Some(code),
+                        _ => None,
+                    },
+                    status: container.status.name().to_string(),
+                    pid: match container.status {
+                        Status::Running => container.pid.map(|pid| pid.as_raw()),
+                        _ => None,
+                    },
+                    id: container.id,
+                    name: container.name,
+                    image: container.image,
+                    command: container.command,
+                    created: container.created,
+                    started: container.started,
+                    finished: container.finished,
+                    labels: container.labels,
+                })
+                .collect(),
+        })
+    }
+
+    /// The `images` command. Lists the images of the store matching the filters of the
+    /// request, newest first. The store is scanned first so images unpacked into it
+    /// are listed too.
+    pub fn list_images(&self, frame: &Frame) -> Result<ImagesResponse, DaemonError> {
+        let request = frame.decode::<ImagesRequest>()?;
+        let index = self.images()?;
+
+        let matches = |image: &Image| {
+            // filters of the same kind are alternatives.
+            let mut kinds = HashMap::new();
+            for filter in &request.filters {
+                let kind = match filter {
+                    ImageFilter::Dangling(_) => "dangling",
+                    ImageFilter::Reference(_) => "reference",
+                    ImageFilter::Label { .. } => "label",
+                };
+                *kinds.entry(kind).or_insert(false) |= image.matches(filter);
+            }
+            kinds.values().all(|matched| *matched)
+        };
+        let mut images = index
+            .images
+            .iter()
+            .filter(|image| matches(image))
+            .collect::<Vec<_>>();
+        images.sort_by(|a, b| (b.created, &b.digest).cmp(&(a.created, &a.digest)));
+        Ok(ImagesResponse {
+            images: images
+                .into_iter()
+                .map(|image| ImageSummary {
+                    digest: image.digest.clone(),
+                    tags: image.tags.clone(),
+                    size: image.size,
+                    created: image.created,
+                    layers: image
+                        .layers
+                        .iter()
+                        .map(|layer| layer.digest.clone())
+                        .collect(),
+                    labels: image.labels.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    /// The `tag` command. Tags an image with another reference.
+    pub fn tag(&self, frame: &Frame) -> Result<TagResponse, DaemonError> {
+        let request = frame.decode::<TagRequest>()?;
+        let images_dir = self.config().config.images_dir.clone();
+        let mut index = self.images()?;
+        let (digest, reference) = index.tag(&request.source, &request.target)?;
+        index.save(&images_dir)?;
+        info!("Tagged image {} as {}", digest, reference);
+        Ok(TagResponse { digest, reference })
+    }
+
+    /// The `untag` command. Removes a tag from an image, which stays in the store.
+    pub fn untag(&self, frame: &Frame) -> Result<UntagResponse, DaemonError> {
+        let request = frame.decode::<UntagRequest>()?;
+        let images_dir = self.config().config.images_dir.clone();
+        let mut index = self.images()?;
+        let (digest, reference) = index.untag(&request.reference)?;
+        index.save(&images_dir)?;
+        info!("Untagged {} from image {}", reference, digest);
+        Ok(UntagResponse { digest, reference })
+    }
+
+    /// The `logs` command. Streams the last `tail` lines of the log of a container that
+    /// were written between `since` and `until`. Following the log streams new lines
+    /// as they are written until the container exited and all of its output was read,
+    /// `until` has passed, the client went away or the daemon shuts down.
+    pub fn logs<W: Write>(
+        &self,
+        frame: &Frame,
+        writer: &mut StreamWriter<W>,
+    ) -> Result<(), DaemonError> {
+        let request = frame.decode::<LogsRequest>()?;
+        let container = self.find(&request.container)?;
+        let now = container::now();
+        let since = request
+            .since
+            .as_deref()
+            .map(|since| logs::parse_time(since, now))
+            .transpose()?;
+        let until = request
+            .until
+            .as_deref()
+            .map(|until| logs::parse_time(until, now))
+            .transpose()?;
+        let within = |record: &logs::Record| {
+            since.is_none_or(|since| record.time >= since)
+                && until.is_none_or(|until| record.time < until)
+        };
+
+        let path = container.log(&self.config().config.containers_dir);
+        let read_error = |source: io::Error| DaemonError::ContainerLog {
+            path: path.display().to_string(),
+            source,
+        };
+        let mut reader = match File::open(&path) {
+            Ok(log) => Reader::new(log),
+            // containers created before their output was captured have no log.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(read_error(err)),
+        };
+
+        let mut lines = VecDeque::new();
+        while let Some(record) = reader.next().map_err(read_error)? {
+            if within(&record) {
+                lines.push_back(record);
+                if request.tail.is_some_and(|tail| lines.len() > tail) {
+                    lines.pop_front();
+                }
+            }
+        }
+        for record in lines {
+            let line = record.render(request.timestamps);
+            writer.chunk(record.stream.output(), line.as_bytes())?;
+        }
+        if !request.follow || !writer.is_streaming() {
+            return Ok(());
+        }
+
+        loop {
+            // lines written before the collector finished are still read below.
+            let finished = self
+                .collectors()
+                .get(&container.id)
+                .is_none_or(JoinHandle::is_finished);
+            while let Some(record) = reader.next().map_err(read_error)? {
+                if within(&record) {
+                    let line = record.render(request.timestamps);
+                    writer.chunk(record.stream.output(), line.as_bytes())?;
+                }
+            }
+            let passed = until.is_some_and(|until| container::now() >= until);
+            if finished || passed || self.stopping.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+
+    /// The `stats` command. Reads the counters of the cgroups of the requested
+    /// containers, or of all running ones. A streaming request samples them again every
+    /// interval until the requested containers have exited, the client went away or
+    /// the daemon shuts down, and answers with the last sample.
+    pub fn stats<W: Write>(
+        &self,
+        frame: &Frame,
+        writer: &mut StreamWriter<W>,
+    ) -> Result<StatsResponse, DaemonError> {
+        let request = frame.decode::<StatsRequest>()?;
+        let interval = match request.interval.unwrap_or(DEFAULT_STATS_INTERVAL) {
+            0 => {
+                return Err(DaemonError::InvalidRequest {
+                    reason: "the interval must be positive".to_string(),
+                })
+            }
+            interval => Duration::from_millis(interval),
+        };
+        loop {
+            let containers = match request.containers.is_empty() {
+                true => {
+                    let mut running = self
+                        .containers()
+                        .values()
+                        .filter(|container| container.status == Status::Running)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    running.sort_by(|a, b| a.name.cmp(&b.name));
+                    running
+                }
+                false => request
+                    .containers
+                    .iter()
+                    .map(|container| self.find(container))
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            let sample = StatsResponse {
+                containers: containers
+                    .iter()
+                    .map(|container| ContainerStats {
+                        id: container.id.clone(),
+                        name: container.name.clone(),
+                        ..container
+                            .cgroup
+                            .as_ref()
+                            .map(Cgroup::stats)
+                            .unwrap_or_default()
+                    })
+                    .collect(),
+            };
+
+            let exited = !request.containers.is_empty()
+                && containers
+                    .iter()
+                    .all(|container| container.status != Status::Running);
+            if !request.stream
+                || !writer.is_streaming()
+                || exited
+                || self.stopping.load(Ordering::SeqCst)
+            {
+                return Ok(sample);
+            }
+            writer.sample(&sample)?;
+            thread::sleep(interval);
+        }
+    }
+
+    /// The `log-level` command. Changes the log level until the configuration is
+    /// reloaded and answers with the level in effect.
+    pub fn log_level(&self, frame: &Frame) -> Result<LogLevelResponse, DaemonError> {
+        let body = frame.decode::<LogLevelRequest>()?;
+        if let Some(level) = body.level {
+            let filter = level
+                .parse::<LevelFilter>()
+                .map_err(|_| DaemonError::InvalidLogLevel { level })?;
+            logger::set_level(filter);
+            info!("Changed log level to {}", filter);
+        }
+        Ok(LogLevelResponse {
+            level: logger::level().to_string().to_lowercase(),
+        })
+    }
+}
+
+/// A counting semaphore bounding the requests handled at once on a connection.
+struct Slots {
+    used: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Slots {
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+            // a connection always handles at least one request at a time.
+            max: max.max(1),
+        }
+    }
+
+    /// Take a slot, waiting for one to be released if they are all taken.
+    fn acquire(&self) -> Slot<'_> {
+        let used = self.used.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut used = self
+            .freed
+            .wait_while(used, |used| *used >= self.max)
+            .unwrap_or_else(PoisonError::into_inner);
+        *used += 1;
+        Slot { slots: self }
+    }
+
+    /// The number of slots taken.
+    fn used(&self) -> usize {
+        *self.used.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A slot taken from `Slots`, released when dropped.
+struct Slot<'a> {
+    slots: &'a Slots,
 }
 
-impl Drop for Daemon {
+impl Drop for Slot<'_> {
     fn drop(&mut self) {
-        // close the socket
-        close(self.socket_fd.as_raw_fd()).unwrap();
+        *self
+            .slots
+            .used
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.slots.freed.notify_one();
     }
 }
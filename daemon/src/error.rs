@@ -1,6 +1,10 @@
+use crate::process::Stage;
 use nix::sys::socket::UnixAddr;
-use shared::error::SharedError;
-use std::os::fd::RawFd;
+use shared::{
+    error::SharedError,
+    protocol::{Command, ErrorCode, ErrorResponse, Type},
+};
+use std::{os::fd::RawFd, time::Duration};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -23,6 +27,246 @@ pub enum DaemonError {
         errno: nix::errno::Errno,
     },
 
+    #[error("Failed to set the permissions of socket {path}: {source}")]
+    SocketPermissions {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to change the group of socket {path} to {group}: {errno}")]
+    SocketOwner {
+        path: String,
+        group: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Unknown group {group}")]
+    UnknownGroup { group: String },
+
+    #[error("Failed to look up group {group}: {errno}")]
+    LookupGroup {
+        group: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to set the flags of the passed socket: {errno}")]
+    SetSocketFlags {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Invalid socket activation: {reason}")]
+    SocketActivation { reason: String },
+
+    #[error("Failed to access pid file {path}: {source}")]
+    PidFile {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to lock pid file {path}: {errno}")]
+    LockPidFile {
+        path: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Another daemon (pid {pid}) is already running, it holds {path}")]
+    AlreadyRunning { path: String, pid: String },
+
+    #[error("Failed to open log file {path}: {source}")]
+    LogFile {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid log level {level}")]
+    InvalidLogLevel { level: String },
+
+    #[error("Invalid request: {reason}")]
+    InvalidRequest { reason: String },
+
+    #[error("Image {image} not found")]
+    ImageNotFound { image: String },
+
+    #[error("{image} matches more than one image")]
+    AmbiguousImage { image: String },
+
+    #[error("Invalid reference {reference}: {reason}")]
+    InvalidReference { reference: String, reason: String },
+
+    #[error("Failed to read image {path}: {source}")]
+    ReadImage {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to save the image index {path}: {source}")]
+    SaveImageIndex {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("The name {name} is already in use by container {id}")]
+    NameInUse { name: String, id: String },
+
+    #[error("No such container: {container}")]
+    ContainerNotFound { container: String },
+
+    #[error("{container} matches more than one container")]
+    AmbiguousContainer { container: String },
+
+    #[error("Container {id} is not running")]
+    NotRunning { id: String },
+
+    #[error("Container {id} is running, stop it first or force its removal")]
+    ContainerRunning { id: String },
+
+    #[error("Container {id} is being started, remove it once it runs")]
+    ContainerStarting { id: String },
+
+    #[error("Invalid signal {signal}")]
+    InvalidSignal { signal: String },
+
+    #[error("Failed to signal container {id}: {errno}")]
+    SignalContainer {
+        id: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Container {id} did not exit after being killed")]
+    StopTimeout { id: String },
+
+    #[error("Failed to generate a container id: {source}")]
+    GenerateId { source: std::io::Error },
+
+    #[error("Failed to create container directory {path}: {source}")]
+    CreateContainer {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to save the state of the container to {path}: {source}")]
+    SaveContainer {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to access container log {path}: {source}")]
+    ContainerLog {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to copy the image into {path}: {source}")]
+    CopyRootfs {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to remove container directory {path}: {source}")]
+    RemoveContainer {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to create volume {path}: {source}")]
+    CreateVolume {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to remove volume {path}: {source}")]
+    RemoveVolume {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to mount an overlay on {path}: {errno}")]
+    MountOverlay {
+        path: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to mount a fuse-overlayfs on {path}: {reason}")]
+    FuseOverlayfs { path: String, reason: String },
+
+    #[error("Failed to unmount {path}: {errno}")]
+    Unmount {
+        path: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to create cgroup {path}: {source}")]
+    CreateCgroup {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to write {path}: {source}")]
+    WriteCgroup {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to remove cgroup {path}: {source}")]
+    RemoveCgroup {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Cannot limit resources: {reason}")]
+    CgroupUnavailable { reason: String },
+
+    #[error("Failed to open /dev/null: {source}")]
+    OpenNull { source: std::io::Error },
+
+    #[error("Failed to create pipe: {errno}")]
+    CreatePipe {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to clone the container process: {errno}")]
+    CloneSyscall {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to wait for the container process: {errno}")]
+    WaitSyscall {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to write {path}: {source}")]
+    IdMap {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to map ids with {program}: {reason}")]
+    IdMapHelper { program: String, reason: String },
+
+    #[error("The container failed to {stage}: {errno}")]
+    ContainerInit {
+        stage: Stage,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("The container failed to {step}: {errno}")]
+    ContainerRootfs {
+        step: String,
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
     #[error("Failed to listen on socket descriptor {fd}: {errno}")]
     ListenSocket {
         fd: RawFd,
@@ -37,13 +281,88 @@ pub enum DaemonError {
         errno: nix::errno::Errno,
     },
 
-    #[error(
-        "Failed to close a connection fd {conn_fd} from socket descriptor {socket_fd}: {errno}"
-    )]
-    CloseSocketConnection {
-        socket_fd: RawFd,
-        conn_fd: RawFd,
+    #[error("Failed to block signals: {errno}")]
+    BlockSignals {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to create signal file descriptor: {errno}")]
+    CreateSignalFd {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to read signal: {errno}")]
+    ReadSignal {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to poll for connections and signals: {errno}")]
+    Poll {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Failed to wait for requests: {errno}")]
+    PollConnection {
+        #[source]
+        errno: nix::errno::Errno,
+    },
+
+    #[error("Timed out after {timeout:?} reading from the client")]
+    ReadTimeout { timeout: Duration },
+
+    #[error("Failed to spawn a thread for a request: {source}")]
+    SpawnRequest { source: std::io::Error },
+
+    #[error("Failed to read the credentials of the peer: {errno}")]
+    PeerCredentials {
         #[source]
         errno: nix::errno::Errno,
     },
+
+    #[error("{peer} is not allowed to run the {command:?} command")]
+    PermissionDenied { command: Command, peer: String },
+
+    #[error("Invalid message type: {_type:?}")]
+    InvalidMessageType { _type: Type },
+
+    #[error("The {command:?} command is not implemented")]
+    Unimplemented { command: Command },
+
+    #[error("The {command:?} command panicked")]
+    Panicked { command: Command },
+}
+
+impl DaemonError {
+    /// The error code reported to the client for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DaemonError::Shared(SharedError::MessageDeserialize(_)) => ErrorCode::InvalidRequest,
+            DaemonError::InvalidMessageType { .. } => ErrorCode::InvalidRequest,
+            DaemonError::InvalidLogLevel { .. } => ErrorCode::InvalidRequest,
+            DaemonError::InvalidRequest { .. } => ErrorCode::InvalidRequest,
+            DaemonError::NameInUse { .. } => ErrorCode::InvalidRequest,
+            DaemonError::CgroupUnavailable { .. } => ErrorCode::InvalidRequest,
+            DaemonError::AmbiguousContainer { .. } => ErrorCode::InvalidRequest,
+            DaemonError::NotRunning { .. } => ErrorCode::InvalidRequest,
+            DaemonError::ContainerRunning { .. } => ErrorCode::InvalidRequest,
+            DaemonError::ContainerStarting { .. } => ErrorCode::InvalidRequest,
+            DaemonError::InvalidSignal { .. } => ErrorCode::InvalidRequest,
+            DaemonError::AmbiguousImage { .. } => ErrorCode::InvalidRequest,
+            DaemonError::InvalidReference { .. } => ErrorCode::InvalidRequest,
+            DaemonError::ImageNotFound { .. } => ErrorCode::NotFound,
+            DaemonError::ContainerNotFound { .. } => ErrorCode::NotFound,
+            DaemonError::Unimplemented { .. } => ErrorCode::Unimplemented,
+            DaemonError::PermissionDenied { .. } => ErrorCode::PermissionDenied,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Convert the error into a structured error response.
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse::from_error(self.code(), self)
+    }
 }
@@ -0,0 +1,230 @@
+//! The uid and gid mappings of the user namespace of a container.
+//!
+//! A daemon running as root may map any host ids. An unprivileged (rootless) daemon
+//! may only map its own ids, or the subordinate ids granted to its user in
+//! `/etc/subuid` and `/etc/subgid`. The kernel only lets it write a mapping of its
+//! own id, any other mapping is written by the setuid `newuidmap` and `newgidmap`.
+
+use crate::error::DaemonError;
+use nix::unistd::{getegid, geteuid, Gid, Pid, Uid, User};
+use shared::requests::IdMapping;
+use std::{fs, process::Command};
+
+/// The kernel rejects maps with more lines.
+const MAX_MAPPINGS: usize = 340;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Uid,
+    Gid,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Uid => "uid",
+            Kind::Gid => "gid",
+        }
+    }
+
+    /// The id of the daemon.
+    fn own(self) -> u32 {
+        match self {
+            Kind::Uid => geteuid().as_raw(),
+            Kind::Gid => getegid().as_raw(),
+        }
+    }
+
+    fn subordinate_file(self) -> &'static str {
+        match self {
+            Kind::Uid => "/etc/subuid",
+            Kind::Gid => "/etc/subgid",
+        }
+    }
+
+    /// The setuid helper writing mappings of other ids than the own one.
+    fn helper(self) -> &'static str {
+        match self {
+            Kind::Uid => "newuidmap",
+            Kind::Gid => "newgidmap",
+        }
+    }
+}
+
+/// The uid and gid mappings of a container, checked before it is started.
+pub struct IdMaps {
+    uid: Vec<IdMapping>,
+    gid: Vec<IdMapping>,
+}
+
+impl IdMaps {
+    /// Check the mappings requested for a container. Empty mappings default to root
+    /// in the container being the daemon, followed by its subordinate ids.
+    pub fn new(uid: &[IdMapping], gid: &[IdMapping]) -> Result<Self, DaemonError> {
+        Ok(IdMaps {
+            uid: resolve(Kind::Uid, uid)?,
+            gid: resolve(Kind::Gid, gid)?,
+        })
+    }
+
+    /// The host uid and gid of root in the container, `None` if root is not mapped.
+    pub fn root(&self) -> (Option<Uid>, Option<Gid>) {
+        let host = |mappings: &[IdMapping]| {
+            mappings
+                .iter()
+                .find(|mapping| mapping.container_id == 0)
+                .map(|mapping| mapping.host_id)
+        };
+        (
+            host(&self.uid).map(Uid::from_raw),
+            host(&self.gid).map(Gid::from_raw),
+        )
+    }
+
+    /// Write the mappings of the user namespace of `pid`.
+    pub fn write(&self, pid: Pid) -> Result<(), DaemonError> {
+        write(Kind::Uid, pid, &self.uid)?;
+        write(Kind::Gid, pid, &self.gid)
+    }
+}
+
+fn resolve(kind: Kind, requested: &[IdMapping]) -> Result<Vec<IdMapping>, DaemonError> {
+    let invalid = |reason: String| DaemonError::InvalidRequest { reason };
+    let own = IdMapping {
+        container_id: 0,
+        host_id: kind.own(),
+        size: 1,
+    };
+    let subordinate = subordinate_ranges(kind);
+    if requested.is_empty() {
+        let mut mappings = vec![own];
+        if let Some(&(start, count)) = subordinate.first() {
+            mappings.push(IdMapping {
+                container_id: 1,
+                host_id: start,
+                size: count,
+            });
+        }
+        return Ok(mappings);
+    }
+
+    if requested.len() > MAX_MAPPINGS {
+        return Err(invalid(format!(
+            "at most {} {} mappings are allowed",
+            MAX_MAPPINGS,
+            kind.name()
+        )));
+    }
+    let root = geteuid().is_root();
+    for mapping in requested {
+        let end = |start: u32| start.checked_add(mapping.size);
+        if mapping.size == 0
+            || end(mapping.container_id).is_none()
+            || end(mapping.host_id).is_none()
+        {
+            return Err(invalid(format!(
+                "invalid {} mapping {}",
+                kind.name(),
+                mapping
+            )));
+        }
+        let allowed = root
+            || (mapping.host_id == own.host_id && mapping.size == 1)
+            || subordinate.iter().any(|&(start, count)| {
+                mapping.host_id >= start
+                    && u64::from(mapping.host_id) + u64::from(mapping.size)
+                        <= u64::from(start) + u64::from(count)
+            });
+        if !allowed {
+            return Err(invalid(format!(
+                "the daemon may not map host {}s {}",
+                kind.name(),
+                mapping
+            )));
+        }
+    }
+    // the init runs as root of the container.
+    if !requested.iter().any(|mapping| mapping.container_id == 0) {
+        return Err(invalid(format!(
+            "{} 0 of the container is not mapped",
+            kind.name()
+        )));
+    }
+    Ok(requested.to_vec())
+}
+
+/// The subordinate ids of the daemon user as `(start, count)`. A missing or
+/// unreadable file grants none.
+fn subordinate_ranges(kind: Kind) -> Vec<(u32, u32)> {
+    let uid = geteuid();
+    let name = User::from_uid(uid)
+        .ok()
+        .flatten()
+        .map(|user| user.name)
+        .unwrap_or_default();
+    let content = fs::read_to_string(kind.subordinate_file()).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split(':');
+            let owner = fields.next()?;
+            let start = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            match owner == name || owner == uid.to_string() {
+                true => Some((start, count)),
+                false => None,
+            }
+        })
+        .collect()
+}
+
+fn write(kind: Kind, pid: Pid, mappings: &[IdMapping]) -> Result<(), DaemonError> {
+    let root = geteuid().is_root();
+    let own_only = mappings
+        .iter()
+        .all(|mapping| mapping.host_id == kind.own() && mapping.size == 1);
+    if !root && !own_only {
+        return write_with_helper(kind, pid, mappings);
+    }
+
+    let write = |file: &str, content: String| {
+        let path = format!("/proc/{}/{}", pid, file);
+        fs::write(&path, content).map_err(|e| DaemonError::IdMap { path, source: e })
+    };
+    // an unprivileged daemon may only map its group once setgroups is denied.
+    if kind == Kind::Gid && !root {
+        write("setgroups", "deny".to_string())?;
+    }
+    let content = mappings
+        .iter()
+        .map(|mapping| {
+            format!(
+                "{} {} {}\n",
+                mapping.container_id, mapping.host_id, mapping.size
+            )
+        })
+        .collect::<String>();
+    write(&format!("{}_map", kind.name()), content)
+}
+
+fn write_with_helper(kind: Kind, pid: Pid, mappings: &[IdMapping]) -> Result<(), DaemonError> {
+    let failed = |reason: String| DaemonError::IdMapHelper {
+        program: kind.helper().to_string(),
+        reason,
+    };
+    let mut command = Command::new(kind.helper());
+    command.arg(pid.to_string());
+    for mapping in mappings {
+        command
+            .arg(mapping.container_id.to_string())
+            .arg(mapping.host_id.to_string())
+            .arg(mapping.size.to_string());
+    }
+    let output = command.output().map_err(|e| failed(e.to_string()))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(failed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )),
+    }
+}
@@ -0,0 +1,456 @@
+//! The local image store. Every image is unpacked under `images_dir/<image>`, either
+//! as layers in `layers/<layer>`, applied in the order of their names, or as a single
+//! root filesystem in `rootfs`. An optional `config.json` holds its `labels`.
+//!
+//! The images are recorded in `images_dir/index.json` along with their tags, digests
+//! and sizes. Images unpacked into the store are imported into the index the next time
+//! it is scanned, and tagged after their directory: `library/alpine` is
+//! `docker.io/library/alpine:latest`. Tags are normalized references, and every tag
+//! names a single image.
+
+use crate::{
+    digest::{self, Sha256},
+    error::DaemonError,
+    storage,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use shared::{
+    reference::{self, Reference},
+    requests::ImageFilter,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// The file the index is kept in, in `images_dir`.
+const INDEX_FILE: &str = "index.json";
+
+/// How deep image directories are searched for, e.g. `registry/library/alpine`.
+const MAX_DEPTH: usize = 4;
+
+/// An image of the store.
+/// - `digest` identifies the image by the contents of its layers.
+/// - `dir` is the directory of the image in `images_dir`.
+/// - `tags` are the references of the image as `repository:tag`, an image without any
+///   is dangling.
+/// - `size` is the size of the files of all layers in bytes.
+/// - `created` is the time the image was unpacked, in milliseconds since the unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub digest: String,
+    pub dir: String,
+    pub tags: Vec<String>,
+    pub size: u64,
+    pub created: u64,
+    pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A layer of an image, from the bottom up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer {
+    pub name: String,
+    pub digest: String,
+    pub size: u64,
+}
+
+/// The optional `config.json` of an image.
+#[derive(Debug, Default, Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// The images of the store.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub images: Vec<Image>,
+}
+
+impl Index {
+    /// Load the index of `images_dir`. A missing index is empty, an unreadable one is
+    /// rebuilt by the next scan.
+    pub fn load(images_dir: &str) -> Self {
+        let path = Path::new(images_dir).join(INDEX_FILE);
+        let index = match fs::read(&path) {
+            Ok(index) => serde_json::from_slice(&index).map_err(|e| e.to_string()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(err) => Err(err.to_string()),
+        };
+        index.unwrap_or_else(|err| {
+            warn!("Failed to load the image index {}: {}", path.display(), err);
+            Index::default()
+        })
+    }
+
+    /// Write the index, replacing it in one step.
+    pub fn save(&self, images_dir: &str) -> Result<(), DaemonError> {
+        let path = Path::new(images_dir).join(INDEX_FILE);
+        let temporary = path.with_extension("json.tmp");
+        let save_error = |source: io::Error| DaemonError::SaveImageIndex {
+            path: path.display().to_string(),
+            source,
+        };
+        let index = serde_json::to_vec_pretty(self)
+            .map_err(io::Error::from)
+            .map_err(save_error)?;
+        fs::write(&temporary, index)
+            .and_then(|_| fs::rename(&temporary, &path))
+            .map_err(save_error)
+    }
+
+    /// Bring the index in line with the store: import the images unpacked since the
+    /// last scan and forget the ones that were deleted. Returns whether it changed.
+    pub fn scan(&mut self, images_dir: &str) -> bool {
+        let dirs = image_dirs(Path::new(images_dir), Path::new(""), 0);
+        let count = self.images.len();
+        self.images.retain(|image| dirs.contains(&image.dir));
+        let mut changed = self.images.len() != count;
+        for dir in dirs {
+            if self.images.iter().any(|image| image.dir == dir) {
+                continue;
+            }
+            match import(images_dir, &dir) {
+                Ok(mut image) => {
+                    info!("Imported image {} as {}", dir, image.digest);
+                    match dir.parse::<Reference>() {
+                        Ok(reference) if reference.digest.is_some() => {}
+                        Ok(reference) if self.tagged(&reference.to_string()).is_some() => warn!(
+                            "{} already tags another image, image {} is dangling",
+                            reference, dir
+                        ),
+                        Ok(reference) => image.tags.push(reference.to_string()),
+                        Err(err) => warn!("Image {} is dangling, {}", dir, err),
+                    }
+                    self.images.push(image);
+                    changed = true;
+                }
+                Err(err) => warn!("Failed to import image {}: {}", dir, err),
+            }
+        }
+        changed
+    }
+
+    /// The image `image` refers to: a digest, a reference, or a unique prefix of the
+    /// id of an image. Tags take precedence over id prefixes.
+    pub fn resolve(&self, image: &str) -> Result<&Image, DaemonError> {
+        self.position(image).map(|position| &self.images[position])
+    }
+
+    /// Tag the image `source` refers to with `target`, taking the tag from any other
+    /// image. Returns the digest of the image and the normalized tag.
+    pub fn tag(&mut self, source: &str, target: &str) -> Result<(String, String), DaemonError> {
+        let target = parse_tag(target)?;
+        let position = self.position(source)?;
+        for image in &mut self.images {
+            image.tags.retain(|tag| *tag != target);
+        }
+        let image = &mut self.images[position];
+        image.tags.push(target.clone());
+        image.tags.sort();
+        Ok((image.digest.clone(), target))
+    }
+
+    /// Remove the tag `reference` from its image. Returns the digest of the image and
+    /// the normalized tag.
+    pub fn untag(&mut self, reference: &str) -> Result<(String, String), DaemonError> {
+        let tag = parse_tag(reference)?;
+        let position = self
+            .tagged(&tag)
+            .ok_or_else(|| DaemonError::ImageNotFound {
+                image: reference.to_string(),
+            })?;
+        let image = &mut self.images[position];
+        image.tags.retain(|other| *other != tag);
+        Ok((image.digest.clone(), tag))
+    }
+
+    /// The position of the image tagged `tag`.
+    fn tagged(&self, tag: &str) -> Option<usize> {
+        self.images
+            .iter()
+            .position(|image| image.tags.iter().any(|other| other == tag))
+    }
+
+    /// The position of the image `image` refers to. Images with the same content share
+    /// a digest, any of them will do.
+    fn position(&self, image: &str) -> Result<usize, DaemonError> {
+        let by_digest = |digest: &str| self.images.iter().position(|other| other.digest == digest);
+        let found = match image.parse::<Reference>() {
+            _ if reference::is_digest(image) => by_digest(image),
+            Ok(Reference {
+                digest: Some(digest),
+                ..
+            }) => by_digest(&digest),
+            Ok(reference) => self.tagged(&reference.to_string()),
+            Err(_) => None,
+        };
+        if let Some(position) = found {
+            return Ok(position);
+        }
+
+        let prefix = image.strip_prefix("sha256:").unwrap_or(image);
+        let hex = !prefix.is_empty()
+            && prefix
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'));
+        if hex {
+            let mut matching = self.images.iter().enumerate().filter(|(_, other)| {
+                other
+                    .digest
+                    .strip_prefix("sha256:")
+                    .is_some_and(|id| id.starts_with(prefix))
+            });
+            match matching.next() {
+                Some((position, first))
+                    if matching.all(|(_, other)| other.digest == first.digest) =>
+                {
+                    return Ok(position)
+                }
+                Some(_) => {
+                    return Err(DaemonError::AmbiguousImage {
+                        image: image.to_string(),
+                    })
+                }
+                None => {}
+            }
+        }
+        match image.parse::<Reference>() {
+            Err(reason) if !hex => Err(DaemonError::InvalidReference {
+                reference: image.to_string(),
+                reason,
+            }),
+            _ => Err(DaemonError::ImageNotFound {
+                image: image.to_string(),
+            }),
+        }
+    }
+}
+
+impl Image {
+    /// Whether the image matches `filter`.
+    pub fn matches(&self, filter: &ImageFilter) -> bool {
+        match filter {
+            ImageFilter::Dangling(dangling) => self.tags.is_empty() == *dangling,
+            // the pattern may match the full or the short name, or the repository path,
+            // with or without the tag.
+            ImageFilter::Reference(pattern) => self.tags.iter().any(|tag| {
+                let Ok(reference) = tag.parse::<Reference>() else {
+                    return glob(pattern.as_bytes(), tag.as_bytes());
+                };
+                let suffix = format!(":{}", reference.tag.as_deref().unwrap_or_default());
+                [
+                    reference.name(),
+                    reference.short_name(),
+                    reference.repository.clone(),
+                ]
+                .into_iter()
+                .any(|name| {
+                    glob(pattern.as_bytes(), name.as_bytes())
+                        || glob(pattern.as_bytes(), format!("{}{}", name, suffix).as_bytes())
+                })
+            }),
+            ImageFilter::Label { key, value } => match value {
+                Some(value) => self.labels.get(key) == Some(value),
+                None => self.labels.contains_key(key),
+            },
+        }
+    }
+}
+
+/// The unpacked layers of `image`, from the bottom to the top layer. Fails with
+/// `ImageNotFound` if the image was never pulled or its name would escape the image
+/// store.
+pub fn layers(images_dir: &str, image: &str) -> Result<Vec<PathBuf>, DaemonError> {
+    let not_found = || DaemonError::ImageNotFound {
+        image: image.to_string(),
+    };
+    let name = Path::new(image);
+    let valid = !image.is_empty()
+        && name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !valid {
+        return Err(not_found());
+    }
+
+    let dir = Path::new(images_dir).join(name);
+    if let Ok(entries) = fs::read_dir(dir.join("layers")) {
+        let mut layers = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>();
+        layers.sort();
+        if !layers.is_empty() {
+            return Ok(layers);
+        }
+    }
+    let rootfs = dir.join("rootfs");
+    match rootfs.is_dir() {
+        true => Ok(vec![rootfs]),
+        false => Err(not_found()),
+    }
+}
+
+/// Parse `reference` as a tag, which cannot name a digest.
+fn parse_tag(reference: &str) -> Result<String, DaemonError> {
+    let invalid = |reason: String| DaemonError::InvalidReference {
+        reference: reference.to_string(),
+        reason,
+    };
+    let parsed = reference.parse::<Reference>().map_err(invalid)?;
+    match parsed.digest {
+        Some(_) => Err(invalid("a tag cannot name a digest".to_string())),
+        None => Ok(parsed.to_string()),
+    }
+}
+
+/// The directories of the images below `dir`, relative to `images_dir`. A directory
+/// holding `layers` or `rootfs` is an image, others may hold images themselves.
+fn image_dirs(images_dir: &Path, dir: &Path, depth: usize) -> Vec<String> {
+    let path = images_dir.join(dir);
+    if depth > 0 && (path.join("layers").is_dir() || path.join("rootfs").is_dir()) {
+        return vec![dir.to_string_lossy().to_string()];
+    }
+    if depth == MAX_DEPTH {
+        return Vec::new();
+    }
+    let Ok(entries) = fs::read_dir(&path) else {
+        return Vec::new();
+    };
+    let mut dirs = entries
+        .flatten()
+        .filter(|entry| !entry.file_name().as_bytes().starts_with(b"."))
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .flat_map(|entry| image_dirs(images_dir, &dir.join(entry.file_name()), depth + 1))
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs
+}
+
+/// Record the image unpacked in `dir`. Its digest is the digest of the digests of its
+/// layers.
+fn import(images_dir: &str, dir: &str) -> Result<Image, DaemonError> {
+    let path = Path::new(images_dir).join(dir);
+    let read_error = |source: io::Error| DaemonError::ReadImage {
+        path: path.display().to_string(),
+        source,
+    };
+    let mut layers = Vec::new();
+    for layer in self::layers(images_dir, dir)? {
+        let mut hash = Sha256::new();
+        let mut size = 0;
+        hash_tree(&mut hash, &layer, &layer, &mut size).map_err(read_error)?;
+        layers.push(Layer {
+            name: layer
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            digest: hash.finish(),
+            size,
+        });
+    }
+    let manifest = layers
+        .iter()
+        .map(|layer| format!("{}\n", layer.digest))
+        .collect::<String>();
+
+    let config = match fs::read(path.join("config.json")) {
+        Ok(config) => serde_json::from_slice::<ImageConfig>(&config)
+            .map_err(|e| read_error(io::Error::new(io::ErrorKind::InvalidData, e)))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => ImageConfig::default(),
+        Err(err) => return Err(read_error(err)),
+    };
+    let created = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(read_error)?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64);
+    Ok(Image {
+        digest: digest::digest(manifest.as_bytes()),
+        dir: dir.to_string(),
+        tags: Vec::new(),
+        size: layers.iter().map(|layer| layer.size).sum(),
+        created,
+        layers,
+        labels: config.labels,
+    })
+}
+
+/// Hash the tree at `path` of the layer at `root`: the path, type, owner and mode of
+/// every file in name order, followed by its contents. Adds the size of the regular
+/// files to `size`.
+fn hash_tree(hash: &mut Sha256, root: &Path, path: &Path, size: &mut u64) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    let kind = match () {
+        _ if file_type.is_dir() => 'd',
+        _ if file_type.is_file() => 'f',
+        _ if file_type.is_symlink() => 'l',
+        _ => 's',
+    };
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    hash.update(relative.as_os_str().as_bytes());
+    hash.update(
+        format!(
+            "\0{}\0{:o}\0{}\0{}\0{}\0",
+            kind,
+            metadata.mode(),
+            metadata.uid(),
+            metadata.gid(),
+            metadata.rdev()
+        )
+        .as_bytes(),
+    );
+    match kind {
+        'f' => {
+            *size += metadata.len();
+            hash.update(&metadata.len().to_be_bytes());
+            let mut file = File::open(path)?;
+            let mut buffer = vec![0; 1 << 16];
+            loop {
+                match file.read(&mut buffer)? {
+                    0 => break,
+                    count => hash.update(&buffer[..count]),
+                }
+            }
+        }
+        'l' => hash.update(fs::read_link(path)?.as_os_str().as_bytes()),
+        'd' => {
+            // an opaque directory hides the same directory of the layers below.
+            hash.update(&[u8::from(storage::is_opaque(path))]);
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+            for entry in entries {
+                hash_tree(hash, root, &entry, size)?;
+            }
+        }
+        _ => {}
+    }
+    hash.update(b"\n");
+    Ok(())
+}
+
+/// Whether `text` matches the glob `pattern`, where `*` matches any run of characters
+/// and `?` any single one.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], text) || (!text.is_empty() && glob(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob(&pattern[1..], &text[1..]),
+        (Some(expected), Some(actual)) if expected == actual => glob(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
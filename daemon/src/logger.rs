@@ -0,0 +1,373 @@
+//! The logger of the daemon. Every record is written as a single line, either as
+//! text or as a JSON object, to stderr or to a log file that is rotated once it
+//! grows past a configured size.
+//!
+//! Records carry the context of the connection and request they were logged from
+//! (connection id, peer uid, command and request id), see `with_context`.
+//!
+//! # Example
+//! ```text
+//! 2026-01-01T12:00:00.000Z INFO  [connection=0 uid=1000 command=Pull request_id=1] Pulling alpine
+//! {"time":"2026-01-01T12:00:00.000Z","level":"INFO","target":"daemon::daemon","connection":0,"uid":1000,"command":"Pull","request_id":1,"message":"Pulling alpine"}
+//! ```
+
+use crate::{
+    config::{LogFormat, Logging},
+    error::DaemonError,
+};
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use shared::protocol::Command;
+use std::{
+    cell::RefCell,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    sync::{Mutex, MutexGuard, Once, PoisonError},
+};
+
+/// The context a record is logged in.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Context {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<Command>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u32>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Run `f` with the context of the current thread updated by `update`. The previous
+/// context is restored afterwards, also if `f` panics.
+pub fn with_context<T>(update: impl FnOnce(&mut Context), f: impl FnOnce() -> T) -> T {
+    struct Restore(Context);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CONTEXT.with(|context| *context.borrow_mut() = self.0);
+        }
+    }
+
+    let previous = CONTEXT.with(|context| {
+        let previous = *context.borrow();
+        update(&mut context.borrow_mut());
+        previous
+    });
+    let _restore = Restore(previous);
+    f()
+}
+
+/// The context of the current thread.
+pub fn context() -> Context {
+    CONTEXT.with(|context| *context.borrow())
+}
+
+/// A single record as written in the JSON format.
+#[derive(Serialize)]
+struct Line<'a> {
+    time: &'a str,
+    level: &'a str,
+    target: &'a str,
+    #[serde(flatten)]
+    context: Context,
+    message: String,
+}
+
+/// Where records are written to.
+enum Sink {
+    Stderr,
+    File {
+        path: String,
+        file: File,
+        size: u64,
+        max_size: u64,
+        max_files: u32,
+    },
+}
+
+impl Sink {
+    /// Open the sink for the logging settings.
+    fn open(logging: &Logging) -> Result<Self, DaemonError> {
+        let Some(path) = &logging.file else {
+            return Ok(Sink::Stderr);
+        };
+        let (file, size) = Self::open_file(path).map_err(|e| DaemonError::LogFile {
+            path: path.clone(),
+            source: e,
+        })?;
+        Ok(Sink::File {
+            path: path.clone(),
+            file,
+            size,
+            max_size: logging.max_size,
+            max_files: logging.max_files,
+        })
+    }
+
+    /// Open the log file for appending and return its current size.
+    fn open_file(path: &str) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    /// Write a line, rotating the log file first if it would grow past its maximum size.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stderr => io::stderr().lock().write_all(line.as_bytes()),
+            Sink::File {
+                path,
+                file,
+                size,
+                max_size,
+                max_files,
+            } => {
+                let length = line.len() as u64;
+                if *max_size > 0 && *size > 0 && *size + length > *max_size {
+                    Self::rotate(path, *max_files)?;
+                    (*file, *size) = Self::open_file(path)?;
+                }
+                file.write_all(line.as_bytes())?;
+                *size += length;
+                Ok(())
+            }
+        }
+    }
+
+    /// Shift `path.1` .. `path.<max_files - 1>` up by one and move the current log file
+    /// to `path.1`. The oldest file is dropped, without any rotated files the log file
+    /// is simply started over.
+    fn rotate(path: &str, max_files: u32) -> io::Result<()> {
+        if max_files == 0 {
+            return fs::remove_file(path);
+        }
+        for index in (1..max_files).rev() {
+            let from = format!("{}.{}", path, index);
+            match fs::rename(&from, format!("{}.{}", path, index + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        fs::rename(path, format!("{}.1", path))
+    }
+}
+
+/// The state of the logger, `None` until it is configured.
+struct State {
+    format: LogFormat,
+    sink: Sink,
+}
+
+pub struct Logger {
+    state: Mutex<Option<State>>,
+}
+
+static LOGGER: Logger = Logger {
+    state: Mutex::new(None),
+};
+
+impl Logger {
+    fn state(&self) -> MutexGuard<'_, Option<State>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Format a record according to the configured format.
+    fn format(format: LogFormat, record: &Record) -> String {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let context = context();
+        match format {
+            LogFormat::Text => {
+                let mut fields = Vec::new();
+                if let Some(connection) = context.connection {
+                    fields.push(format!("connection={}", connection));
+                }
+                if let Some(uid) = context.uid {
+                    fields.push(format!("uid={}", uid));
+                }
+                if let Some(command) = context.command {
+                    fields.push(format!("command={:?}", command));
+                }
+                if let Some(request_id) = context.request_id {
+                    fields.push(format!("request_id={}", request_id));
+                }
+                let fields = match fields.is_empty() {
+                    true => String::new(),
+                    false => format!("[{}] ", fields.join(" ")),
+                };
+                // records of the daemon itself are told apart from e.g. the audit trail.
+                let target = match record.target().starts_with(env!("CARGO_CRATE_NAME")) {
+                    true => String::new(),
+                    false => format!("{}: ", record.target()),
+                };
+                format!(
+                    "{} {:<5} {}{}{}\n",
+                    time,
+                    record.level(),
+                    fields,
+                    target,
+                    record.args()
+                )
+            }
+            LogFormat::Json => {
+                let line = Line {
+                    time: &time,
+                    level: record.level().as_str(),
+                    target: record.target(),
+                    context,
+                    message: record.args().to_string(),
+                };
+                // serializing plain strings and numbers never fails.
+                let mut line = serde_json::to_string(&line).unwrap_or_default();
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut state = self.state();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+        let line = Self::format(state.format, record);
+        if let Err(err) = state.sink.write_line(&line) {
+            // the log itself is broken, stderr is the only place left to report it.
+            eprintln!("Failed to write log record: {}", err);
+            eprint!("{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(State {
+            sink: Sink::File { file, .. },
+            ..
+        }) = self.state().as_mut()
+        {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Install the logger and configure it. Can be called again to apply new settings,
+/// e.g. after the configuration was reloaded.
+pub fn init(logging: &Logging) -> Result<(), DaemonError> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        // the daemon never installs another logger.
+        let _ = log::set_logger(&LOGGER);
+    });
+
+    let sink = Sink::open(logging)?;
+    *LOGGER.state() = Some(State {
+        format: logging.format,
+        sink,
+    });
+    log::set_max_level(logging.level);
+    Ok(())
+}
+
+/// Change the level of the records that are logged until the configuration is reloaded.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// The level of the records that are logged.
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn file_sink(path: &str, max_size: u64, max_files: u32) -> Sink {
+        let logging = Logging {
+            file: Some(path.to_string()),
+            max_size,
+            max_files,
+            ..Default::default()
+        };
+        Sink::open(&logging).unwrap()
+    }
+
+    #[test]
+    fn log_file_is_rotated_at_its_maximum_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.log").display().to_string();
+        let read = |suffix: &str| fs::read_to_string(format!("{}{}", path, suffix)).ok();
+
+        let mut sink = file_sink(&path, 12, 2);
+        sink.write_line("first\n").unwrap();
+        sink.write_line("1234\n").unwrap();
+        assert_eq!(read("").as_deref(), Some("first\n1234\n"));
+        assert_eq!(read(".1").as_deref(), None);
+
+        sink.write_line("second\n").unwrap();
+        assert_eq!(read("").as_deref(), Some("second\n"));
+        assert_eq!(read(".1").as_deref(), Some("first\n1234\n"));
+        sink.write_line("third\n").unwrap();
+        sink.write_line("fourth\n").unwrap();
+        assert_eq!(read("").as_deref(), Some("fourth\n"));
+        assert_eq!(read(".1").as_deref(), Some("third\n"));
+        assert_eq!(read(".2").as_deref(), Some("second\n"));
+        // the oldest file is dropped once `max_files` are kept.
+        assert_eq!(read(".3"), None);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        // a line longer than the limit still goes to a file of its own.
+        sink.write_line("a line longer than the limit\n").unwrap();
+        assert_eq!(read("").as_deref(), Some("a line longer than the limit\n"));
+        assert_eq!(read(".1").as_deref(), Some("fourth\n"));
+    }
+
+    #[test]
+    fn log_file_starts_over_without_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.log").display().to_string();
+
+        let mut sink = file_sink(&path, 10, 0);
+        sink.write_line("first\n").unwrap();
+        sink.write_line("second\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn json_records_are_single_lines() {
+        let args = format_args!("first line\nsecond \"line\"");
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("daemon::daemon")
+            .args(args)
+            .build();
+        let line = with_context(
+            |context| context.request_id = Some(7),
+            || Logger::format(LogFormat::Json, &record),
+        );
+
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.matches('\n').count(), 1, "{}", line);
+        let value = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "daemon::daemon");
+        assert_eq!(value["message"], "first line\nsecond \"line\"");
+        assert_eq!(value["request_id"], 7);
+        assert!(value.get("connection").is_none());
+        assert!(value["time"].as_str().unwrap().ends_with('Z'));
+    }
+}
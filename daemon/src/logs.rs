@@ -0,0 +1,349 @@
+//! The output of containers. The standard output and error of a container init are
+//! fifos in the directory of the container, read by a collector thread of the daemon
+//! which appends every line to `container.log`, tagged with its stream and the time it
+//! was read. The log is removed along with the container. The lines of each stream
+//! keep their order, lines written to both at about the same time may not.
+//!
+//! The container holds its fifos open for reading as well, so writing to them never
+//! fails while the daemon is not running. Its output waits in the fifos until the next
+//! daemon opens them again and resumes collecting, once they are full the container
+//! blocks on writing.
+
+use crate::error::DaemonError;
+use chrono::{DateTime, SecondsFormat};
+use log::warn;
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    poll::{poll, PollFd, PollFlags},
+    sys::stat::Mode,
+    unistd::mkfifo,
+};
+use serde::{Deserialize, Serialize};
+use shared::protocol::Output;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{fd::OwnedFd, unix::fs::OpenOptionsExt},
+    path::Path,
+};
+
+/// The log file in the directory of a container.
+pub const LOG_FILE: &str = "container.log";
+
+/// The fifos the standard output and error of a container are written to, in its
+/// directory.
+const FIFOS: [(Stream, &str); 2] = [(Stream::Stdout, "stdout"), (Stream::Stderr, "stderr")];
+
+/// Longer lines are split into several records.
+const MAX_LINE: usize = 16 * 1024;
+
+/// How often, in milliseconds, a collector checks whether the fifos were closed. A fifo
+/// opened after the container exited, or before it wrote, reports no hang up.
+const CLOSE_INTERVAL: i32 = 1000;
+
+/// The stream a line was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    /// The output a line of the stream is sent to clients on.
+    pub fn output(&self) -> Output {
+        match self {
+            Stream::Stdout => Output::Stdout,
+            Stream::Stderr => Output::Stderr,
+        }
+    }
+}
+
+/// A line of the log, one JSON object per line of the file.
+/// - `time` is the time the line was read, in milliseconds since the unix epoch.
+/// - `log` is the line including its newline, which the last line of a stream or a
+///   line split for its length may lack.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub time: u64,
+    pub stream: Stream,
+    pub log: String,
+}
+
+impl Record {
+    /// The line as sent to clients, prefixed with its time in RFC 3339 if `timestamps`.
+    pub fn render(&self, timestamps: bool) -> String {
+        match timestamps {
+            true => {
+                let time = DateTime::from_timestamp_millis(self.time as i64)
+                    .unwrap_or_default()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true);
+                format!("{} {}", time, self.log)
+            }
+            false => self.log.clone(),
+        }
+    }
+}
+
+/// The output of a container being collected: its log and the read ends of its fifos.
+pub struct Capture {
+    log: File,
+    streams: Vec<(Stream, File)>,
+}
+
+/// Create the log and the fifos of a new container in its directory `dir`, before it
+/// can write anything. Returns the output to collect and the standard output and error
+/// to hand to the container.
+pub fn create(dir: &Path) -> Result<(Capture, [OwnedFd; 2]), DaemonError> {
+    for (_, name) in FIFOS {
+        let path = dir.join(name);
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(|e| log_error(&path, e.into()))?;
+    }
+    let output = open(dir)?;
+    // the container reads its fifos too, so they outlive the daemon.
+    let open_write = |name: &str| {
+        let path = dir.join(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(OFlag::O_CLOEXEC.bits())
+            .open(&path)
+            .map(OwnedFd::from)
+            .map_err(|e| log_error(&path, e))
+    };
+    let stdio = [open_write(FIFOS[0].1)?, open_write(FIFOS[1].1)?];
+    Ok((output, stdio))
+}
+
+/// Open the log and the fifos of the container in `dir` to collect its output, also
+/// the output written while no daemon was running.
+pub fn open(dir: &Path) -> Result<Capture, DaemonError> {
+    let path = dir.join(LOG_FILE);
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| log_error(&path, e))?;
+    let streams = FIFOS
+        .iter()
+        .map(|(stream, name)| {
+            let path = dir.join(name);
+            OpenOptions::new()
+                .read(true)
+                .custom_flags((OFlag::O_NONBLOCK | OFlag::O_CLOEXEC).bits())
+                .open(&path)
+                .map(|fifo| (*stream, fifo))
+                .map_err(|e| log_error(&path, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Capture { log, streams })
+}
+
+fn log_error(path: &Path, source: io::Error) -> DaemonError {
+    DaemonError::ContainerLog {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// Append the output of a container to its log line by line. Returns once every
+/// process of the container closed its fifos.
+pub fn collect(capture: Capture) {
+    let Capture { mut log, streams } = capture;
+    let mut streams = streams
+        .into_iter()
+        .map(|(stream, fifo)| (stream, Some(fifo), Vec::new()))
+        .collect::<Vec<_>>();
+    let mut failed = false;
+    let mut write = |stream: Stream, line: &[u8]| {
+        let record = Record {
+            time: crate::container::now(),
+            stream,
+            log: String::from_utf8_lossy(line).to_string(),
+        };
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        // a single write per record, so readers never see half of one for long.
+        if let Err(err) = log.write_all(&line) {
+            if !failed {
+                warn!("Failed to write the output of a container: {}", err);
+            }
+            failed = true;
+        }
+    };
+
+    let mut buffer = vec![0; MAX_LINE];
+    loop {
+        if streams.iter().all(|(_, fifo, _)| fifo.is_none()) {
+            return;
+        }
+        let mut fds = streams
+            .iter()
+            .filter_map(|(_, fifo, _)| fifo.as_ref())
+            .map(|fifo| PollFd::new(fifo, PollFlags::POLLIN))
+            .collect::<Vec<_>>();
+        match poll(&mut fds, CLOSE_INTERVAL) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(errno) => {
+                warn!("Failed to wait for the output of a container: {}", errno);
+                return;
+            }
+        }
+        drop(fds);
+
+        // the fifos are read until they are empty, a closed one reads as empty.
+        for (stream, fifo, pending) in &mut streams {
+            while let Some(reading) = fifo.as_mut() {
+                let count = match reading.read(&mut buffer) {
+                    Ok(count) => count,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        warn!("Failed to read the output of a container: {}", err);
+                        0
+                    }
+                };
+                if count == 0 {
+                    // the stream is closed, its last line may lack a newline.
+                    if !pending.is_empty() {
+                        write(*stream, pending);
+                    }
+                    *fifo = None;
+                    break;
+                }
+                pending.extend_from_slice(&buffer[..count]);
+                while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                    write(*stream, &pending[..=end]);
+                    pending.drain(..=end);
+                }
+                while pending.len() >= MAX_LINE {
+                    let end = split_point(pending, MAX_LINE);
+                    write(*stream, &pending[..end]);
+                    pending.drain(..end);
+                }
+            }
+        }
+    }
+}
+
+/// Where to split a line longer than `max` bytes: at the last character boundary
+/// before `max`, so no character is split into two records. Bytes that are not UTF-8
+/// are split at `max`.
+fn split_point(line: &[u8], max: usize) -> usize {
+    let is_continuation = |byte: u8| byte & 0b1100_0000 == 0b1000_0000;
+    // a character is up to 4 bytes long, 3 of them continuation bytes.
+    (max.saturating_sub(3)..=max)
+        .rev()
+        .find(|end| *end < line.len() && !is_continuation(line[*end]))
+        .filter(|end| *end > 0)
+        .unwrap_or(max)
+}
+
+/// Reads the records of a log, also the ones appended while reading.
+pub struct Reader {
+    reader: BufReader<File>,
+    /// The start of a record that is still being written, which may end in the
+    /// middle of a character.
+    partial: Vec<u8>,
+}
+
+impl Reader {
+    pub fn new(log: File) -> Self {
+        Reader {
+            reader: BufReader::new(log),
+            partial: Vec::new(),
+        }
+    }
+
+    /// The next complete record, `None` at the end of the log for now.
+    pub fn next(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            match self.reader.read_until(b'\n', &mut self.partial)? {
+                0 => return Ok(None),
+                _ if !self.partial.ends_with(b"\n") => return Ok(None),
+                _ => {}
+            }
+            let line = std::mem::take(&mut self.partial);
+            match serde_json::from_slice(&line) {
+                Ok(record) => return Ok(Some(record)),
+                Err(err) => warn!("Skipping a malformed line of a container log: {}", err),
+            }
+        }
+    }
+}
+
+/// Parse the bounds of `logs --since` and `--until` into milliseconds since the unix
+/// epoch: an RFC 3339 time, a unix timestamp in seconds, or a duration before `now`
+/// such as `90s`, `10m`, `2h` or `1d`.
+pub fn parse_time(value: &str, now: u64) -> Result<u64, DaemonError> {
+    let invalid = || DaemonError::InvalidRequest {
+        reason: format!(
+            "{} is not a time, expected an RFC 3339 time, a unix timestamp or a duration like 10m",
+            value
+        ),
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return u64::try_from(time.timestamp_millis()).map_err(|_| invalid());
+    }
+    if let Ok(seconds) = value.parse::<f64>() {
+        return match seconds.is_finite() && seconds >= 0.0 {
+            true => Ok((seconds * 1000.0) as u64),
+            false => Err(invalid()),
+        };
+    }
+    let unit = match value.chars().last() {
+        Some('s') => 1000,
+        Some('m') => 60 * 1000,
+        Some('h') => 60 * 60 * 1000,
+        Some('d') => 24 * 60 * 60 * 1000,
+        _ => return Err(invalid()),
+    };
+    let count = value[..value.len() - 1]
+        .parse::<u64>()
+        .map_err(|_| invalid())?;
+    Ok(now.saturating_sub(count.saturating_mul(unit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_lines_are_split_between_characters() {
+        assert_eq!(split_point(b"abcdefgh", 4), 4);
+        // 'é' is 2 bytes and '€' 3, neither may be cut.
+        assert_eq!(split_point("abcé".as_bytes(), 4), 3);
+        assert_eq!(split_point("ab€x".as_bytes(), 4), 2);
+        assert_eq!(split_point("ab€x".as_bytes(), 5), 5);
+        // bytes that are not UTF-8 are split anyway.
+        assert_eq!(split_point(&[0x80; 8], 4), 4);
+    }
+
+    #[test]
+    fn partial_records_are_read_once_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let record = r#"{"time":1,"stream":"stderr","log":"é\n"}"#.as_bytes();
+        // the record ends in the middle of 'é' for now.
+        let (start, end) = record.split_at(r#"{"time":1,"stream":"stderr","log":""#.len() + 1);
+
+        let mut reader = Reader::new(File::open(&path).unwrap());
+        log.write_all(start).unwrap();
+        assert!(reader.next().unwrap().is_none());
+        log.write_all(end).unwrap();
+        assert!(reader.next().unwrap().is_none());
+        log.write_all(b"\n").unwrap();
+        let record = reader.next().unwrap().unwrap();
+        assert_eq!(
+            (record.stream, record.log.as_str()),
+            (Stream::Stderr, "é\n")
+        );
+        assert!(reader.next().unwrap().is_none());
+    }
+}
@@ -1,43 +1,30 @@
+mod audit;
+mod auth;
+mod cgroup;
 mod config;
+mod container;
 mod daemon;
+mod digest;
 mod error;
+mod idmap;
+mod image;
+mod logger;
+mod logs;
+mod pidfile;
+mod process;
+mod rootfs;
+mod storage;
+mod volume;
 
 use daemon::Daemon;
-use error::DaemonError;
+use std::sync::Arc;
 
-fn main() -> Result<(), DaemonError> {
-    let daemon = Daemon::new()?;
-    match daemon.run() {
+fn main() {
+    match Daemon::new().and_then(|daemon| Arc::new(daemon).run()) {
         Ok(_) => {}
         Err(err) => {
-            eprintln!("Error: {}", err.to_string());
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
     }
-    Ok(())
 }
-
-// const STACK_SIZE: usize = 2 * 1024 * 1024; // 2 MB
-// fn create_process() -> Result<(), DaemonError> {
-//     let pid: Pid;
-//     let mut stack = vec![0; STACK_SIZE];
-//     let child_stack = stack.as_mut_slice();
-//     let flags = CloneFlags::CLONE_NEWUTS
-//         | CloneFlags::CLONE_NEWPID
-//         | CloneFlags::CLONE_NEWNS
-//         | CloneFlags::CLONE_NEWNET
-//         | CloneFlags::CLONE_NEWIPC
-//         | CloneFlags::CLONE_NEWUSER
-//         | CloneFlags::CLONE_NEWCGROUP;
-//     unsafe {
-//         pid = clone(Box::new(child), child_stack, flags, None)
-//             .map_err(|err| DaemonError::CloneSyscall { errno: err })?
-//     }
-//     let exit = waitpid(Some(pid), Some(WaitPidFlag::__WCLONE))
-//         .map_err(|err| DaemonError::WaitSyscall { errno: err })?;
-//     println!("Child exited with {:?}", exit);
-//     Ok(())
-// }
-//
-// fn child() -> isize {
-//     0
-// }
@@ -0,0 +1,84 @@
+//! The pid file of the daemon. It holds the pid of the running daemon and stays
+//! exclusively locked (`flock`) for as long as the daemon runs, so a second daemon
+//! refuses to start instead of taking over the socket of the first one.
+
+use crate::error::DaemonError;
+use log::error;
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    unistd::getpid,
+};
+use shared::utils;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+};
+
+pub struct PidFile {
+    path: String,
+    // the lock is released when the file is closed.
+    file: File,
+}
+
+impl PidFile {
+    /// Lock the pid file at `path` and write the pid of this process to it. Fails
+    /// with `AlreadyRunning` if another daemon holds the lock.
+    pub fn lock(path: &str) -> Result<Self, DaemonError> {
+        let io_error = |e| DaemonError::PidFile {
+            path: path.to_string(),
+            source: e,
+        };
+        if let Some(parent) = Path::new(path).parent() {
+            utils::ensure_private_directory(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+            .map_err(io_error)?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(DaemonError::AlreadyRunning {
+                    path: path.to_string(),
+                    pid: pid.trim().to_string(),
+                });
+            }
+            Err(errno) => {
+                return Err(DaemonError::LockPidFile {
+                    path: path.to_string(),
+                    errno,
+                })
+            }
+        }
+
+        // only the holder of the lock may replace the pid.
+        file.set_len(0).map_err(io_error)?;
+        file.rewind().map_err(io_error)?;
+        file.write_all(format!("{}\n", getpid()).as_bytes())
+            .map_err(io_error)?;
+
+        Ok(PidFile {
+            path: path.to_string(),
+            file,
+        })
+    }
+
+    /// Clear the pid of the file. The file itself is kept: unlinking it while the lock
+    /// is held would let a starting daemon lock a new file at the same path while this
+    /// one still runs. The lock is held until the daemon exits.
+    pub fn remove(&self) {
+        if let Err(err) = self.file.set_len(0) {
+            error!("Failed to clear pid file {}: {}", self.path, err);
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! The init process of a container. It is cloned into new namespaces, waits for the
+//! daemon to map its user and group ids (see `idmap`) and to move it into its cgroup
+//! (see `cgroup`), becomes root of its user namespace, isolates itself in the root
+//! filesystem of the container (see `rootfs`) and finally executes the command of
+//! the container.
+//!
+//! The daemon is multithreaded, so the cloned child must not allocate or take any
+//! lock a thread of the daemon might have held. Everything it needs is prepared
+//! before cloning and it only makes raw system calls. A failing step is reported to
+//! the daemon over a pipe that is closed once the command is executed.
+
+use crate::{cgroup::Cgroup, error::DaemonError, idmap::IdMaps, rootfs::Rootfs};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sched::{clone, CloneFlags},
+    sys::{
+        signal::{SigSet, SigmaskHow, Signal},
+        wait::waitpid,
+    },
+    unistd::{pipe2, Gid, Pid, Uid},
+};
+use std::{
+    ffi::{CString, OsStr},
+    fmt,
+    fs::OpenOptions,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+/// The stack of the cloned child, it only lives until the command is executed.
+const STACK_SIZE: usize = 2 * 1024 * 1024; // 2 MB
+
+/// The `PATH` of a container unless it sets one.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// What to run in a container.
+/// - `volumes` are the host directories mounted into the container, with the paths
+///   they are mounted at.
+/// - `env` holds `KEY=VALUE` pairs.
+/// - `stdout` and `stderr` are the files the output of the command is written to. The
+///   standard input is `/dev/null`.
+pub struct Spec<'a> {
+    pub rootfs: &'a Path,
+    pub volumes: &'a [(PathBuf, String)],
+    pub hostname: &'a str,
+    pub command: &'a [String],
+    pub env: &'a [String],
+    pub id_maps: &'a IdMaps,
+    pub cgroup: Option<&'a Cgroup>,
+    pub stdout: BorrowedFd<'a>,
+    pub stderr: BorrowedFd<'a>,
+}
+
+/// The steps of setting up the container init. The failing one is reported to the daemon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Stage {
+    Wait = 1,
+    Hostname = 2,
+    Rootfs = 3,
+    Signals = 4,
+    Stdio = 5,
+    Exec = 6,
+    User = 7,
+    Cgroup = 8,
+}
+
+impl Stage {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Stage::Wait),
+            2 => Some(Stage::Hostname),
+            3 => Some(Stage::Rootfs),
+            4 => Some(Stage::Signals),
+            5 => Some(Stage::Stdio),
+            6 => Some(Stage::Exec),
+            7 => Some(Stage::User),
+            8 => Some(Stage::Cgroup),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            Stage::Wait => "wait for the daemon",
+            Stage::Hostname => "set the hostname",
+            Stage::Rootfs => "set up its root filesystem",
+            Stage::Signals => "unblock signals",
+            Stage::Stdio => "set up standard input and output",
+            Stage::Exec => "execute the command",
+            Stage::User => "become root of its user namespace",
+            Stage::Cgroup => "enter its cgroup namespace",
+        };
+        write!(f, "{}", stage)
+    }
+}
+
+/// A failing stage, its errno and the failing step of the stage, as written to the
+/// report pipe.
+const REPORT_LENGTH: usize = 9;
+
+/// A failing stage as reported by the child.
+struct Report {
+    stage: Stage,
+    errno: Errno,
+    step: usize,
+}
+
+/// Everything the child needs, prepared before cloning.
+struct Init {
+    hostname: CString,
+    rootfs: Rootfs,
+    /// The paths the command may be found at, in the order they are tried.
+    programs: Vec<CString>,
+    // own the strings the pointers point to.
+    _argv: Vec<CString>,
+    _envp: Vec<CString>,
+    argv_pointers: Vec<*const libc::c_char>,
+    envp_pointers: Vec<*const libc::c_char>,
+    null: OwnedFd,
+    stdout: RawFd,
+    stderr: RawFd,
+    ready_read: OwnedFd,
+    ready_write: RawFd,
+    report_read: RawFd,
+    report_write: OwnedFd,
+}
+
+/// Clone the init of a container into new namespaces and start the command of the
+/// container in it. Returns once the command is executed.
+pub fn spawn(spec: &Spec) -> Result<Pid, DaemonError> {
+    let (ready_read, ready_write) = pipe()?;
+    let (report_read, report_write) = pipe()?;
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| DaemonError::OpenNull { source: e })?;
+    let mut init = Init::new(
+        spec,
+        null.into(),
+        ready_read,
+        ready_write.as_raw_fd(),
+        report_read.as_raw_fd(),
+        report_write,
+    )?;
+
+    let flags = CloneFlags::CLONE_NEWUTS
+        | CloneFlags::CLONE_NEWPID
+        | CloneFlags::CLONE_NEWNS
+        | CloneFlags::CLONE_NEWNET
+        | CloneFlags::CLONE_NEWIPC
+        | CloneFlags::CLONE_NEWUSER;
+    let mut stack = vec![0u8; STACK_SIZE];
+    let pid = unsafe {
+        clone(
+            Box::new(|| init.run()),
+            &mut stack,
+            flags,
+            Some(Signal::SIGCHLD as i32),
+        )
+    }
+    .map_err(|e| DaemonError::CloneSyscall { errno: e })?;
+
+    // the ends of the child are closed so the pipes report when it is gone.
+    let rootfs = init.close();
+    // the child waits until it is mapped and moved into its cgroup.
+    let prepared = spec.id_maps.write(pid).and_then(|_| match spec.cgroup {
+        Some(cgroup) => cgroup.add(pid),
+        None => Ok(()),
+    });
+    if prepared.is_ok() {
+        let _ = nix::unistd::write(ready_write.as_raw_fd(), &[0]);
+    }
+    drop(ready_write);
+
+    let failure = read_report(&report_read);
+    if prepared.is_err() || failure.is_some() {
+        waitpid(pid, None).map_err(|e| DaemonError::WaitSyscall { errno: e })?;
+    }
+    prepared?;
+    match failure {
+        Some(Report {
+            stage: Stage::Rootfs,
+            errno,
+            step,
+        }) => Err(DaemonError::ContainerRootfs {
+            step: rootfs.describe(step),
+            errno,
+        }),
+        Some(Report { stage, errno, .. }) => Err(DaemonError::ContainerInit { stage, errno }),
+        None => Ok(pid),
+    }
+}
+
+/// Create a pipe whose ends are closed when a command is executed.
+fn pipe() -> Result<(OwnedFd, OwnedFd), DaemonError> {
+    let (read, write) =
+        pipe2(OFlag::O_CLOEXEC).map_err(|e| DaemonError::CreatePipe { errno: e })?;
+    // the ends were just created and are owned by nothing else.
+    Ok(unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) })
+}
+
+/// Read the report of the child. `None` if it executed the command.
+fn read_report(report: &OwnedFd) -> Option<Report> {
+    let mut buffer = [0u8; REPORT_LENGTH];
+    let mut length = 0;
+    while length < REPORT_LENGTH {
+        match nix::unistd::read(report.as_raw_fd(), &mut buffer[length..]) {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+    }
+    if length < REPORT_LENGTH {
+        return None;
+    }
+    let stage = Stage::from_u8(buffer[0])?;
+    let errno = i32::from_ne_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]);
+    let step = u32::from_ne_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+    Some(Report {
+        stage,
+        errno: Errno::from_i32(errno),
+        step: step as usize,
+    })
+}
+
+impl Init {
+    fn new(
+        spec: &Spec,
+        null: OwnedFd,
+        ready_read: OwnedFd,
+        ready_write: RawFd,
+        report_read: RawFd,
+        report_write: OwnedFd,
+    ) -> Result<Self, DaemonError> {
+        let invalid = |reason: &str| DaemonError::InvalidRequest {
+            reason: reason.to_string(),
+        };
+        let cstring = |value: &[u8], what: &str| {
+            CString::new(value).map_err(|_| invalid(&format!("{} contains a NUL byte", what)))
+        };
+
+        let mut env = spec.env.to_vec();
+        if !env.iter().any(|variable| variable.starts_with("PATH=")) {
+            env.push(format!("PATH={}", DEFAULT_PATH));
+        }
+        let path = env
+            .iter()
+            .rev()
+            .find_map(|variable| variable.strip_prefix("PATH="))
+            .unwrap_or(DEFAULT_PATH);
+
+        // the command is looked up in `PATH` of the container unless it is a path.
+        let program = spec
+            .command
+            .first()
+            .ok_or_else(|| invalid("no command given"))?;
+        let programs = match program.contains('/') {
+            true => vec![cstring(program.as_bytes(), "the command")?],
+            false => path
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| cstring(format!("{}/{}", dir, program).as_bytes(), "the command"))
+                .collect::<Result<_, _>>()?,
+        };
+        let argv = spec
+            .command
+            .iter()
+            .map(|arg| cstring(arg.as_bytes(), "the command"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let envp = env
+            .iter()
+            .map(|variable| cstring(variable.as_bytes(), "the environment"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let argv_pointers = pointers(&argv);
+        let envp_pointers = pointers(&envp);
+
+        Ok(Init {
+            hostname: cstring(spec.hostname.as_bytes(), "the hostname")?,
+            rootfs: Rootfs::new(spec.rootfs, spec.volumes)?,
+            programs,
+            _argv: argv,
+            _envp: envp,
+            argv_pointers,
+            envp_pointers,
+            null,
+            stdout: spec.stdout.as_raw_fd(),
+            stderr: spec.stderr.as_raw_fd(),
+            ready_read,
+            ready_write,
+            report_read,
+            report_write,
+        })
+    }
+
+    /// The body of the child. Only returns if a step fails.
+    fn run(&mut self) -> isize {
+        // the daemon's ends of the pipes stay open in the child otherwise.
+        unsafe {
+            libc::close(self.ready_write);
+            libc::close(self.report_read);
+        }
+
+        // the user namespace is usable once the daemon has written the id maps.
+        let mut byte = [0u8; 1];
+        match nix::unistd::read(self.ready_read.as_raw_fd(), &mut byte) {
+            Ok(1) => {}
+            Ok(_) => return self.fail(Stage::Wait, Errno::UnknownErrno),
+            Err(errno) => return self.fail(Stage::Wait, errno),
+        }
+
+        // the init still has the ids of the daemon, which the namespace may not map.
+        // dropping the supplementary groups fails if setgroups is denied, there are
+        // none to drop then.
+        unsafe { libc::setgroups(0, ptr::null()) };
+        let root = (Gid::from_raw(0), Uid::from_raw(0));
+        if let Err(errno) = nix::unistd::setresgid(root.0, root.0, root.0)
+            .and_then(|_| nix::unistd::setresuid(root.1, root.1, root.1))
+        {
+            return self.fail(Stage::User, errno);
+        }
+
+        // the cgroup namespace is created once the daemon moved the init into the
+        // cgroup of the container, which becomes its root.
+        if let Err(errno) = nix::sched::unshare(CloneFlags::CLONE_NEWCGROUP) {
+            return self.fail(Stage::Cgroup, errno);
+        }
+
+        let hostname = OsStr::from_bytes(self.hostname.to_bytes());
+        if let Err(errno) = nix::unistd::sethostname(hostname) {
+            return self.fail(Stage::Hostname, errno);
+        }
+        if let Err((step, errno)) = self.rootfs.setup() {
+            return self.fail_step(Stage::Rootfs, errno, step);
+        }
+
+        // the daemon blocks the signals it handles, the mask survives the exec.
+        if let Err(errno) =
+            nix::sys::signal::sigprocmask(SigmaskHow::SIG_SETMASK, Some(&SigSet::empty()), None)
+        {
+            return self.fail(Stage::Signals, errno);
+        }
+
+        for (source, fd) in [
+            (self.null.as_raw_fd(), 0),
+            (self.stdout, 1),
+            (self.stderr, 2),
+        ] {
+            if let Err(errno) = nix::unistd::dup2(source, fd) {
+                return self.fail(Stage::Stdio, errno);
+            }
+        }
+
+        // like a shell, a command found but not executable beats one not found.
+        let mut error = Errno::ENOENT;
+        for program in &self.programs {
+            unsafe {
+                libc::execve(
+                    program.as_ptr(),
+                    self.argv_pointers.as_ptr(),
+                    self.envp_pointers.as_ptr(),
+                )
+            };
+            let errno = Errno::last();
+            if errno != Errno::ENOENT && errno != Errno::ENOTDIR {
+                error = errno;
+            }
+        }
+        self.fail(Stage::Exec, error)
+    }
+
+    /// Report a failing stage to the daemon and exit.
+    fn fail(&self, stage: Stage, errno: Errno) -> isize {
+        self.fail_step(stage, errno, 0)
+    }
+
+    /// Report a failing step of a stage to the daemon and exit.
+    fn fail_step(&self, stage: Stage, errno: Errno, step: usize) -> isize {
+        let errno = (errno as i32).to_ne_bytes();
+        let step = (step as u32).to_ne_bytes();
+        let report = [
+            stage as u8,
+            errno[0],
+            errno[1],
+            errno[2],
+            errno[3],
+            step[0],
+            step[1],
+            step[2],
+            step[3],
+        ];
+        let _ = nix::unistd::write(self.report_write.as_raw_fd(), &report);
+        127
+    }
+
+    /// Close the ends of the pipes the child uses. The prepared rootfs is kept to
+    /// describe a failing step.
+    fn close(self) -> Rootfs {
+        self.rootfs
+    }
+}
+
+/// A null-terminated array of pointers to `strings`, as taken by `execve`.
+fn pointers(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|string| string.as_ptr())
+        .chain(std::iter::once(ptr::null()))
+        .collect()
+}
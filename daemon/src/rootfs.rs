@@ -0,0 +1,288 @@
+//! The root filesystem of a container. The container init isolates itself in the
+//! root filesystem of the container before executing its command:
+//! - All mounts are made private, so nothing done in the container propagates to the host.
+//! - The root filesystem is bind-mounted onto itself, `pivot_root` requires a mount point.
+//! - The volumes are bind-mounted onto their paths, which are created if missing.
+//! - `/proc`, `/sys`, `/dev` (a tmpfs with the minimal device nodes), `/dev/pts` and
+//!   `/dev/shm` are mounted.
+//! - The root filesystem becomes `/` with `pivot_root` and the old root is unmounted,
+//!   so no host path is reachable afterwards.
+//!
+//! Like the rest of the init, the steps are prepared by the daemon and only run as
+//! raw system calls in the container.
+
+use crate::error::DaemonError;
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sys::stat::Mode,
+    unistd::{chdir, close, mkdir, pivot_root, symlinkat},
+};
+use std::{
+    ffi::{CStr, CString, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// The device nodes of the host bind-mounted into `/dev`. A user namespace may not
+/// create device nodes itself.
+const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// The symlinks created in `/dev`, as `(path, target)`.
+const DEVICE_LINKS: [(&str, &str); 5] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx"),
+];
+
+/// A single step of setting up the root filesystem.
+enum Step {
+    /// Create a directory unless it exists.
+    Mkdir {
+        path: CString,
+    },
+    /// Create an empty file unless it exists, e.g. to bind-mount a device node onto.
+    Touch {
+        path: CString,
+    },
+    Symlink {
+        path: CString,
+        target: CString,
+    },
+    Mount {
+        source: Option<CString>,
+        target: CString,
+        fstype: Option<CString>,
+        flags: MsFlags,
+        data: Option<CString>,
+    },
+    /// Make `root` the root directory and unmount the old one.
+    PivotRoot {
+        root: CString,
+    },
+}
+
+/// The prepared steps setting up the root filesystem of a container.
+pub struct Rootfs {
+    root: PathBuf,
+    steps: Vec<Step>,
+}
+
+impl Rootfs {
+    /// Prepare the setup of the root filesystem at `root`. `volumes` are the host
+    /// directories to mount and the paths in the container to mount them at.
+    pub fn new(root: &Path, volumes: &[(PathBuf, String)]) -> Result<Self, DaemonError> {
+        let mut rootfs = Rootfs {
+            root: root.to_path_buf(),
+            steps: Vec::new(),
+        };
+        let nosuid = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+
+        let host_root = cstring(b"/")?;
+        rootfs.mount(
+            None,
+            host_root,
+            None,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None,
+        )?;
+        let root = rootfs.path("/")?;
+        rootfs.mount(
+            Some(root.clone()),
+            root,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None,
+        )?;
+
+        for (source, path) in volumes {
+            let mut parent = PathBuf::from("/");
+            for component in Path::new(path).components().skip(1) {
+                parent.push(component);
+                rootfs.mkdir(&parent.to_string_lossy())?;
+            }
+            rootfs.mount(
+                Some(cstring(source.as_os_str().as_bytes())?),
+                rootfs.path(path)?,
+                None,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None,
+            )?;
+        }
+
+        rootfs.mkdir("/proc")?;
+        rootfs.mount_filesystem("proc", "/proc", nosuid, None)?;
+        rootfs.mkdir("/sys")?;
+        rootfs.mount_filesystem("sysfs", "/sys", nosuid | MsFlags::MS_RDONLY, None)?;
+
+        rootfs.mkdir("/dev")?;
+        rootfs.mount_filesystem(
+            "tmpfs",
+            "/dev",
+            MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
+            Some("mode=755,size=65536k"),
+        )?;
+        for device in DEVICES {
+            let path = format!("/dev/{}", device);
+            let target = rootfs.path(&path)?;
+            rootfs.steps.push(Step::Touch {
+                path: target.clone(),
+            });
+            rootfs.mount(
+                Some(cstring(path.as_bytes())?),
+                target,
+                None,
+                MsFlags::MS_BIND,
+                None,
+            )?;
+        }
+        for (path, target) in DEVICE_LINKS {
+            rootfs.steps.push(Step::Symlink {
+                path: rootfs.path(&format!("/dev/{}", path))?,
+                target: cstring(target.as_bytes())?,
+            });
+        }
+        rootfs.mkdir("/dev/pts")?;
+        rootfs.mount_filesystem(
+            "devpts",
+            "/dev/pts",
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("newinstance,ptmxmode=0666,mode=0620"),
+        )?;
+        rootfs.mkdir("/dev/shm")?;
+        rootfs.mount_filesystem("tmpfs", "/dev/shm", nosuid, Some("mode=1777,size=65536k"))?;
+
+        let root = rootfs.path("/")?;
+        rootfs.steps.push(Step::PivotRoot { root });
+        Ok(rootfs)
+    }
+
+    /// The host path of `path` in the container.
+    fn path(&self, path: &str) -> Result<CString, DaemonError> {
+        let relative = path.trim_start_matches('/');
+        cstring(self.root.join(relative).as_os_str().as_bytes())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), DaemonError> {
+        let path = self.path(path)?;
+        self.steps.push(Step::Mkdir { path });
+        Ok(())
+    }
+
+    /// Mount a new instance of a filesystem onto a path of the container.
+    fn mount_filesystem(
+        &mut self,
+        fstype: &str,
+        target: &str,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> Result<(), DaemonError> {
+        let source = cstring(fstype.as_bytes())?;
+        let target = self.path(target)?;
+        self.mount(Some(source), target, Some(fstype), flags, data)
+    }
+
+    fn mount(
+        &mut self,
+        source: Option<CString>,
+        target: CString,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> Result<(), DaemonError> {
+        let optional = |value: Option<&str>| value.map(|value| cstring(value.as_bytes()));
+        self.steps.push(Step::Mount {
+            source,
+            target,
+            fstype: optional(fstype).transpose()?,
+            flags,
+            data: optional(data).transpose()?,
+        });
+        Ok(())
+    }
+
+    /// Run the steps in the container. Fails with the index of the failing step.
+    /// Must not allocate, see `process`.
+    pub fn setup(&self) -> Result<(), (usize, Errno)> {
+        for (index, step) in self.steps.iter().enumerate() {
+            Self::run(step).map_err(|errno| (index, errno))?;
+        }
+        Ok(())
+    }
+
+    fn run(step: &Step) -> Result<(), Errno> {
+        match step {
+            Step::Mkdir { path } => match mkdir(path.as_c_str(), Mode::from_bits_truncate(0o755)) {
+                Err(Errno::EEXIST) => Ok(()),
+                result => result,
+            },
+            Step::Touch { path } => {
+                let fd = open(
+                    path.as_c_str(),
+                    OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+                    Mode::from_bits_truncate(0o644),
+                )?;
+                close(fd)
+            }
+            Step::Symlink { path, target } => symlinkat(target.as_c_str(), None, path.as_c_str()),
+            Step::Mount {
+                source,
+                target,
+                fstype,
+                flags,
+                data,
+            } => mount(
+                source.as_deref(),
+                target.as_c_str(),
+                fstype.as_deref(),
+                *flags,
+                data.as_deref(),
+            ),
+            Step::PivotRoot { root } => {
+                // with the new root as the current directory, the old root ends up
+                // stacked on top of it and is unmounted right away.
+                chdir(root.as_c_str())?;
+                let current = c".";
+                pivot_root(current, current)?;
+                umount2(current, MntFlags::MNT_DETACH)?;
+                chdir(c"/")
+            }
+        }
+    }
+
+    /// Describe the step at `index`, with paths as seen from the container.
+    pub fn describe(&self, index: usize) -> String {
+        let path = |path: &CStr| {
+            let path = Path::new(OsStr::from_bytes(path.to_bytes()));
+            match path.strip_prefix(&self.root) {
+                Ok(relative) => format!("/{}", relative.display()),
+                Err(_) => path.display().to_string(),
+            }
+        };
+        match self.steps.get(index) {
+            Some(Step::Mkdir { path: dir }) => format!("create {}", path(dir)),
+            Some(Step::Touch { path: file }) => format!("create {}", path(file)),
+            Some(Step::Symlink { path: link, .. }) => format!("link {}", path(link)),
+            Some(Step::Mount {
+                source,
+                target,
+                fstype,
+                ..
+            }) => match fstype.as_deref().or(source.as_deref()).map(path) {
+                Some(what) => format!("mount {} on {}", what, path(target)),
+                None => format!("change the propagation of {}", path(target)),
+            },
+            Some(Step::PivotRoot { .. }) => "pivot into its root filesystem".to_string(),
+            None => "set up its root filesystem".to_string(),
+        }
+    }
+}
+
+fn cstring(value: &[u8]) -> Result<CString, DaemonError> {
+    CString::new(value).map_err(|_| DaemonError::InvalidRequest {
+        reason: format!("{} contains a NUL byte", String::from_utf8_lossy(value)),
+    })
+}
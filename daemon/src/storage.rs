@@ -0,0 +1,317 @@
+//! The storage drivers building the root filesystem of a container from the layers
+//! of its image. The layers are never modified, every container writes to its own
+//! directory under `containers_dir`:
+//! - `overlay` mounts an overlayfs with the layers as lower dirs and the `upper` and
+//!   `work` directories of the container. Starting a container copies nothing.
+//! - `fuse-overlayfs` mounts the same overlay with `fuse-overlayfs`, which does not
+//!   require the privileges to mount an overlayfs.
+//! - `copy` copies the layers into the container, for hosts without any overlay.
+//!
+//! Layers are stored in the overlayfs format: a whiteout is a character device with
+//! device number 0/0 and an opaque directory carries the `overlay.opaque` xattr.
+
+use crate::{
+    config::{Storage, StorageDriver},
+    error::DaemonError,
+};
+use log::warn;
+use nix::{
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sys::stat::{major, makedev, minor, mknod, Mode, SFlag},
+    unistd::{fchownat, geteuid, FchownatFlags, Gid, Uid},
+};
+use std::{
+    ffi::CString,
+    fs::{self, Metadata},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The xattrs marking an opaque directory, for overlays mounted by root and by users.
+const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// The directories of a container used by the storage drivers.
+/// - `rootfs` is the root filesystem the container sees.
+/// - `upper` and `work` hold the changes made by an overlay container.
+pub struct Layout {
+    pub rootfs: PathBuf,
+    pub upper: PathBuf,
+    pub work: PathBuf,
+}
+
+impl Layout {
+    pub fn new(container_dir: &Path) -> Self {
+        Layout {
+            rootfs: container_dir.join("rootfs"),
+            upper: container_dir.join("upper"),
+            work: container_dir.join("work"),
+        }
+    }
+}
+
+/// Build the root filesystem of a container in `container_dir` from `layers`,
+/// ordered from the bottom to the top layer.
+pub fn create(
+    storage: &Storage,
+    layers: &[PathBuf],
+    container_dir: &Path,
+) -> Result<(), DaemonError> {
+    let layout = Layout::new(container_dir);
+    match storage.driver {
+        StorageDriver::Overlay | StorageDriver::FuseOverlayfs => {
+            for dir in [&layout.rootfs, &layout.upper, &layout.work] {
+                create_dir(dir)?;
+            }
+            // the root of the overlay is the upper dir, it takes after the image.
+            if let Some(top) = layers.last() {
+                copy_owner(top, &layout.upper).map_err(|e| DaemonError::CreateContainer {
+                    path: layout.upper.display().to_string(),
+                    source: e,
+                })?;
+            }
+            let options = overlay_options(layers, &layout)?;
+            match storage.driver {
+                StorageDriver::Overlay => mount_overlay(&layout.rootfs, &options),
+                _ => mount_fuse_overlayfs(&storage.fuse_overlayfs, &layout.rootfs, &options),
+            }
+        }
+        StorageDriver::Copy => {
+            create_dir(&layout.rootfs)?;
+            for layer in layers {
+                copy_layer(layer, &layout.rootfs).map_err(|e| DaemonError::CopyRootfs {
+                    path: layout.rootfs.display().to_string(),
+                    source: e,
+                })?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Tear down the root filesystem of a container. Its directory is left in place.
+pub fn release(driver: StorageDriver, container_dir: &Path) -> Result<(), DaemonError> {
+    let rootfs = Layout::new(container_dir).rootfs;
+    let unmount = |rootfs: &Path| match umount2(rootfs, MntFlags::MNT_DETACH) {
+        // never mounted, e.g. the container failed to be created.
+        Ok(()) | Err(nix::errno::Errno::EINVAL) | Err(nix::errno::Errno::ENOENT) => Ok(()),
+        Err(errno) => Err(DaemonError::Unmount {
+            path: rootfs.display().to_string(),
+            errno,
+        }),
+    };
+    match driver {
+        StorageDriver::Overlay => unmount(&rootfs),
+        // an unprivileged daemon can only unmount its fuse mounts with fusermount.
+        StorageDriver::FuseOverlayfs => match unmount(&rootfs) {
+            Err(err) => ["fusermount3", "fusermount"]
+                .iter()
+                .find(|fusermount| {
+                    Command::new(fusermount)
+                        .arg("-u")
+                        .arg(&rootfs)
+                        .status()
+                        .is_ok_and(|status| status.success())
+                })
+                .map(|_| ())
+                .ok_or(err),
+            Ok(()) => Ok(()),
+        },
+        StorageDriver::Copy => Ok(()),
+    }
+}
+
+fn create_dir(dir: &Path) -> Result<(), DaemonError> {
+    fs::create_dir_all(dir).map_err(|e| DaemonError::CreateContainer {
+        path: dir.display().to_string(),
+        source: e,
+    })
+}
+
+/// The mount options of an overlay of `layers`. overlayfs takes the top layer first.
+fn overlay_options(layers: &[PathBuf], layout: &Layout) -> Result<String, DaemonError> {
+    let path = |path: &Path| {
+        let path = path.display().to_string();
+        // the separators of the options cannot be escaped.
+        match path.contains([',', ':']) {
+            true => Err(DaemonError::InvalidRequest {
+                reason: format!("{} cannot be used in an overlay", path),
+            }),
+            false => Ok(path),
+        }
+    };
+    let lower = layers
+        .iter()
+        .rev()
+        .map(|layer| path(layer))
+        .collect::<Result<Vec<_>, _>>()?
+        .join(":");
+    Ok(format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower,
+        path(&layout.upper)?,
+        path(&layout.work)?
+    ))
+}
+
+fn mount_overlay(rootfs: &Path, options: &str) -> Result<(), DaemonError> {
+    mount(
+        Some("overlay"),
+        rootfs,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(options),
+    )
+    .map_err(|e| DaemonError::MountOverlay {
+        path: rootfs.display().to_string(),
+        errno: e,
+    })
+}
+
+fn mount_fuse_overlayfs(binary: &str, rootfs: &Path, options: &str) -> Result<(), DaemonError> {
+    let failed = |reason: String| DaemonError::FuseOverlayfs {
+        path: rootfs.display().to_string(),
+        reason,
+    };
+    // fuse-overlayfs returns once the overlay is mounted and keeps serving it.
+    let status = Command::new(binary)
+        .arg("-o")
+        .arg(options)
+        .arg(rootfs)
+        .status()
+        .map_err(|e| failed(format!("failed to run {}: {}", binary, e)))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(failed(format!("{} exited with {}", binary, status))),
+    }
+}
+
+/// Copy a layer onto the layers below it, applying its whiteouts and opaque
+/// directories. Keeps the type, mode and owner of every entry.
+fn copy_layer(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if is_whiteout(&metadata) {
+        return remove(to);
+    }
+    let file_type = metadata.file_type();
+    let existing = fs::symlink_metadata(to).ok();
+
+    if file_type.is_dir() {
+        match existing {
+            Some(existing) if existing.is_dir() => {
+                if is_opaque(from) {
+                    for entry in fs::read_dir(to)? {
+                        remove(&entry?.path())?;
+                    }
+                }
+            }
+            Some(_) => {
+                remove(to)?;
+                fs::create_dir(to)?;
+            }
+            None => fs::create_dir(to)?,
+        }
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_layer(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        if existing.is_some() {
+            remove(to)?;
+        }
+        if file_type.is_symlink() {
+            symlink(fs::read_link(from)?, to)?;
+        } else if file_type.is_file() {
+            fs::copy(from, to)?;
+        } else {
+            // device nodes, fifos and sockets are recreated rather than read.
+            let kind = if file_type.is_block_device() {
+                SFlag::S_IFBLK
+            } else if file_type.is_char_device() {
+                SFlag::S_IFCHR
+            } else if file_type.is_fifo() {
+                SFlag::S_IFIFO
+            } else {
+                SFlag::S_IFSOCK
+            };
+            let rdev = metadata.rdev();
+            mknod(
+                to,
+                kind,
+                Mode::from_bits_truncate(metadata.mode()),
+                makedev(major(rdev), minor(rdev)),
+            )?;
+        }
+    }
+
+    copy_owner(from, to)
+}
+
+/// Give `to` the owner and mode of `from`. A rootless daemon cannot give away its
+/// files, they keep their owner.
+fn copy_owner(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    let owner = fchownat(
+        None,
+        to,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    );
+    match owner {
+        Err(nix::errno::Errno::EPERM) if !geteuid().is_root() => {}
+        result => result?,
+    }
+    // the owner is set first since changing it clears the setuid and setgid bits.
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(to, fs::Permissions::from_mode(metadata.mode()))?;
+    }
+    Ok(())
+}
+
+/// Remove a file or a directory tree, if it exists.
+fn remove(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Whether an entry of a layer deletes the entry of the layers below.
+fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Whether a directory of a layer hides the contents of the layers below.
+pub fn is_opaque(dir: &Path) -> bool {
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    OPAQUE_XATTRS.iter().any(|xattr| {
+        let Ok(name) = CString::new(*xattr) else {
+            return false;
+        };
+        let mut value = [0u8; 1];
+        let length = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if length < 0 && nix::errno::Errno::last() != nix::errno::Errno::ENODATA {
+            warn!("Failed to read {} of {}", xattr, dir.display());
+        }
+        length == 1 && value[0] == b'y'
+    })
+}
@@ -0,0 +1,80 @@
+//! The volumes of containers. A volume is a directory in `volumes_dir` named after its
+//! id, bind-mounted over a path of the container. It belongs to root in the container
+//! and outlives the container, unless it is removed along with it.
+
+use crate::{container, error::DaemonError};
+use nix::unistd::{chown, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// A volume of a container, mounted at `path` in the container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volume {
+    pub id: String,
+    pub path: String,
+}
+
+impl Volume {
+    /// Create an empty volume to mount at `path`, owned by `uid` and `gid` on the host.
+    /// `path` is checked with `check_path` beforehand.
+    pub fn create(
+        volumes_dir: &str,
+        path: &str,
+        uid: Option<Uid>,
+        gid: Option<Gid>,
+    ) -> Result<Self, DaemonError> {
+        let volume = Volume {
+            id: container::generate_id()?,
+            path: path.to_string(),
+        };
+        let dir = volume.dir(volumes_dir);
+        let create_error = |source: io::Error| DaemonError::CreateVolume {
+            path: dir.display().to_string(),
+            source,
+        };
+        fs::create_dir_all(&dir).map_err(create_error)?;
+        if let Err(errno) = chown(&dir, uid, gid) {
+            let _ = fs::remove_dir(&dir);
+            return Err(create_error(errno.into()));
+        }
+        Ok(volume)
+    }
+
+    /// The directory of the volume.
+    pub fn dir(&self, volumes_dir: &str) -> PathBuf {
+        Path::new(volumes_dir).join(&self.id)
+    }
+
+    /// Delete the volume along with its content.
+    pub fn remove(&self, volumes_dir: &str) -> Result<(), DaemonError> {
+        let dir = self.dir(volumes_dir);
+        match fs::remove_dir_all(&dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(DaemonError::RemoveVolume {
+                path: dir.display().to_string(),
+                source: err,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check the path a volume is to be mounted at: an absolute path below `/` without
+/// `..` components.
+pub fn check_path(path: &str) -> Result<(), DaemonError> {
+    let mut components = Path::new(path).components();
+    let absolute = components.next() == Some(Component::RootDir);
+    let mut normal = components.peekable();
+    let below_root = normal.peek().is_some();
+    if absolute && below_root && normal.all(|component| matches!(component, Component::Normal(_))) {
+        return Ok(());
+    }
+    Err(DaemonError::InvalidRequest {
+        reason: format!(
+            "{} is not an absolute path below / to mount a volume at",
+            path
+        ),
+    })
+}
@@ -0,0 +1,183 @@
+//! Tests for the cgroups of containers. Every container runs in its own cgroup,
+//! which is the root of its cgroup namespace and carries its resource limits.
+
+mod common;
+
+use common::{cgroup_root, TestDaemon};
+use shared::requests::{Resources, RunRequest};
+use std::fs;
+
+#[test]
+fn containers_run_in_their_own_cgroup() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    let Some(root) = cgroup_root() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script("test", "cat /proc/self/cgroup > /result");
+    let cgroups = daemon.read(&id, "/result");
+    let unified = cgroups.lines().find(|line| line.starts_with("0::"));
+    assert_eq!(unified, Some("0::/"), "{}", cgroups);
+    assert!(root.join(daemon.cgroup(&id)).is_dir());
+}
+
+#[test]
+fn limits_are_written_to_the_cgroup() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    let Some(root) = cgroup_root() else {
+        return;
+    };
+    daemon.image("test");
+
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            ": > /done".to_string(),
+        ],
+        resources: Resources {
+            memory: Some(64 << 20),
+            cpu_quota: Some(50_000),
+            pids: Some(32),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let controllers = fs::read_to_string(root.join("cgroup.controllers")).unwrap();
+    let available = ["cpu", "memory", "pids"].iter().all(|controller| {
+        controllers
+            .split_whitespace()
+            .any(|name| name == *controller)
+    });
+    if !available {
+        // the limits cannot be enforced, so the container is not started at all.
+        let error = daemon.client().run(request).unwrap_err();
+        assert!(
+            error.to_string().contains("Cannot limit resources"),
+            "{}",
+            error
+        );
+        let containers = fs::read_dir(daemon.dir.path().join("containers")).unwrap();
+        assert_eq!(containers.count(), 0);
+        return;
+    }
+
+    let id = daemon.client().run(request).unwrap().id;
+    let cgroup = root.join(daemon.cgroup(&id));
+    let read = |file: &str| fs::read_to_string(cgroup.join(file)).unwrap();
+    assert_eq!(read("memory.max"), format!("{}\n", 64 << 20));
+    assert_eq!(read("cpu.max"), "50000 100000\n");
+    assert_eq!(read("pids.max"), "32\n");
+}
+
+#[test]
+fn invalid_limits_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    if cgroup_root().is_none() {
+        return;
+    }
+    daemon.image("test");
+
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec!["/bin/sh".to_string()],
+        resources: Resources {
+            cpu_weight: Some(0),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let error = daemon.client().run(request).unwrap_err();
+    assert!(
+        error.to_string().contains("CPU weight") || error.to_string().contains("Cannot limit"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn unlimited_containers_run_without_a_cgroup_that_cannot_be_created() {
+    let Some(mut daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    // a regular file where the hierarchy should be mounted, like a parent the daemon
+    // may not write to.
+    let home = daemon.dir.path().to_path_buf();
+    fs::write(home.join("no-cgroups"), "").unwrap();
+    let config = home.join(".config/j1407b/daemon.toml");
+    let root = format!(
+        "[cgroups]\nroot = \"{}\"",
+        home.join("no-cgroups").display()
+    );
+    let content = fs::read_to_string(&config).unwrap();
+    fs::write(&config, content.replace("[cgroups]", &root)).unwrap();
+    daemon.restart();
+
+    let id = daemon.run_script("test", "echo unlimited > /result");
+    assert_eq!(daemon.read(&id, "/result"), "unlimited\n");
+
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec!["/bin/sh".to_string()],
+        resources: Resources {
+            pids: Some(32),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let error = daemon.client().run(request).unwrap_err();
+    assert!(
+        error.to_string().contains("Failed to create cgroup"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn containers_are_limited_below_the_cgroup_of_a_service() {
+    let Some(daemon) = TestDaemon::start_as_service() else {
+        return;
+    };
+    let root = cgroup_root().unwrap();
+    daemon.image("test");
+    let controllers = fs::read_to_string(root.join("cgroup.controllers")).unwrap_or_default();
+    let limited = controllers.split_whitespace().any(|name| name == "pids");
+
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            ": > /done".to_string(),
+        ],
+        resources: Resources {
+            pids: limited.then_some(32),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let id = daemon.client().run(request).unwrap().id;
+    let cgroup = root.join(daemon.cgroup(&id));
+    assert!(cgroup.is_dir());
+
+    // the daemon left the cgroup of the service so it could enable the controllers.
+    let service = cgroup.parent().unwrap().parent().unwrap();
+    assert_eq!(
+        fs::read_to_string(service.join("cgroup.procs")).unwrap(),
+        ""
+    );
+    let leaf = fs::read_to_string(service.join("daemon/cgroup.procs")).unwrap();
+    assert_eq!(leaf.lines().count(), 1, "{}", leaf);
+    if limited {
+        assert_eq!(fs::read_to_string(cgroup.join("pids.max")).unwrap(), "32\n");
+    }
+}
@@ -0,0 +1,352 @@
+//! A daemon running in a temporary home directory, with its own config, socket,
+//! image store and container store, for tests that run real containers.
+
+#![allow(dead_code)]
+
+use nix::{
+    mount::{umount2, MntFlags},
+    sys::signal::{kill, Signal},
+    unistd::{geteuid, Pid},
+};
+use shared::client::Client;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+/// How long to wait for the daemon to listen or for a container to finish.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The host binaries copied into test images.
+const BINARIES: [&str; 4] = ["sh", "cat", "ls", "sleep"];
+
+pub struct TestDaemon {
+    pub dir: TempDir,
+    daemon: Child,
+    // the cgroup the daemon is started in, like a service manager would, if any.
+    service: Option<PathBuf>,
+}
+
+impl TestDaemon {
+    /// Start a daemon. `None` if containers cannot be run by the current user.
+    pub fn start() -> Option<Self> {
+        Self::start_with("")
+    }
+
+    /// Start a daemon with `config` appended to its configuration.
+    pub fn start_with(config: &str) -> Option<Self> {
+        Self::start_in(config, false)
+    }
+
+    /// Start a daemon in a cgroup of its own, like a service manager would, without a
+    /// configured parent for the cgroups of containers. `None` without a cgroup v2
+    /// hierarchy.
+    pub fn start_as_service() -> Option<Self> {
+        if cgroup_root().is_none() {
+            eprintln!("skipping, no cgroup v2 hierarchy is mounted");
+            return None;
+        }
+        Self::start_in("", true)
+    }
+
+    fn start_in(config: &str, service: bool) -> Option<Self> {
+        if !geteuid().is_root() {
+            eprintln!("skipping, running containers requires root");
+            return None;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path();
+        let cgroups = match service {
+            true => String::new(),
+            false => format!("[cgroups]\nparent = \"{}\"", cgroup_parent(home).display()),
+        };
+        let config = format!(
+            r#"
+images_dir = "{home}/images"
+containers_dir = "{home}/containers"
+pid_file = "{home}/run/j1407b.pid"
+
+[registry]
+url = "hub.docker.com"
+token = ""
+https = true
+
+[socket]
+path = "{home}/run/j1407b.sock"
+
+[logging]
+level = "debug"
+file = "{home}/daemon.log"
+
+{cgroups}
+
+{config}
+"#,
+            home = home.display(),
+            cgroups = cgroups,
+            config = config
+        );
+        fs::create_dir_all(home.join(".config/j1407b")).unwrap();
+        fs::write(home.join(".config/j1407b/daemon.toml"), config).unwrap();
+
+        let service = match service {
+            true => {
+                let cgroup = cgroup_root().unwrap().join(cgroup_parent(home));
+                fs::create_dir_all(&cgroup).unwrap();
+                Some(cgroup)
+            }
+            false => None,
+        };
+        let daemon = TestDaemon {
+            daemon: spawn(home, service.as_deref()),
+            dir,
+            service,
+        };
+        daemon.wait_listening();
+        Some(daemon)
+    }
+
+    /// Shut the daemon down and wait for it to exit.
+    pub fn shutdown(&mut self) {
+        // the pid of a daemon that already exited may have been reused.
+        if let Ok(None) = self.daemon.try_wait() {
+            let _ = kill(Pid::from_raw(self.daemon.id() as i32), Signal::SIGTERM);
+        }
+        let _ = self.daemon.wait();
+    }
+
+    /// Shut the daemon down and start it again. Its containers keep running.
+    pub fn restart(&mut self) {
+        self.shutdown();
+        self.daemon = spawn(self.dir.path(), self.service.as_deref());
+        self.wait_listening();
+    }
+
+    /// Wait until the daemon accepts connections. Its socket file exists a moment
+    /// before it listens on it.
+    fn wait_listening(&self) {
+        wait_until(|| Client::connect(self.socket()).is_ok());
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        self.dir.path().join("run/j1407b.sock")
+    }
+
+    pub fn client(&self) -> Client {
+        Client::connect(self.socket()).unwrap()
+    }
+
+    /// Unpack an image named `name` made of a few host binaries and their libraries.
+    pub fn image(&self, name: &str) {
+        base_layer(&self.dir.path().join("images").join(name).join("rootfs"));
+    }
+
+    /// The directory of layer `layer` of image `name`.
+    pub fn layer(&self, name: &str, layer: &str) -> PathBuf {
+        let dir = self
+            .dir
+            .path()
+            .join("images")
+            .join(name)
+            .join("layers")
+            .join(layer);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The root filesystem of a container as seen from the host.
+    pub fn rootfs(&self, id: &str) -> PathBuf {
+        self.dir.path().join("containers").join(id).join("rootfs")
+    }
+
+    /// Run `script` with `sh` in a container of `image` and wait for it to create
+    /// `/done`. Returns the container id.
+    pub fn run_script(&self, image: &str, script: &str) -> String {
+        let request = shared::requests::RunRequest {
+            image: image.to_string(),
+            command: vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                format!("{}; : > /done", script),
+            ],
+            ..Default::default()
+        };
+        let id = self.client().run(request).unwrap().id;
+        let done = self.rootfs(&id).join("done");
+        wait_until(|| done.exists());
+        id
+    }
+
+    /// Start a container of the `test` image named `name` with `labels` that runs
+    /// `script` with `sh`, then sleeps until it is stopped or `/stop` is created. Waits
+    /// until `script` ran. Returns the container id.
+    pub fn start_container(&self, name: &str, labels: &[(&str, &str)], script: &str) -> String {
+        let request = shared::requests::RunRequest {
+            image: "test".to_string(),
+            command: vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                format!(
+                    "{}\n: > /started\nwhile [ ! -e /stop ]; do sleep 0.1; done",
+                    script
+                ),
+            ],
+            name: Some(name.to_string()),
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let id = self.client().run(request).unwrap().id;
+        let started = self.rootfs(&id).join("started");
+        wait_until(|| started.exists());
+        id
+    }
+
+    /// Read a file of a container.
+    pub fn read(&self, id: &str, path: &str) -> String {
+        fs::read_to_string(self.rootfs(id).join(path.trim_start_matches('/'))).unwrap()
+    }
+
+    /// The cgroup of a container, relative to the cgroup v2 hierarchy. The daemon of a
+    /// service creates them in `j1407b` below its own cgroup.
+    pub fn cgroup(&self, id: &str) -> PathBuf {
+        match self.service {
+            Some(_) => cgroup_parent(self.dir.path()).join("j1407b").join(id),
+            None => cgroup_parent(self.dir.path()).join(id),
+        }
+    }
+
+    /// The log of the daemon, to debug failing tests.
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("daemon.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for TestDaemon {
+    fn drop(&mut self) {
+        self.shutdown();
+        // the root filesystems of containers stay mounted after the daemon exits.
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        for mount_point in mountinfo
+            .lines()
+            .rev()
+            .filter_map(|line| line.split(' ').nth(4))
+        {
+            if Path::new(mount_point).starts_with(self.dir.path()) {
+                let _ = umount2(mount_point, MntFlags::MNT_DETACH);
+            }
+        }
+        // so are the cgroups of containers, along with any process left in them.
+        if let Some(root) = cgroup_root() {
+            let parent = root.join(cgroup_parent(self.dir.path()));
+            remove_cgroups(&parent);
+            let _ = fs::remove_dir(&parent);
+            let _ = fs::remove_dir(parent.parent().unwrap());
+        }
+    }
+}
+
+/// Kill the processes of the cgroups below `parent` and remove them, deepest first.
+fn remove_cgroups(parent: &Path) {
+    let entries = fs::read_dir(parent).into_iter().flatten().flatten();
+    for cgroup in entries
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+    {
+        remove_cgroups(&cgroup);
+        let _ = fs::write(cgroup.join("cgroup.kill"), "1");
+        wait_until(|| fs::remove_dir(&cgroup).is_ok());
+    }
+}
+
+/// Run the daemon in `home`, in the cgroup `service` if given.
+fn spawn(home: &Path, service: Option<&Path>) -> Child {
+    let mut command = match service {
+        // the shell moves itself into the cgroup before it becomes the daemon.
+        Some(cgroup) => {
+            let mut command = Command::new("/bin/sh");
+            command
+                .arg("-c")
+                .arg(r#"echo $$ > "$1/cgroup.procs" && exec "$0""#)
+                .arg(env!("CARGO_BIN_EXE_daemon"))
+                .arg(cgroup);
+            command
+        }
+        None => Command::new(env!("CARGO_BIN_EXE_daemon")),
+    };
+    command
+        .env("HOME", home)
+        .env("XDG_RUNTIME_DIR", home.join("run"))
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// The cgroup the containers of a test daemon are created in, relative to the
+/// cgroup v2 hierarchy.
+fn cgroup_parent(home: &Path) -> PathBuf {
+    Path::new("j1407b-tests").join(home.file_name().unwrap())
+}
+
+/// Where the cgroup v2 hierarchy is mounted, if anywhere.
+pub fn cgroup_root() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap();
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        match filesystem.starts_with("cgroup2 ") {
+            true => mount.split(' ').nth(4).map(PathBuf::from),
+            false => None,
+        }
+    })
+}
+
+/// Wait until `condition` holds, panicking after `TIMEOUT`.
+pub fn wait_until<F: Fn() -> bool>(condition: F) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Fill `rootfs` with a few host binaries and their libraries.
+pub fn base_layer(rootfs: &Path) {
+    for dir in ["bin", "tmp"] {
+        fs::create_dir_all(rootfs.join(dir)).unwrap();
+    }
+    for binary in BINARIES {
+        let path = which(binary);
+        fs::copy(&path, rootfs.join("bin").join(binary)).unwrap();
+        for library in libraries(&path) {
+            let target = rootfs.join(library.strip_prefix("/").unwrap());
+            fs::create_dir_all(target.parent().unwrap()).unwrap();
+            fs::copy(&library, target).unwrap();
+        }
+    }
+}
+
+/// Find a binary in `PATH`.
+fn which(binary: &str) -> PathBuf {
+    env::var("PATH")
+        .unwrap()
+        .split(':')
+        .map(|dir| Path::new(dir).join(binary))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| panic!("{} not found", binary))
+}
+
+/// The shared libraries a binary is linked against, as listed by `ldd`.
+fn libraries(binary: &Path) -> Vec<PathBuf> {
+    let output = Command::new("ldd").arg(binary).output().unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .filter(|word| word.starts_with('/'))
+        .map(PathBuf::from)
+        .collect()
+}
@@ -0,0 +1,93 @@
+//! Tests for the limits the daemon puts on its connections: how many it serves at once
+//! and how long it waits for them.
+
+mod common;
+
+use common::TestDaemon;
+use shared::{
+    error::SharedError,
+    protocol::{Handshake, Protocol, Type, DEFAULT_MAX_BODY_SIZE},
+};
+use std::{io::Read, os::unix::net::UnixStream, time::Duration};
+
+/// Connect to the daemon and send it a handshake, without waiting for its answer.
+fn connect(daemon: &TestDaemon) -> UnixStream {
+    let mut stream = UnixStream::connect(daemon.socket()).unwrap();
+    Protocol::send(
+        &mut stream,
+        &Protocol::write_handshake(Handshake::local()).unwrap(),
+    )
+    .unwrap();
+    stream
+}
+
+/// Read the handshake of the daemon, or `None` if it does not arrive within `timeout`.
+fn answer(stream: &mut UnixStream, timeout: Duration) -> Option<Handshake> {
+    stream.set_read_timeout(Some(timeout)).unwrap();
+    match Protocol::read_frame(stream, DEFAULT_MAX_BODY_SIZE) {
+        Ok(frame) => {
+            assert_eq!(frame.header._type, Type::Handshake);
+            Some(frame.decode().unwrap())
+        }
+        Err(SharedError::ReadConnection { .. }) => None,
+        Err(err) => panic!("unexpected error: {}", err),
+    }
+}
+
+/// Whether the daemon closes the connection within `timeout`.
+fn closed(stream: &mut UnixStream, timeout: Duration) -> bool {
+    stream.set_read_timeout(Some(timeout)).unwrap();
+    matches!(stream.read(&mut [0; 1]), Ok(0))
+}
+
+#[test]
+fn connections_without_a_handshake_are_closed() {
+    let config = r#"
+[connections]
+read_timeout = 1
+"#;
+    let Some(daemon) = TestDaemon::start_with(config) else {
+        return;
+    };
+    let mut stream = UnixStream::connect(daemon.socket()).unwrap();
+
+    // the daemon still answers with its handshake before it gives up.
+    assert!(answer(&mut stream, Duration::from_secs(5)).is_some());
+    assert!(closed(&mut stream, Duration::from_secs(5)));
+    assert!(daemon
+        .log()
+        .contains("Timed out after 1s reading from the client"));
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let config = r#"
+[connections]
+idle_timeout = 1
+"#;
+    let Some(daemon) = TestDaemon::start_with(config) else {
+        return;
+    };
+    let mut stream = connect(&daemon);
+    assert!(answer(&mut stream, Duration::from_secs(5)).is_some());
+    assert!(closed(&mut stream, Duration::from_secs(5)));
+}
+
+#[test]
+fn clients_beyond_the_limit_wait_for_a_free_connection() {
+    let config = r#"
+[connections]
+max = 1
+"#;
+    let Some(daemon) = TestDaemon::start_with(config) else {
+        return;
+    };
+    let mut first = connect(&daemon);
+    assert!(answer(&mut first, Duration::from_secs(5)).is_some());
+
+    let mut second = connect(&daemon);
+    assert!(answer(&mut second, Duration::from_secs(1)).is_none());
+
+    drop(first);
+    assert!(answer(&mut second, Duration::from_secs(5)).is_some());
+}
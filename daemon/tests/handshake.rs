@@ -0,0 +1,94 @@
+//! Tests for the protocol handshake with clients of other versions. The daemon answers
+//! every handshake with its own so a client it cannot talk to learns why, and talks to
+//! older clients in their version.
+
+mod common;
+
+use common::TestDaemon;
+use shared::{
+    error::SharedError,
+    protocol::{
+        Capabilities, Command, ErrorCode, Handshake, Protocol, Response, Type,
+        DEFAULT_MAX_BODY_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    requests::{PullRequest, PullResponse},
+};
+use std::os::unix::net::UnixStream;
+
+/// Connect as a client speaking only `version` and return the handshake of the daemon.
+/// The daemon drops the connection right after it.
+fn handshake(daemon: &TestDaemon, version: u8) -> Handshake {
+    let mut stream = UnixStream::connect(daemon.socket()).unwrap();
+    let client = Handshake {
+        version,
+        min_version: version,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    Protocol::send(&mut stream, &Protocol::write_handshake(client).unwrap()).unwrap();
+
+    let frame = Protocol::read_frame(&mut stream, DEFAULT_MAX_BODY_SIZE).unwrap();
+    assert_eq!(frame.header._type, Type::Handshake);
+    let remote = frame.decode::<Handshake>().unwrap();
+    match client.negotiate(&remote) {
+        Err(SharedError::IncompatibleProtocol { remote, .. }) => {
+            assert_eq!(remote, PROTOCOL_VERSION)
+        }
+        other => panic!("unexpected negotiation: {:?}", other),
+    }
+    assert!(matches!(
+        Protocol::read_frame(&mut stream, DEFAULT_MAX_BODY_SIZE),
+        Err(SharedError::ConnectionClosed)
+    ));
+    remote
+}
+
+#[test]
+fn clients_of_unsupported_versions_get_the_handshake_of_the_daemon() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+        assert_eq!(handshake(&daemon, version), Handshake::local());
+    }
+
+    // the daemon keeps serving clients it can talk to.
+    assert_eq!(daemon.client().protocol().version, PROTOCOL_VERSION);
+}
+
+#[test]
+fn clients_of_the_oldest_version_are_answered_in_it() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    let mut stream = UnixStream::connect(daemon.socket()).unwrap();
+    let client = Handshake {
+        version: MIN_PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    Protocol::send(&mut stream, &Protocol::write_handshake(client).unwrap()).unwrap();
+    let frame = Protocol::read_frame(&mut stream, DEFAULT_MAX_BODY_SIZE).unwrap();
+    let protocol = client
+        .negotiate(&frame.decode::<Handshake>().unwrap())
+        .unwrap();
+    assert_eq!(protocol.version, MIN_PROTOCOL_VERSION);
+
+    let request = PullRequest {
+        image: "alpine".to_string(),
+    };
+    let body = Protocol::write_body(protocol.version, request).unwrap();
+    let header = Protocol::write_header(
+        protocol.version,
+        Type::Request,
+        Command::Pull,
+        1,
+        body.len() as u64,
+    );
+    Protocol::send(&mut stream, &Protocol::write_message(header, body)).unwrap();
+    let frame = Protocol::read_frame(&mut stream, DEFAULT_MAX_BODY_SIZE).unwrap();
+    assert_eq!(frame.header.version, MIN_PROTOCOL_VERSION);
+    match frame.decode::<Response<PullResponse>>().unwrap() {
+        Response::Error(error) => assert_eq!(error.code, ErrorCode::Unimplemented),
+        Response::Success(_) => panic!("pulled an image"),
+    }
+}
@@ -0,0 +1,135 @@
+//! Tests for the `images` command. Images unpacked into the store are imported into
+//! the index, identified by the digest of their layers, and listed with filters.
+
+mod common;
+
+use common::TestDaemon;
+use shared::requests::{ImageSummary, ImagesRequest};
+use std::fs;
+
+fn images(daemon: &TestDaemon, filters: &[&str]) -> Vec<ImageSummary> {
+    let request = ImagesRequest {
+        filters: filters
+            .iter()
+            .map(|filter| filter.parse().unwrap())
+            .collect(),
+    };
+    daemon.client().images(request).unwrap().images
+}
+
+/// The tags of the listed images, sorted.
+fn tags(images: &[ImageSummary]) -> Vec<String> {
+    let mut tags = images
+        .iter()
+        .flat_map(|image| image.tags.clone())
+        .collect::<Vec<_>>();
+    tags.sort();
+    tags
+}
+
+#[test]
+fn identical_images_share_a_digest() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("first");
+    daemon.image("second");
+    fs::write(daemon.layer("layered", "0").join("file"), "bottom").unwrap();
+    fs::write(daemon.layer("layered", "1").join("file"), "top").unwrap();
+
+    let listed = images(&daemon, &[]);
+    assert_eq!(
+        tags(&listed),
+        [
+            "docker.io/library/first:latest",
+            "docker.io/library/layered:latest",
+            "docker.io/library/second:latest"
+        ]
+    );
+    let digest = |tag: &str| {
+        let image = listed.iter().find(|image| image.tags[0] == tag).unwrap();
+        image.digest.clone()
+    };
+    assert_eq!(
+        digest("docker.io/library/first:latest"),
+        digest("docker.io/library/second:latest")
+    );
+    assert_ne!(
+        digest("docker.io/library/first:latest"),
+        digest("docker.io/library/layered:latest")
+    );
+    assert!(digest("docker.io/library/first:latest").starts_with("sha256:"));
+
+    let layered = listed
+        .iter()
+        .find(|image| image.tags[0] == "docker.io/library/layered:latest")
+        .unwrap();
+    assert_eq!(layered.layers.len(), 2);
+    assert_eq!(layered.size, "bottom".len() as u64 + "top".len() as u64);
+
+    // deleted images are dropped from the index.
+    fs::remove_dir_all(daemon.dir.path().join("images/second")).unwrap();
+    let listed = images(&daemon, &[]);
+    assert_eq!(
+        tags(&listed),
+        [
+            "docker.io/library/first:latest",
+            "docker.io/library/layered:latest"
+        ]
+    );
+    assert!(daemon.dir.path().join("images/index.json").exists());
+}
+
+#[test]
+fn images_are_filtered() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    fs::write(daemon.layer("library/alpine", "0").join("os"), "alpine").unwrap();
+    fs::write(daemon.layer("library/debian", "0").join("os"), "debian").unwrap();
+    fs::write(daemon.layer("team/tools", "0").join("os"), "tools").unwrap();
+    fs::write(
+        daemon.dir.path().join("images/team/tools/config.json"),
+        r#"{"labels": {"team": "build", "tier": "ci"}}"#,
+    )
+    .unwrap();
+
+    assert_eq!(tags(&images(&daemon, &["reference=library/*"])).len(), 2);
+    assert_eq!(
+        tags(&images(&daemon, &["reference=*alp*"])),
+        ["docker.io/library/alpine:latest"]
+    );
+    assert_eq!(
+        tags(&images(&daemon, &["reference=alpine:latest"])),
+        ["docker.io/library/alpine:latest"]
+    );
+    assert_eq!(
+        tags(&images(&daemon, &["reference=library/debian:latest"])),
+        ["docker.io/library/debian:latest"]
+    );
+    assert_eq!(
+        tags(&images(
+            &daemon,
+            &["reference=*alpine", "reference=team/too?s"]
+        )),
+        [
+            "docker.io/library/alpine:latest",
+            "docker.io/team/tools:latest"
+        ]
+    );
+
+    assert_eq!(
+        tags(&images(&daemon, &["label=team"])),
+        ["docker.io/team/tools:latest"]
+    );
+    assert_eq!(
+        tags(&images(&daemon, &["label=team=build"])),
+        ["docker.io/team/tools:latest"]
+    );
+    assert!(images(&daemon, &["label=team=test"]).is_empty());
+    // filters of different kinds must all match.
+    assert!(images(&daemon, &["label=team", "reference=library/*"]).is_empty());
+
+    assert_eq!(images(&daemon, &["dangling=false"]).len(), 3);
+    assert!(images(&daemon, &["dangling=true"]).is_empty());
+}
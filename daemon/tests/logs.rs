@@ -0,0 +1,242 @@
+//! Tests for the `logs` command. The output of containers is captured line by line
+//! into their log, which can be read back, bounded in time, and followed.
+
+mod common;
+
+use common::TestDaemon;
+use shared::{
+    client::Event,
+    protocol::Output,
+    requests::{LogsRequest, RmRequest, RunRequest},
+};
+use std::fs;
+
+fn request(container: &str) -> LogsRequest {
+    LogsRequest {
+        container: container.to_string(),
+        ..Default::default()
+    }
+}
+
+/// The lines of a log and the streams they were written to.
+fn logs(daemon: &TestDaemon, request: LogsRequest) -> Vec<(Output, String)> {
+    let mut lines = Vec::new();
+    daemon
+        .client()
+        .logs(request)
+        .unwrap()
+        .wait(|event| {
+            if let Event::Output(output, data) = event {
+                lines.push((output, String::from_utf8(data).unwrap()));
+            }
+        })
+        .unwrap();
+    lines
+}
+
+/// Run `script`, wait for its whole output to be captured and return the id.
+fn run(daemon: &TestDaemon, script: &str, lines: usize) -> String {
+    let id = daemon.run_script("test", script);
+    common::wait_until(|| logs(daemon, request(&id)).len() == lines);
+    id
+}
+
+#[test]
+fn output_is_captured_per_stream() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = run(&daemon, "echo out; echo err >&2; printf last", 3);
+    // the streams are read apart, only the lines of each one keep their order.
+    let lines = logs(&daemon, request(&id));
+    let stream = |output: Output| {
+        lines
+            .iter()
+            .filter(|(written, _)| *written == output)
+            .map(|(_, line)| line.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(stream(Output::Stdout), ["out\n", "last"]);
+    assert_eq!(stream(Output::Stderr), ["err\n"]);
+
+    // the log goes with the container.
+    let log = daemon
+        .dir
+        .path()
+        .join("containers")
+        .join(&id)
+        .join("container.log");
+    assert!(log.exists());
+    let request = RmRequest {
+        containers: vec![id],
+        ..Default::default()
+    };
+    daemon.client().rm(request).unwrap();
+    assert!(!log.exists());
+}
+
+#[test]
+fn logs_are_tailed_and_bounded_in_time() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let id = run(&daemon, "for line in 1 2 3 4 5; do echo $line; done", 5);
+
+    let tail = logs(
+        &daemon,
+        LogsRequest {
+            tail: Some(2),
+            ..request(&id)
+        },
+    );
+    assert_eq!(
+        tail,
+        [
+            (Output::Stdout, "4\n".to_string()),
+            (Output::Stdout, "5\n".to_string())
+        ]
+    );
+    let none = logs(
+        &daemon,
+        LogsRequest {
+            tail: Some(0),
+            ..request(&id)
+        },
+    );
+    assert!(none.is_empty());
+
+    let timestamped = logs(
+        &daemon,
+        LogsRequest {
+            timestamps: true,
+            tail: Some(1),
+            ..request(&id)
+        },
+    );
+    let (time, line) = timestamped[0].1.split_once(' ').unwrap();
+    assert_eq!(line, "5\n");
+    assert!(time.ends_with('Z') && time.contains('T'), "{}", time);
+
+    // every line was written within the last hour.
+    let since = |since: &str| {
+        logs(
+            &daemon,
+            LogsRequest {
+                since: Some(since.to_string()),
+                ..request(&id)
+            },
+        )
+        .len()
+    };
+    assert_eq!(since("1h"), 5);
+    assert_eq!(since("2000-01-01T00:00:00Z"), 5);
+    assert_eq!(since("4102444800"), 0);
+    let until = logs(
+        &daemon,
+        LogsRequest {
+            until: Some("1h".to_string()),
+            ..request(&id)
+        },
+    );
+    assert!(until.is_empty());
+
+    let error = daemon
+        .client()
+        .logs(LogsRequest {
+            since: Some("yesterday".to_string()),
+            ..request(&id)
+        })
+        .unwrap()
+        .finish()
+        .unwrap_err();
+    assert!(error.to_string().contains("is not a time"), "{}", error);
+}
+
+#[test]
+fn followed_logs_end_when_the_container_exits() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "echo first; while [ ! -e /stop ]; do sleep 0.1; done; echo second >&2".to_string(),
+        ],
+        name: Some("chatty".to_string()),
+        ..Default::default()
+    };
+    let id = daemon.client().run(request).unwrap().id;
+
+    let client = daemon.client();
+    let mut stream = client
+        .logs(LogsRequest {
+            follow: true,
+            ..self::request("chatty")
+        })
+        .unwrap();
+    let mut lines = Vec::new();
+    while let Some(event) = stream.next_event().unwrap() {
+        let Event::Output(output, data) = event else {
+            panic!("unexpected event {:?}", event);
+        };
+        lines.push((output, String::from_utf8(data).unwrap()));
+        if lines.len() == 1 {
+            fs::write(daemon.rootfs(&id).join("stop"), "").unwrap();
+        }
+    }
+    stream.finish().unwrap();
+    assert_eq!(
+        lines,
+        [
+            (Output::Stdout, "first\n".to_string()),
+            (Output::Stderr, "second\n".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn output_is_captured_across_restarts() {
+    let Some(mut daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let script =
+        "for i in 1 2 3; do while [ ! -e /go$i ]; do :; done; echo line $i; : > /wrote$i; done";
+    let run = RunRequest {
+        image: "test".to_string(),
+        command: vec!["/bin/sh".to_string(), "-c".to_string(), script.to_string()],
+        ..Default::default()
+    };
+    let id = daemon.client().run(run).unwrap().id;
+    let rootfs = daemon.rootfs(&id);
+    fs::write(rootfs.join("go1"), "").unwrap();
+    common::wait_until(|| logs(&daemon, request(&id)).len() == 1);
+
+    // the container keeps writing while no daemon reads its output.
+    daemon.shutdown();
+    fs::write(rootfs.join("go2"), "").unwrap();
+    common::wait_until(|| rootfs.join("wrote2").exists());
+    daemon.restart();
+
+    fs::write(rootfs.join("go3"), "").unwrap();
+    let follow = LogsRequest {
+        follow: true,
+        ..request(&id)
+    };
+    assert_eq!(
+        logs(&daemon, follow),
+        [
+            (Output::Stdout, "line 1\n".to_string()),
+            (Output::Stdout, "line 2\n".to_string()),
+            (Output::Stdout, "line 3\n".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,141 @@
+//! Tests for the `ps` command and the container state behind it, which survives
+//! restarts of the daemon.
+
+mod common;
+
+use common::TestDaemon;
+use shared::requests::{Filter, PsRequest, SortKey, StopRequest};
+use std::collections::BTreeMap;
+
+/// The names of the containers listed by `request`.
+fn names(daemon: &TestDaemon, request: PsRequest) -> Vec<String> {
+    let response = daemon.client().ps(request).unwrap();
+    response
+        .containers
+        .into_iter()
+        .map(|container| container.name)
+        .collect()
+}
+
+fn filtered(filters: &[&str]) -> PsRequest {
+    PsRequest {
+        all: true,
+        filters: filters
+            .iter()
+            .map(|filter| filter.parse().unwrap())
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Wait until container `id` has exited.
+fn wait_exited(daemon: &TestDaemon, id: &str) {
+    common::wait_until(|| {
+        let response = daemon.client().ps(filtered(&["status=exited"])).unwrap();
+        response
+            .containers
+            .iter()
+            .any(|container| container.id == id)
+    });
+}
+
+#[test]
+fn containers_are_listed_and_filtered() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let exited = daemon.run_script("test", "true");
+    wait_exited(&daemon, &exited);
+    let web = daemon.start_container("web", &[("tier", "front")], "");
+    daemon.start_container("db", &[("tier", "back"), ("backup", "")], "");
+
+    // only running containers are listed by default, newest first.
+    assert_eq!(names(&daemon, PsRequest::default()), ["db", "web"]);
+    assert_eq!(names(&daemon, filtered(&[])).len(), 3);
+
+    assert_eq!(names(&daemon, filtered(&["label=tier=front"])), ["web"]);
+    assert_eq!(names(&daemon, filtered(&["label=backup"])), ["db"]);
+    assert_eq!(
+        names(&daemon, filtered(&["name=web", "name=db"])),
+        ["db", "web"]
+    );
+    assert_eq!(
+        names(&daemon, filtered(&["name=web", "label=backup"])).len(),
+        0
+    );
+    assert_eq!(names(&daemon, filtered(&["image=other"])).len(), 0);
+    let response = daemon.client().ps(filtered(&["status=exited"])).unwrap();
+    assert_eq!(response.containers.len(), 1);
+    assert_eq!(response.containers[0].id, exited);
+    assert_eq!(response.containers[0].exit_code, Some(0));
+    assert!(response.containers[0].finished.is_some());
+
+    let request = PsRequest {
+        sort: Some(SortKey::Name),
+        ..Default::default()
+    };
+    assert_eq!(names(&daemon, request), ["db", "web"]);
+    let request = PsRequest {
+        sort: Some(SortKey::Created),
+        reverse: true,
+        ..Default::default()
+    };
+    assert_eq!(names(&daemon, request), ["db", "web"]);
+
+    let response = daemon.client().ps(filtered(&["name=web"])).unwrap();
+    let container = &response.containers[0];
+    assert_eq!(container.id, web);
+    assert_eq!(
+        (container.status.as_str(), container.exit_code),
+        ("running", None)
+    );
+    assert!(container.pid.is_some() && container.started.is_some());
+    assert_eq!(
+        container.labels,
+        BTreeMap::from([("tier".to_string(), "front".to_string())])
+    );
+}
+
+#[test]
+fn invalid_filters_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+
+    let request = PsRequest {
+        filters: vec![Filter::Status("sleeping".to_string())],
+        ..Default::default()
+    };
+    let error = daemon.client().ps(request).unwrap_err();
+    assert!(error.to_string().contains("unknown status"), "{}", error);
+}
+
+#[test]
+fn containers_survive_restarts() {
+    let Some(mut daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let exited = daemon.run_script("test", "true");
+    wait_exited(&daemon, &exited);
+    let running = daemon.start_container("busy", &[("keep", "yes")], "");
+    let before = daemon.client().ps(filtered(&[])).unwrap();
+
+    daemon.restart();
+    let after = daemon.client().ps(filtered(&[])).unwrap();
+    assert_eq!(format!("{:?}", after), format!("{:?}", before));
+
+    // the container outlived the daemon and can still be stopped.
+    let request = StopRequest {
+        container: running.clone(),
+        signal: None,
+        timeout: Some(0),
+    };
+    let response = daemon.client().stop(request).unwrap();
+    assert!(response.killed);
+    wait_exited(&daemon, &running);
+    assert_eq!(names(&daemon, PsRequest::default()).len(), 0);
+}
@@ -0,0 +1,206 @@
+//! Tests for the `rm` command. Removing a container tears down its root filesystem,
+//! its cgroup and its directory, and reports a result for every container.
+
+mod common;
+
+use common::{cgroup_root, TestDaemon};
+use shared::requests::{RmRequest, RmResponse, RunRequest};
+use std::{fs, path::PathBuf};
+
+fn rm(daemon: &TestDaemon, containers: &[&str], force: bool) -> RmResponse {
+    let request = RmRequest {
+        containers: containers.iter().map(|id| id.to_string()).collect(),
+        force,
+        ..Default::default()
+    };
+    daemon.client().rm(request).unwrap()
+}
+
+/// The directories of the volumes of every container.
+fn volumes(daemon: &TestDaemon) -> Vec<PathBuf> {
+    match fs::read_dir(daemon.dir.path().join("volumes")) {
+        Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Whether anything of container `id` is left on the host.
+fn leftovers(daemon: &TestDaemon, id: &str) -> Vec<String> {
+    let mut leftovers = fs::read_dir(daemon.dir.path().join("containers"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap();
+    leftovers.extend(
+        mountinfo
+            .lines()
+            .filter(|line| line.contains(id))
+            .map(str::to_string),
+    );
+    if let Some(root) = cgroup_root() {
+        if root.join(daemon.cgroup(id)).exists() {
+            leftovers.push(daemon.cgroup(id).display().to_string());
+        }
+    }
+    leftovers
+}
+
+#[test]
+fn exited_containers_are_removed() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script("test", "true");
+    // the script may still be exiting.
+    common::wait_until(|| rm(&daemon, &[&id], false).results[0].id.is_some());
+    assert_eq!(leftovers(&daemon, &id), Vec::<String>::new());
+}
+
+#[test]
+fn running_containers_are_only_removed_by_force() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.start_container("busy", &[], "trap 'exit 3' TERM");
+    let started = daemon.rootfs(&id).join("started");
+
+    let response = rm(&daemon, &["busy"], false);
+    let error = response.results[0].error.as_deref().unwrap();
+    assert!(error.contains("is running"), "{}", error);
+    assert!(started.exists());
+
+    let response = rm(&daemon, &["busy"], true);
+    assert_eq!(response.results[0].id.as_deref(), Some(id.as_str()));
+    assert_eq!(leftovers(&daemon, &id), Vec::<String>::new());
+
+    // it was stopped with the stop signal, not killed right away.
+    let log = daemon.log();
+    assert!(log.contains("with SIGTERM"), "{}", log);
+    assert!(!log.contains("killing it"), "{}", log);
+}
+
+#[test]
+fn volumes_are_kept_unless_removed_too() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let run = |name: &str| {
+        let request = RunRequest {
+            image: "test".to_string(),
+            command: vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "echo kept > /data/file; : > /var/db/file; : > /done".to_string(),
+            ],
+            name: Some(name.to_string()),
+            volumes: vec!["/data".to_string(), "/var/db".to_string()],
+            ..Default::default()
+        };
+        let id = daemon.client().run(request).unwrap().id;
+        let done = daemon.rootfs(&id).join("done");
+        common::wait_until(|| done.exists());
+        id
+    };
+
+    let kept = run("kept");
+    // the files are written to the volumes, not to the root filesystem.
+    assert!(!daemon.rootfs(&kept).join("data/file").exists());
+    common::wait_until(|| rm(&daemon, &["kept"], false).results[0].id.is_some());
+    let mut volumes_after = volumes(&daemon);
+    volumes_after.sort();
+    assert_eq!(volumes_after.len(), 2);
+    let data = volumes_after
+        .iter()
+        .filter_map(|volume| fs::read_to_string(volume.join("file")).ok())
+        .find(|content| !content.is_empty());
+    assert_eq!(data.as_deref(), Some("kept\n"));
+
+    run("removed");
+    assert_eq!(volumes(&daemon).len(), 4);
+    common::wait_until(|| {
+        let request = RmRequest {
+            containers: vec!["removed".to_string()],
+            volumes: true,
+            ..Default::default()
+        };
+        daemon.client().rm(request).unwrap().results[0].id.is_some()
+    });
+    let mut remaining = volumes(&daemon);
+    remaining.sort();
+    assert_eq!(remaining, volumes_after);
+}
+
+#[test]
+fn every_container_gets_a_result() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script("test", "true");
+    common::wait_until(|| {
+        let response = rm(&daemon, &["missing", &id], false);
+        let error = response.results[0].error.as_deref().unwrap();
+        assert!(error.contains("No such container"), "{}", error);
+        assert_eq!(response.results[0].container, "missing");
+        response.results[1].id.is_some()
+    });
+    assert_eq!(leftovers(&daemon, &id), Vec::<String>::new());
+}
+
+#[test]
+fn interrupted_removals_are_finished_on_start() {
+    let Some(mut daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.start_container("busy", &[], "");
+
+    // a removal that got as far as renaming the container before the daemon stopped.
+    daemon.shutdown();
+    let containers = daemon.dir.path().join("containers");
+    fs::rename(
+        containers.join(&id),
+        containers.join(format!(".removing-{}", id)),
+    )
+    .unwrap();
+    daemon.restart();
+
+    assert_eq!(leftovers(&daemon, &id), Vec::<String>::new());
+    assert!(daemon
+        .client()
+        .ps(Default::default())
+        .unwrap()
+        .containers
+        .is_empty());
+}
+
+#[test]
+fn volumes_outside_the_container_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    for path in ["data", "/", "/../etc", "/data/../../etc"] {
+        let request = RunRequest {
+            image: "test".to_string(),
+            command: vec!["/bin/sh".to_string()],
+            volumes: vec![path.to_string()],
+            ..Default::default()
+        };
+        let error = daemon.client().run(request).unwrap_err();
+        assert!(
+            error.to_string().contains("not an absolute path"),
+            "{}",
+            error
+        );
+    }
+    assert!(volumes(&daemon).is_empty());
+}
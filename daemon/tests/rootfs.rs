@@ -0,0 +1,118 @@
+//! Tests for the root filesystem of containers. A container only sees its own root
+//! filesystem and the filesystems mounted into it, never the paths of the host.
+
+mod common;
+
+use common::TestDaemon;
+use std::{collections::BTreeSet, fs};
+
+#[test]
+fn host_paths_are_not_visible() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let marker = daemon.dir.path().join("marker");
+    fs::write(&marker, "host").unwrap();
+    assert!(marker.exists());
+
+    let script = format!(
+        "if [ -e {} ]; then echo visible; else echo hidden; fi > /result",
+        marker.display()
+    );
+    let id = daemon.run_script("test", &script);
+    assert_eq!(daemon.read(&id, "/result"), "hidden\n", "{}", daemon.log());
+}
+
+#[test]
+fn root_is_the_container_rootfs() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script("test", "ls / > /result");
+    let entries = daemon
+        .read(&id, "/result")
+        .lines()
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>();
+    for entry in ["bin", "tmp", "proc", "sys", "dev", "result"] {
+        assert!(
+            entries.contains(entry),
+            "{} missing from {:?}",
+            entry,
+            entries
+        );
+    }
+    // the image has no such directories, only the host does.
+    for entry in ["root", "home", "var"] {
+        assert!(
+            !entries.contains(entry),
+            "{} visible in {:?}",
+            entry,
+            entries
+        );
+    }
+}
+
+#[test]
+fn only_container_filesystems_are_mounted() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script("test", "cat /proc/self/mountinfo > /result");
+    let mounts = daemon.read(&id, "/result");
+    let mount_points = mounts
+        .lines()
+        .map(|line| line.split(' ').nth(4).unwrap())
+        .collect::<BTreeSet<_>>();
+    let expected = [
+        "/",
+        "/proc",
+        "/sys",
+        "/dev",
+        "/dev/null",
+        "/dev/zero",
+        "/dev/full",
+        "/dev/random",
+        "/dev/urandom",
+        "/dev/tty",
+        "/dev/pts",
+        "/dev/shm",
+    ];
+    assert_eq!(mount_points, expected.into_iter().collect(), "{}", mounts);
+}
+
+#[test]
+fn container_has_its_own_pid_namespace() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    // the shell running the script is the init of the container.
+    let id = daemon.run_script("test", "cat /proc/1/cmdline > /result");
+    let cmdline = daemon.read(&id, "/result");
+    assert!(cmdline.starts_with("/bin/sh\0-c\0"), "{:?}", cmdline);
+}
+
+#[test]
+fn device_nodes_are_usable() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script(
+        "test",
+        "echo discarded > /dev/null && cat /dev/null > /result && ls /dev > /devices",
+    );
+    assert_eq!(daemon.read(&id, "/result"), "");
+    let devices = daemon.read(&id, "/devices");
+    for device in ["null", "zero", "urandom", "pts", "shm", "ptmx", "fd"] {
+        assert!(devices.lines().any(|line| line == device), "{}", devices);
+    }
+}
@@ -0,0 +1,93 @@
+//! Tests for the shutdown of the daemon. Running containers are either left running
+//! for the next daemon or stopped like the `stop` command does.
+
+mod common;
+
+use common::TestDaemon;
+use shared::requests::{ContainerSummary, PsRequest};
+use std::{fs, path::Path};
+
+fn container(daemon: &TestDaemon, id: &str) -> ContainerSummary {
+    let request = PsRequest {
+        all: true,
+        ..Default::default()
+    };
+    let response = daemon.client().ps(request).unwrap();
+    let container = response
+        .containers
+        .into_iter()
+        .find(|container| container.id == id);
+    container.unwrap()
+}
+
+#[test]
+fn containers_are_left_running_by_default() {
+    let Some(mut daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let id = daemon.start_container("busy", &[], "trap 'exit 3' TERM");
+    let pid = container(&daemon, &id).pid.unwrap();
+
+    daemon.restart();
+    let container = container(&daemon, &id);
+    assert_eq!(container.status, "running");
+    assert_eq!(container.pid, Some(pid));
+}
+
+#[test]
+fn containers_are_stopped_on_shutdown() {
+    let config = r#"
+[shutdown]
+containers = "stop"
+timeout = 1
+"#;
+    let Some(mut daemon) = TestDaemon::start_with(config) else {
+        return;
+    };
+    daemon.image("test");
+    let exits = daemon.start_container("exits", &[], "trap 'exit 3' TERM");
+    // the init of a pid namespace ignores signals it has no handler for.
+    let ignores = daemon.start_container("ignores", &[], "");
+
+    let pids = [&exits, &ignores].map(|id| container(&daemon, id).pid.unwrap());
+
+    daemon.shutdown();
+    for pid in pids {
+        assert!(
+            !Path::new("/proc").join(pid.to_string()).exists(),
+            "{}",
+            pid
+        );
+    }
+
+    daemon.restart();
+    let exits = container(&daemon, &exits);
+    assert_eq!(
+        (exits.status.as_str(), exits.exit_code),
+        ("exited", Some(3))
+    );
+    let ignores = container(&daemon, &ignores);
+    assert_eq!(
+        (ignores.status.as_str(), ignores.exit_code),
+        ("exited", Some(128 + 9))
+    );
+}
+
+#[test]
+fn pid_file_is_cleared_but_kept_on_shutdown() {
+    let Some(mut daemon) = TestDaemon::start() else {
+        return;
+    };
+    let pid_file = daemon.dir.path().join("run/j1407b.pid");
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    assert!(pid.trim().parse::<u32>().is_ok(), "{:?}", pid);
+
+    daemon.shutdown();
+    assert_eq!(fs::read_to_string(&pid_file).unwrap(), "");
+
+    // the next daemon locks the same file.
+    daemon.restart();
+    assert_ne!(fs::read_to_string(&pid_file).unwrap(), pid);
+    assert!(!fs::read_to_string(&pid_file).unwrap().is_empty());
+}
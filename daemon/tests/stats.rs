@@ -0,0 +1,93 @@
+//! Tests for the `stats` command. It reads the counters of the cgroups of containers
+//! once, or streams them until the containers exit.
+
+mod common;
+
+use common::{cgroup_root, TestDaemon};
+use shared::{
+    client::Event,
+    requests::{StatsRequest, StatsResponse},
+};
+use std::fs;
+
+fn stats(daemon: &TestDaemon, containers: &[&str]) -> StatsResponse {
+    let request = StatsRequest {
+        containers: containers.iter().map(|id| id.to_string()).collect(),
+        ..Default::default()
+    };
+    daemon.client().stats(request).unwrap().finish().unwrap()
+}
+
+#[test]
+fn running_containers_are_sampled() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let id = daemon.start_container("busy", &[], "");
+
+    let all = stats(&daemon, &[]);
+    assert_eq!(all.containers.len(), 1);
+    assert_eq!(all.containers[0].id, id);
+    assert_eq!(all.containers[0].name, "busy");
+    // containers are found by name and by id prefix too.
+    assert_eq!(stats(&daemon, &["busy"]).containers[0].id, id);
+    assert_eq!(stats(&daemon, &[&id[..6]]).containers[0].id, id);
+    if cgroup_root().is_some() {
+        assert!(all.containers[0].cpu_usage.unwrap() > 0);
+    }
+
+    // exited containers are only sampled when asked for.
+    fs::write(daemon.rootfs(&id).join("stop"), "").unwrap();
+    common::wait_until(|| stats(&daemon, &[]).containers.is_empty());
+    assert_eq!(stats(&daemon, &[&id]).containers.len(), 1);
+}
+
+#[test]
+fn streams_end_when_the_containers_exit() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let id = daemon.start_container("busy", &[], "");
+
+    let client = daemon.client();
+    let request = StatsRequest {
+        containers: vec![id.clone()],
+        stream: true,
+        interval: Some(50),
+    };
+    let mut stream = client.stats(request).unwrap();
+    let mut samples = 0;
+    while let Some(event) = stream.next_event().unwrap() {
+        let Event::Stats(sample) = event else {
+            panic!("unexpected event {:?}", event);
+        };
+        assert_eq!(sample.containers[0].id, id);
+        samples += 1;
+        if samples == 2 {
+            fs::write(daemon.rootfs(&id).join("stop"), "").unwrap();
+        }
+    }
+    assert!(samples >= 2);
+    assert_eq!(stream.finish().unwrap().containers[0].id, id);
+}
+
+#[test]
+fn unknown_containers_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+
+    let request = StatsRequest {
+        containers: vec!["missing".to_string()],
+        ..Default::default()
+    };
+    let error = daemon
+        .client()
+        .stats(request)
+        .unwrap()
+        .finish()
+        .unwrap_err();
+    assert!(error.to_string().contains("No such container"), "{}", error);
+}
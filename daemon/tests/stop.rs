@@ -0,0 +1,65 @@
+//! Tests for the `stop` command. The init of a container gets the stop signal and is
+//! killed along with the rest of the container once the timeout has passed.
+
+mod common;
+
+use common::TestDaemon;
+use shared::requests::StopRequest;
+
+fn stop(container: &str, signal: Option<&str>, timeout: u64) -> StopRequest {
+    StopRequest {
+        container: container.to_string(),
+        signal: signal.map(str::to_string),
+        timeout: Some(timeout),
+    }
+}
+
+#[test]
+fn containers_exit_on_the_stop_signal() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.start_container("exits", &[], "trap 'exit 3' TERM");
+    let response = daemon.client().stop(stop(&id, None, 10)).unwrap();
+    assert_eq!((response.id.as_str(), response.code), (id.as_str(), 3));
+    assert!(!response.killed);
+
+    let id = daemon.start_container("interrupted", &[], "trap 'exit 4' INT");
+    let response = daemon.client().stop(stop(&id, Some("int"), 10)).unwrap();
+    assert_eq!(response.code, 4);
+
+    // stopping it again answers with the same exit code.
+    let response = daemon.client().stop(stop(&id, None, 10)).unwrap();
+    assert_eq!(response.code, 4);
+}
+
+#[test]
+fn containers_ignoring_the_signal_are_killed() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    // the init of a pid namespace ignores signals it has no handler for.
+    let id = daemon.start_container("ignores", &[], "");
+    let response = daemon.client().stop(stop(&id, None, 1)).unwrap();
+    assert_eq!(response.code, 128 + 9);
+    assert!(response.killed);
+}
+
+#[test]
+fn invalid_signals_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.start_container("busy", &[], "");
+    let error = daemon
+        .client()
+        .stop(stop(&id, Some("SIGNOPE"), 1))
+        .unwrap_err();
+    assert!(error.to_string().contains("Invalid signal"), "{}", error);
+}
@@ -0,0 +1,100 @@
+//! Tests for the storage drivers. Containers see the layers of their image stacked
+//! on top of each other and never modify them.
+
+mod common;
+
+use common::{base_layer, TestDaemon};
+use nix::sys::stat::{mknod, Mode, SFlag};
+use std::{ffi::CString, fs, os::unix::ffi::OsStrExt, path::Path};
+
+const DRIVERS: [&str; 2] = ["overlay", "copy"];
+
+/// Start a daemon using `driver`.
+fn start(driver: &str) -> Option<TestDaemon> {
+    TestDaemon::start_with(&format!("[storage]\ndriver = \"{}\"", driver))
+}
+
+/// An image of two layers: the second one changes a file of the first one, deletes
+/// another one with a whiteout and hides a directory with an opaque directory.
+fn layered_image(daemon: &TestDaemon) {
+    let base = daemon.layer("layered", "0");
+    base_layer(&base);
+    fs::create_dir_all(base.join("etc")).unwrap();
+    fs::write(base.join("etc/changed"), "base\n").unwrap();
+    fs::write(base.join("etc/deleted"), "base\n").unwrap();
+    fs::create_dir_all(base.join("opaque")).unwrap();
+    fs::write(base.join("opaque/hidden"), "base\n").unwrap();
+
+    let top = daemon.layer("layered", "1");
+    fs::create_dir_all(top.join("etc")).unwrap();
+    fs::write(top.join("etc/changed"), "top\n").unwrap();
+    mknod(&top.join("etc/deleted"), SFlag::S_IFCHR, Mode::empty(), 0).unwrap();
+    fs::create_dir_all(top.join("opaque")).unwrap();
+    fs::write(top.join("opaque/visible"), "top\n").unwrap();
+    set_opaque(&top.join("opaque"));
+}
+
+fn set_opaque(dir: &Path) {
+    let path = CString::new(dir.as_os_str().as_bytes()).unwrap();
+    let name = CString::new("trusted.overlay.opaque").unwrap();
+    let result =
+        unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+    assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+}
+
+#[test]
+fn layers_are_stacked() {
+    for driver in DRIVERS {
+        let Some(daemon) = start(driver) else {
+            return;
+        };
+        layered_image(&daemon);
+
+        let id = daemon.run_script(
+            "layered",
+            "ls /etc /opaque > /result; cat /etc/changed >> /result",
+        );
+        assert_eq!(
+            daemon.read(&id, "/result"),
+            "/etc:\nchanged\n\n/opaque:\nvisible\ntop\n",
+            "{}: {}",
+            driver,
+            daemon.log()
+        );
+    }
+}
+
+#[test]
+fn image_layers_are_not_modified() {
+    for driver in DRIVERS {
+        let Some(daemon) = start(driver) else {
+            return;
+        };
+        layered_image(&daemon);
+
+        let id = daemon.run_script(
+            "layered",
+            "echo container > /etc/changed && echo new > /etc/new && rm /bin/ls",
+        );
+        assert_eq!(
+            daemon.read(&id, "/etc/changed"),
+            "container\n",
+            "{}",
+            driver
+        );
+        assert_eq!(daemon.read(&id, "/etc/new"), "new\n", "{}", driver);
+
+        let base = daemon.layer("layered", "0");
+        let top = daemon.layer("layered", "1");
+        assert_eq!(
+            fs::read_to_string(top.join("etc/changed")).unwrap(),
+            "top\n"
+        );
+        assert!(!top.join("etc/new").exists() && !base.join("etc/new").exists());
+        assert!(base.join("bin/ls").exists(), "{}", driver);
+
+        // a second container starts from the unmodified image.
+        let id = daemon.run_script("layered", "cat /etc/changed > /result");
+        assert_eq!(daemon.read(&id, "/result"), "top\n", "{}", driver);
+    }
+}
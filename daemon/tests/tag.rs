@@ -0,0 +1,142 @@
+//! Tests for the `tag` and `untag` commands. Tags are normalized references that name
+//! a single image, and containers can be run from any of them.
+
+mod common;
+
+use common::TestDaemon;
+use shared::requests::{ImageSummary, ImagesRequest, TagRequest, UntagRequest};
+use std::fs;
+
+fn tag(daemon: &TestDaemon, source: &str, target: &str) -> String {
+    let request = TagRequest {
+        source: source.to_string(),
+        target: target.to_string(),
+    };
+    daemon.client().tag(request).unwrap().reference
+}
+
+/// The image tagged `reference`.
+fn image(daemon: &TestDaemon, reference: &str) -> ImageSummary {
+    let request = ImagesRequest {
+        filters: vec![format!("reference={}", reference).parse().unwrap()],
+    };
+    let mut images = daemon.client().images(request).unwrap().images;
+    assert_eq!(images.len(), 1, "{:?}", images);
+    images.remove(0)
+}
+
+#[test]
+fn images_are_tagged_by_reference_digest_and_id() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    let digest = image(&daemon, "test").digest;
+
+    assert_eq!(
+        tag(&daemon, "test", "registry.local:5000/team/app:1.2"),
+        "registry.local:5000/team/app:1.2"
+    );
+    assert_eq!(tag(&daemon, &digest, "app"), "docker.io/library/app:latest");
+    assert_eq!(
+        tag(
+            &daemon,
+            &digest["sha256:".len()..][..12],
+            "docker.io/team/app:rc"
+        ),
+        "docker.io/team/app:rc"
+    );
+    assert_eq!(
+        image(&daemon, "test").tags,
+        [
+            "docker.io/library/app:latest",
+            "docker.io/library/test:latest",
+            "docker.io/team/app:rc",
+            "registry.local:5000/team/app:1.2",
+        ]
+    );
+
+    // containers are created from any reference of the image.
+    let id = daemon.run_script("registry.local:5000/team/app:1.2", "true");
+    assert!(!id.is_empty());
+    let id = daemon.run_script(&format!("app@{}", digest), "true");
+    assert!(!id.is_empty());
+}
+
+#[test]
+fn tags_move_between_images() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    fs::write(daemon.layer("first", "0").join("file"), "first").unwrap();
+    fs::write(daemon.layer("second", "0").join("file"), "second").unwrap();
+
+    tag(&daemon, "first", "release");
+    tag(&daemon, "second", "release");
+    assert_eq!(
+        image(&daemon, "second").tags,
+        [
+            "docker.io/library/release:latest",
+            "docker.io/library/second:latest"
+        ]
+    );
+    assert_eq!(
+        image(&daemon, "first").tags,
+        ["docker.io/library/first:latest"]
+    );
+
+    // an image without tags is dangling but kept.
+    let response = daemon
+        .client()
+        .untag(UntagRequest {
+            reference: "first".to_string(),
+        })
+        .unwrap();
+    assert_eq!(response.reference, "docker.io/library/first:latest");
+    let request = ImagesRequest {
+        filters: vec!["dangling=true".parse().unwrap()],
+    };
+    let dangling = daemon.client().images(request).unwrap().images;
+    assert_eq!(dangling.len(), 1);
+    assert_eq!(dangling[0].digest, response.digest);
+
+    // it can still be tagged by its id.
+    tag(
+        &daemon,
+        &response.digest["sha256:".len()..][..8],
+        "first:again",
+    );
+    assert_eq!(
+        image(&daemon, "first").tags,
+        ["docker.io/library/first:again"]
+    );
+}
+
+#[test]
+fn invalid_references_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    for target in ["Upper", "app:-tag", "app@sha256:1234", "app:1@sha256:12"] {
+        let request = TagRequest {
+            source: "test".to_string(),
+            target: target.to_string(),
+        };
+        let error = daemon.client().tag(request).unwrap_err();
+        assert!(error.to_string().contains("Invalid reference"), "{}", error);
+    }
+    let request = TagRequest {
+        source: "missing".to_string(),
+        target: "app".to_string(),
+    };
+    let error = daemon.client().tag(request).unwrap_err();
+    assert!(error.to_string().contains("not found"), "{}", error);
+
+    let request = UntagRequest {
+        reference: "missing".to_string(),
+    };
+    let error = daemon.client().untag(request).unwrap_err();
+    assert!(error.to_string().contains("not found"), "{}", error);
+}
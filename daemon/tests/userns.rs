@@ -0,0 +1,119 @@
+//! Tests for the user namespace of containers. Root in a container is the user
+//! running the daemon unless the run request maps other ids.
+
+mod common;
+
+use common::TestDaemon;
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use shared::requests::{IdMapping, RunRequest};
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+/// Change the owner of a tree, as an image unpacked for a mapped container would be.
+fn chown_tree(path: &Path, id: u32) {
+    fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(id)),
+        Some(Gid::from_raw(id)),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .unwrap();
+    if fs::symlink_metadata(path).unwrap().is_dir() {
+        for entry in fs::read_dir(path).unwrap() {
+            chown_tree(&entry.unwrap().path(), id);
+        }
+    }
+}
+
+/// The lines of an id map, with the columns separated by single spaces.
+fn id_map(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect()
+}
+
+#[test]
+fn root_is_the_daemon_user_by_default() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let id = daemon.run_script(
+        "test",
+        "cat /proc/self/uid_map > /uid_map; cat /proc/self/gid_map > /gid_map",
+    );
+    assert_eq!(id_map(&daemon.read(&id, "/uid_map"))[0], "0 0 1");
+    assert_eq!(id_map(&daemon.read(&id, "/gid_map"))[0], "0 0 1");
+}
+
+#[test]
+fn requested_ids_are_mapped() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+    // the mapped root of the container has to reach its root filesystem.
+    fs::set_permissions(daemon.dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    chown_tree(&daemon.dir.path().join("images/test/rootfs"), 100000);
+
+    let mapping = |container_id, host_id, size| IdMapping {
+        container_id,
+        host_id,
+        size,
+    };
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "cat /proc/self/uid_map > /uid_map; cat /proc/self/gid_map > /gid_map; : > /done"
+                .to_string(),
+        ],
+        uid_map: vec![mapping(0, 100000, 65536)],
+        gid_map: vec![mapping(0, 100000, 1000), mapping(1000, 200000, 10)],
+        ..Default::default()
+    };
+    let id = daemon.client().run(request).unwrap().id;
+    let done = daemon.rootfs(&id).join("done");
+    common::wait_until(|| done.exists());
+
+    assert_eq!(id_map(&daemon.read(&id, "/uid_map")), ["0 100000 65536"]);
+    assert_eq!(
+        id_map(&daemon.read(&id, "/gid_map")),
+        ["0 100000 1000", "1000 200000 10"]
+    );
+    // files created by root in the container belong to the mapped host user.
+    let metadata = fs::metadata(&done).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (100000, 100000));
+}
+
+#[test]
+fn invalid_mappings_are_rejected() {
+    let Some(daemon) = TestDaemon::start() else {
+        return;
+    };
+    daemon.image("test");
+
+    let request = RunRequest {
+        image: "test".to_string(),
+        command: vec!["/bin/sh".to_string()],
+        uid_map: vec![IdMapping {
+            container_id: 0,
+            host_id: u32::MAX,
+            size: 2,
+        }],
+        ..Default::default()
+    };
+    let error = daemon.client().run(request).unwrap_err();
+    assert!(
+        error.to_string().contains("invalid uid mapping"),
+        "{}",
+        error
+    );
+}
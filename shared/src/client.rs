@@ -0,0 +1,212 @@
+//! A typed client for the daemon. It wraps a `Dispatcher` so a single connection
+//! can be shared by many threads, and turns the frames answering a request into
+//! typed responses, streamed events and `ClientError`s.
+//!
+//! # Example
+//! ```rust,no_run
+//! use shared::{client::Client, requests::PsRequest, utils};
+//!
+//! let client = Client::connect(utils::default_socket_path()).unwrap();
+//!
+//! // commands that answer with a single response.
+//! let response = client.ps(PsRequest::default()).unwrap();
+//! for container in response.containers {
+//!     println!("{} {}", container.id, container.status);
+//! }
+//!
+//! // commands that stream output or progress before their response.
+//! let pull = client.pull("alpine").unwrap();
+//! let response = pull.wait(|event| println!("{:?}", event)).unwrap();
+//! println!("pulled {}", response.image);
+//! ```
+
+use crate::{
+    dispatcher::{Call, Dispatcher},
+    error::{ClientError, SharedError},
+    protocol::{Command, Handshake, Output, Progress, Response, Type},
+    requests::{
+        ImagesRequest, ImagesResponse, LogLevelRequest, LogLevelResponse, LogsRequest, PsRequest,
+        PsResponse, PullRequest, PullResponse, RmRequest, RmResponse, RunRequest, RunResponse,
+        StatsRequest, StatsResponse, StopRequest, StopResponse, TagRequest, TagResponse,
+        UntagRequest, UntagResponse,
+    },
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{marker::PhantomData, os::unix::net::UnixStream, path::Path};
+
+/// A connection to the daemon.
+pub struct Client {
+    dispatcher: Dispatcher,
+}
+
+impl Client {
+    /// Connect to the daemon listening at `path` and negotiate the protocol.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, ClientError> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path).map_err(|e| ClientError::Connect {
+            path: path.display().to_string(),
+            source: e,
+        })?;
+        Self::from_stream(stream)
+    }
+
+    /// Negotiate the protocol over an already connected stream.
+    pub fn from_stream(stream: UnixStream) -> Result<Self, ClientError> {
+        Ok(Client {
+            dispatcher: Dispatcher::new(stream)?,
+        })
+    }
+
+    /// The negotiated protocol version and capabilities.
+    pub fn protocol(&self) -> Handshake {
+        self.dispatcher.protocol()
+    }
+
+    /// `pull`: fetch an image from the registry. Streams download progress.
+    pub fn pull(&self, image: &str) -> Result<Stream<PullResponse>, ClientError> {
+        let request = PullRequest {
+            image: image.to_string(),
+        };
+        self.request(Command::Pull, request)
+    }
+
+    /// `run`: create a container and start it. Answers once the container is running.
+    pub fn run(&self, request: RunRequest) -> Result<RunResponse, ClientError> {
+        self.request(Command::Run, request)?.finish()
+    }
+
+    /// `stop`: stop a container. Answers once it has exited.
+    pub fn stop(&self, request: StopRequest) -> Result<StopResponse, ClientError> {
+        self.request(Command::Stop, request)?.finish()
+    }
+
+    /// `rm`: remove containers. Answers with a result for every container, the
+    /// request fails as a whole only if it is invalid.
+    pub fn rm(&self, request: RmRequest) -> Result<RmResponse, ClientError> {
+        self.request(Command::Rm, request)?.finish()
+    }
+
+    /// `ps`: list containers.
+    pub fn ps(&self, request: PsRequest) -> Result<PsResponse, ClientError> {
+        self.request(Command::Ps, request)?.finish()
+    }
+
+    /// `images`: list the images of the store.
+    pub fn images(&self, request: ImagesRequest) -> Result<ImagesResponse, ClientError> {
+        self.request(Command::Images, request)?.finish()
+    }
+
+    /// `tag`: tag an image with another reference.
+    pub fn tag(&self, request: TagRequest) -> Result<TagResponse, ClientError> {
+        self.request(Command::Tag, request)?.finish()
+    }
+
+    /// `untag`: remove a tag from an image.
+    pub fn untag(&self, request: UntagRequest) -> Result<UntagResponse, ClientError> {
+        self.request(Command::Untag, request)?.finish()
+    }
+
+    /// `logs`: fetch the output of a container. Streams the log lines as chunks of the
+    /// output they were written to.
+    pub fn logs(&self, request: LogsRequest) -> Result<Stream<()>, ClientError> {
+        self.request(Command::Logs, request)
+    }
+
+    /// `stats`: read the resource usage of containers. With `stream` every sample but
+    /// the last one is streamed as an `Event::Stats`.
+    pub fn stats(&self, request: StatsRequest) -> Result<Stream<StatsResponse>, ClientError> {
+        self.request(Command::Stats, request)
+    }
+
+    /// `log-level`: read the log level of the daemon, or change it if `level` is given.
+    pub fn log_level(&self, level: Option<&str>) -> Result<LogLevelResponse, ClientError> {
+        let request = LogLevelRequest {
+            level: level.map(str::to_string),
+        };
+        self.request(Command::LogLevel, request)?.finish()
+    }
+
+    /// Send a request for any command. The returned stream yields the events the
+    /// daemon streams back and finally the `T` response.
+    pub fn request<B: Serialize, T: DeserializeOwned>(
+        &self,
+        command: Command,
+        body: B,
+    ) -> Result<Stream<T>, ClientError> {
+        let call = self.dispatcher.request::<B>(command, body)?;
+        Ok(Stream {
+            call,
+            result: None,
+            response: PhantomData,
+        })
+    }
+}
+
+/// Something streamed by the daemon before the final response.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Output(Output, Vec<u8>),
+    Progress(Progress),
+    Stats(StatsResponse),
+}
+
+/// The answer to a request in flight: any number of `Event`s followed by the `T` response.
+pub struct Stream<T> {
+    call: Call,
+    result: Option<Result<T, ClientError>>,
+    response: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Stream<T> {
+    /// Wait for the next event. Returns `None` once the final response has arrived.
+    pub fn next_event(&mut self) -> Result<Option<Event>, ClientError> {
+        if self.result.is_some() {
+            return Ok(None);
+        }
+        let frame = self.call.recv()?;
+        if frame.header.command != self.call.command() {
+            return Err(SharedError::UnexpectedMessage {
+                _type: frame.header._type,
+                command: frame.header.command,
+            })?;
+        }
+        match frame.header._type {
+            Type::Response | Type::End => {
+                let result = match frame.decode::<Response<T>>()? {
+                    Response::Success(payload) => Ok(payload),
+                    Response::Error(error) => Err(ClientError::Daemon(error)),
+                };
+                self.result = Some(result);
+                Ok(None)
+            }
+            Type::Chunk => Ok(Some(Event::Output(
+                Output::from_flags(frame.header.flags),
+                frame.body,
+            ))),
+            Type::Progress => Ok(Some(Event::Progress(frame.decode::<Progress>()?))),
+            // the type of a sample depends on the command.
+            Type::Sample if frame.header.command == Command::Stats => {
+                Ok(Some(Event::Stats(frame.decode::<StatsResponse>()?)))
+            }
+            _type => Err(SharedError::UnexpectedMessage {
+                _type,
+                command: frame.header.command,
+            })?,
+        }
+    }
+
+    /// Wait for the final response, handing every event to `on_event` as it arrives.
+    pub fn wait<F: FnMut(Event)>(mut self, mut on_event: F) -> Result<T, ClientError> {
+        while let Some(event) = self.next_event()? {
+            on_event(event);
+        }
+        self.result
+            .take()
+            .unwrap_or(Err(SharedError::ConnectionClosed.into()))
+    }
+
+    /// Wait for the final response, discarding any events.
+    pub fn finish(self) -> Result<T, ClientError> {
+        self.wait(|_| {})
+    }
+}
@@ -9,8 +9,8 @@
 //! deserialization.
 //!
 //! # Example
-//! ```rust
-//! use j1407b_shared::config::{ConfigHolder, DefaultConfig};
+//! ```rust,no_run
+//! use shared::config::{ConfigHolder, DefaultConfig};
 //! use serde::{Deserialize, Serialize};
 //!
 //! #[derive(Debug, Deserialize, Serialize)]
@@ -19,8 +19,9 @@
 //! }
 //!
 //! impl DefaultConfig for MyConfig {
-//!   fn default(path: &str) -> Self {
-//!   MyConfig { value: 42 }
+//!     fn default(path: &str) -> Self {
+//!         MyConfig { value: 42 }
+//!     }
 //! }
 //!
 //! // create a new config holder. `my_config.toml` will be created in the
@@ -34,8 +35,6 @@
 //! config.write().unwrap();
 //! ```
 
-#![allow(clippy::redundant_field_names)]
-
 use crate::{
     error::SharedError,
     utils::{self, ensure_directory},
@@ -66,7 +65,7 @@ where
         let config = Self::read(base_path.as_str(), config_path.as_str())?;
         Ok(ConfigHolder {
             path: config_path,
-            config: config,
+            config,
         })
     }
 
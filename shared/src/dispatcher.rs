@@ -0,0 +1,260 @@
+//! The dispatcher multiplexes many requests over a single connection to the daemon.
+//! A background thread reads every incoming frame and routes it, by its request id,
+//! to the `Call` waiting for it. Requests may be sent from any number of threads and
+//! their responses may arrive in any order.
+//!
+//! # Example
+//! ```rust,no_run
+//! use shared::{
+//!     dispatcher::Dispatcher,
+//!     protocol::{Command, Type},
+//!     requests::PullRequest,
+//!     utils,
+//! };
+//! use std::os::unix::net::UnixStream;
+//!
+//! let stream = UnixStream::connect(utils::default_socket_path()).unwrap();
+//! let dispatcher = Dispatcher::new(stream).unwrap();
+//!
+//! // send a request and wait for the frames answering it.
+//! let request = PullRequest { image: "alpine".to_string() };
+//! let call = dispatcher.request(Command::Pull, request).unwrap();
+//! loop {
+//!     let frame = call.recv().unwrap();
+//!     if matches!(frame.header._type, Type::Response | Type::End) {
+//!         break;
+//!     }
+//! }
+//! ```
+
+use crate::{
+    error::SharedError,
+    protocol::{
+        Capabilities, Command, Frame, Handshake, Protocol, SharedWriter, Type,
+        DEFAULT_MAX_BODY_SIZE,
+    },
+};
+use serde::Serialize;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// The calls waiting for frames, by request id. `None` once the connection is closed.
+type Pending = Arc<Mutex<Option<HashMap<u32, Sender<Frame>>>>>;
+
+/// A connection to the daemon shared by many concurrent requests.
+pub struct Dispatcher {
+    stream: UnixStream,
+    writer: SharedWriter<UnixStream>,
+    protocol: Handshake,
+    pending: Pending,
+    next_request_id: AtomicU32,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    /// Negotiate the protocol over a freshly connected stream and start routing
+    /// the frames read from it.
+    pub fn new(mut stream: UnixStream) -> Result<Self, SharedError> {
+        let protocol = Self::handshake(&mut stream)?;
+
+        let reader = stream
+            .try_clone()
+            .map_err(|e| SharedError::IO { source: e })?;
+        let writer = stream
+            .try_clone()
+            .map_err(|e| SharedError::IO { source: e })?;
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let handle = thread::Builder::new()
+            .name("dispatcher".to_string())
+            .spawn({
+                let pending = Arc::clone(&pending);
+                move || Self::dispatch(reader, pending)
+            })
+            .map_err(|e| SharedError::IO { source: e })?;
+
+        Ok(Dispatcher {
+            stream,
+            writer: SharedWriter::new(writer),
+            protocol,
+            pending,
+            next_request_id: AtomicU32::new(1),
+            reader: Some(handle),
+        })
+    }
+
+    /// Exchange handshakes with the daemon and negotiate the protocol version
+    /// and capabilities. Fails if the daemon speaks an incompatible protocol. Every
+    /// request carries the negotiated version.
+    fn handshake(stream: &mut UnixStream) -> Result<Handshake, SharedError> {
+        let local = Handshake::local();
+        let message = Protocol::write_handshake(local)?;
+        Protocol::send(stream, &message)?;
+
+        let header = Protocol::read_header(stream)?;
+        if header._type != Type::Handshake {
+            return Err(SharedError::UnexpectedMessage {
+                _type: header._type,
+                command: header.command,
+            });
+        }
+        let remote = Protocol::read_body::<Handshake, _>(stream, &header, DEFAULT_MAX_BODY_SIZE)?;
+        local.negotiate(&remote)
+    }
+
+    /// The negotiated protocol version and capabilities.
+    pub fn protocol(&self) -> Handshake {
+        self.protocol
+    }
+
+    /// Send a request to the daemon. The frames answering it are delivered to the
+    /// returned `Call`.
+    pub fn request<B: Serialize>(&self, command: Command, body: B) -> Result<Call, SharedError> {
+        // responses can only be decoded if the daemon wraps them in an envelope.
+        if !self.protocol.capabilities.contains(Capabilities::RESPONSES) {
+            return Err(SharedError::MissingCapability {
+                capabilities: Capabilities::RESPONSES,
+            });
+        }
+        let body = Protocol::write_body::<B>(self.protocol.version, body)?;
+
+        // register the call before sending so no frame can arrive unrouted.
+        let (sender, receiver) = mpsc::channel();
+        let request_id = {
+            let mut pending = lock(&self.pending);
+            let calls = pending.as_mut().ok_or(SharedError::ConnectionClosed)?;
+            let request_id = self.allocate_request_id(calls);
+            calls.insert(request_id, sender);
+            request_id
+        };
+
+        let header = Protocol::write_header(
+            self.protocol.version,
+            Type::Request,
+            command,
+            request_id,
+            body.len() as u64,
+        );
+        let message = Protocol::write_message(header, body);
+        if let Err(err) = Protocol::send(&mut self.writer.clone(), &message) {
+            if let Some(calls) = lock(&self.pending).as_mut() {
+                calls.remove(&request_id);
+            }
+            return Err(err);
+        }
+
+        Ok(Call {
+            request_id,
+            command,
+            receiver,
+            pending: Arc::clone(&self.pending),
+            finished: Cell::new(false),
+        })
+    }
+
+    /// Pick a request id that is not in flight. Id `0` is reserved for the handshake.
+    fn allocate_request_id(&self, calls: &HashMap<u32, Sender<Frame>>) -> u32 {
+        loop {
+            let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+            if request_id != 0 && !calls.contains_key(&request_id) {
+                return request_id;
+            }
+        }
+    }
+
+    /// Read frames until the connection fails and route each one to its call.
+    fn dispatch(mut reader: UnixStream, pending: Pending) {
+        while let Ok(frame) = Protocol::read_frame(&mut reader, DEFAULT_MAX_BODY_SIZE) {
+            let mut pending = lock(&pending);
+            let Some(calls) = pending.as_mut() else {
+                break;
+            };
+            // the final frame of a response finishes the call.
+            let request_id = frame.header.request_id;
+            let sender = match frame.header._type {
+                Type::Response | Type::End => calls.remove(&request_id),
+                _ => calls.get(&request_id).cloned(),
+            };
+            // frames for calls that were dropped are discarded.
+            if let Some(sender) = sender {
+                let _ = sender.send(frame);
+            }
+        }
+
+        // fail every call still waiting and every call made from now on.
+        lock(&pending).take();
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        // shutting the socket down wakes the reader thread up.
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// A request in flight. Receives the frames answering it in the order the daemon
+/// sent them.
+pub struct Call {
+    request_id: u32,
+    command: Command,
+    receiver: Receiver<Frame>,
+    pending: Pending,
+    finished: Cell<bool>,
+}
+
+impl Call {
+    /// The id of the request.
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    /// The command of the request.
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// Wait for the next frame answering the request. The last frame is either a
+    /// `Response` or an `End`. Fails with `ConnectionClosed` once the connection is
+    /// gone or the call is finished.
+    pub fn recv(&self) -> Result<Frame, SharedError> {
+        let frame = self
+            .receiver
+            .recv()
+            .map_err(|_| SharedError::ConnectionClosed)?;
+        if matches!(frame.header._type, Type::Response | Type::End) {
+            self.finished.set(true);
+        }
+        Ok(frame)
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        // stop routing frames to an abandoned call. finished calls were already
+        // removed and their id may have been handed out again.
+        if self.finished.get() {
+            return;
+        }
+        if let Some(calls) = lock(&self.pending).as_mut() {
+            calls.remove(&self.request_id);
+        }
+    }
+}
+
+/// Lock the pending calls, ignoring poisoning since the map stays consistent.
+fn lock(pending: &Pending) -> MutexGuard<'_, Option<HashMap<u32, Sender<Frame>>>> {
+    pending.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -1,7 +1,6 @@
 //! Error types for the shared library. It uses `thiserror` for error handling.
 
-use std::os::fd::RawFd;
-
+use crate::protocol::{Capabilities, Command, ErrorResponse, Type};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,6 +14,9 @@ pub enum SharedError {
     #[error("IO error: {source}")]
     IO { source: std::io::Error },
 
+    #[error("Refusing to use directory {path}: {reason}")]
+    InsecureDirectory { path: String, reason: &'static str },
+
     #[error("Failed to create socket: {errno}")]
     CreateSocket {
         #[source]
@@ -27,15 +29,20 @@ pub enum SharedError {
         errno: nix::errno::Errno,
     },
 
-    #[error(
-        "Failed to read a connection fd {conn_fd} from socket descriptor {socket_fd}: {errno}"
-    )]
-    ReadSocketConnection {
-        socket_fd: RawFd,
-        conn_fd: RawFd,
-        #[source]
-        errno: nix::errno::Errno,
-    },
+    #[error("Failed to read from connection: {source}")]
+    ReadConnection { source: std::io::Error },
+
+    #[error("Failed to write to connection: {source}")]
+    WriteConnection { source: std::io::Error },
+
+    #[error("Connection closed by peer")]
+    ConnectionClosed,
+
+    #[error("Connection closed after {read} of {expected} bytes")]
+    UnexpectedEof { expected: usize, read: usize },
+
+    #[error("Message body of {length} bytes exceeds the maximum of {max} bytes")]
+    BodyTooLarge { length: u64, max: u64 },
 
     #[error("Failed to serialize message: {0}")]
     MessageSerialize(String),
@@ -48,4 +55,43 @@ pub enum SharedError {
 
     #[error("Serialize error: {source}")]
     ConfigSerialize { source: toml::ser::Error },
+
+    #[error("Incompatible protocol: local speaks versions {local_min}..={local}, remote speaks versions {remote_min}..={remote}")]
+    IncompatibleProtocol {
+        local: u8,
+        local_min: u8,
+        remote: u8,
+        remote_min: u8,
+    },
+
+    #[error("Unsupported protocol version {version} in message header")]
+    UnsupportedVersion { version: u8 },
+
+    #[error("Invalid magic bytes {magic:02x?} in message header")]
+    InvalidMagic { magic: [u8; 4] },
+
+    #[error("Invalid {field} {value} in message header")]
+    InvalidHeaderField { field: &'static str, value: u8 },
+
+    #[error("The peer does not support the required capabilities {capabilities}")]
+    MissingCapability { capabilities: Capabilities },
+
+    #[error("Unexpected {_type:?} message for command {command:?}")]
+    UnexpectedMessage { _type: Type, command: Command },
+}
+
+/// Errors returned by the `Client`.
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Shared Errror: {0}")]
+    Shared(#[from] SharedError),
+
+    #[error("Failed to connect to the daemon at {path}: {source}")]
+    Connect {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("{0}")]
+    Daemon(ErrorResponse),
 }
@@ -2,4 +2,7 @@ pub mod config;
 pub mod error;
 pub mod utils;
 pub mod requests;
+pub mod reference;
 pub mod protocol;
+pub mod dispatcher;
+pub mod client;
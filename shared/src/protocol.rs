@@ -1,147 +1,845 @@
 //! This module deals with the protocol used to communicate between the client and the server. The
-//! serialization and deserialization of messages is done using the `rust-fr` crate.
+//! header is encoded by hand into a fixed-width layout while bodies are serialized with the
+//! `Encoding` of the protocol version: `rust-fr` up to version 2 and JSON from version 3 on.
 //!
 //! - The protocol makes use of local unix sockets for communication.
 //! - The protocol is a binary request-response protocol.
-//! - Here `Message = Serialize(Header) + Serialize(Body)` where `Header = Type + Command + Length` & `Body = T`.
-//! - Header is 16 bytes long. Serialized Header is 41 bytes long.
-//!     - The `Type` is either a `Request` or a `Response`.
-//!     - The `Command` is the type of command being executed. This is an enum.
-//!     - The `Length` is the length of the body. This is a `u64`.
-//! - The body is `Length` bytes long. This information is stored in the header.
-//! - The header is first read from the connection. It is deserialized and the body length is extracted.
-//! This body length is then used to read the body from the connection which is then deserialized into a `T` type.
+//! - Here `Message = Encode(Header) + Serialize(Body)` where
+//!   `Header = Magic + Version + Type + Command + Flags + RequestId + Length` & `Body = T`.
+//! - The encoded header is always `20` bytes long. Multi-byte fields are big-endian (network byte order).
+//!
+//!   | Offset | Size | Field        | Description                                               |
+//!   |--------|------|--------------|-----------------------------------------------------------|
+//!   | 0      | 4    | `Magic`      | Always `J1KB` (`0x4a 0x31 0x4b 0x42`).                     |
+//!   | 4      | 1    | `Version`    | The protocol version the sender speaks.                   |
+//!   | 5      | 1    | `Type`       | The message type, see `Type`.                             |
+//!   | 6      | 1    | `Command`    | The command being executed, see `Command`.                |
+//!   | 7      | 1    | `Flags`      | A bitfield of message flags, see `Flags`.                 |
+//!   | 8      | 4    | `RequestId`  | Identifies a request. Responses echo it back.             |
+//!   | 12     | 8    | `Length`     | The length of the body in bytes.                          |
+//!
+//! - The body is `Length` bytes long. This information is stored in the header. Bodies longer than the
+//!   configured maximum are rejected before they are read.
+//! - The header is first read from the connection. It is decoded and the body length is extracted.
+//!   This body length is then used to read the body from the connection which is then deserialized into a `T` type.
+//! - Every request is answered with a `Response` message carrying the same `Command` and `RequestId`. Its body is a
+//!   `Response<T>` envelope which either holds the command specific payload or an `ErrorResponse`.
+//! - Long running commands may answer with a stream instead of a single `Response`. A stream is any
+//!   number of `Chunk` (raw output, the `STDERR` flag marks stderr), `Progress` and `Sample` (a
+//!   command specific value, e.g. a `stats` sample) messages followed by one `End` message whose
+//!   body is the final `Response<T>`. Streams are only sent to peers that
+//!   negotiated the `STREAMING` capability.
+//! - A connection carries any number of requests. Request ids must be unique among the requests in
+//!   flight on a connection and every message answering a request echoes its id. Peers that
+//!   negotiated the `MULTIPLEX` capability may have many requests in flight whose responses
+//!   interleave; otherwise requests are answered one after the other in the order they were sent.
+//! - A connection starts with a handshake. The client sends a `Handshake` message carrying the
+//!   protocol versions and capabilities it supports and the daemon answers with its own, also when
+//!   it does not support the version of the client. Handshakes are read whatever their version is.
+//!   Both sides negotiate independently and drop the connection if the peers are incompatible.
+//!   Every later message carries the negotiated version.
 //!
 //! - The grammer for the protocol is as follows:
 //! ```bnf
 //! <message> ::= <header> <body>
-//! <header> ::= <type> <command> <length>
-//! <type> ::= 1 | 2
-//! <command> ::= 1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 9
-//! <length> ::= <int>+
-//! <body> ::= <string>
-//! <string> ::= <char>+
-//! <char> ::= any printable ASCII character
-//! <int> ::= any integer value
+//! <header> ::= <magic> <version> <type> <command> <flags> <request-id> <length>
+//! <magic> ::= 0x4a 0x31 0x4b 0x42
+//! <version> ::= <u8>
+//! <type> ::= 1 | 2 | 3 | 4 | 5 | 6 | 7
+//! <command> ::= 1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 9 | 10 | 11 | 12 | 13
+//! <flags> ::= <u8>
+//! <request-id> ::= <u32>
+//! <length> ::= <u64>
+//! <body> ::= <byte>*
 //! ```
 //!
 //! This is also defined under [architecture/protocol.md](../../architecture/protocol.md) file. The
 //! communication is shown in the diagram at [architecture/communication.png](../../architecture/communication.png).
 
 use crate::error::SharedError;
-use nix::unistd::read;
 use serde::{Deserialize, Serialize};
-use std::os::fd::RawFd;
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+    panic,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+/// The protocol version spoken by this build.
+/// - Version 2 serializes bodies with `rust-fr`.
+/// - Version 3 serializes bodies as JSON, see `Encoding`.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// The oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// The magic bytes every header starts with.
+pub const MAGIC: [u8; 4] = *b"J1KB";
+
+/// The size of an encoded `Header`.
+pub const HEADER_SIZE: usize = 20;
+
+/// The default upper bound for the length of a message body (16 MiB).
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Type {
-    Request = 01,
-    Response = 02,
+    Request = 1,
+    Response = 2,
+    Handshake = 3,
+    Chunk = 4,
+    Progress = 5,
+    End = 6,
+    Sample = 7,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+impl TryFrom<u8> for Type {
+    type Error = SharedError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Type::Request),
+            2 => Ok(Type::Response),
+            3 => Ok(Type::Handshake),
+            4 => Ok(Type::Chunk),
+            5 => Ok(Type::Progress),
+            6 => Ok(Type::End),
+            7 => Ok(Type::Sample),
+            _ => Err(SharedError::InvalidHeaderField {
+                field: "type",
+                value,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Command {
-    Pull = 01,
-    Run = 02,
-    Stop = 03,
-    Rm = 04,
-    Ps = 05,
-    Images = 06,
-    Logs = 07,
-    Exec = 08,
-    Tag = 09,
+    Pull = 1,
+    Run = 2,
+    Stop = 3,
+    Rm = 4,
+    Ps = 5,
+    Images = 6,
+    Logs = 7,
+    Exec = 8,
+    Tag = 9,
+    Handshake = 10,
+    LogLevel = 11,
+    Stats = 12,
+    Untag = 13,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+impl TryFrom<u8> for Command {
+    type Error = SharedError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Command::Pull),
+            2 => Ok(Command::Run),
+            3 => Ok(Command::Stop),
+            4 => Ok(Command::Rm),
+            5 => Ok(Command::Ps),
+            6 => Ok(Command::Images),
+            7 => Ok(Command::Logs),
+            8 => Ok(Command::Exec),
+            9 => Ok(Command::Tag),
+            10 => Ok(Command::Handshake),
+            11 => Ok(Command::LogLevel),
+            12 => Ok(Command::Stats),
+            13 => Ok(Command::Untag),
+            _ => Err(SharedError::InvalidHeaderField {
+                field: "command",
+                value,
+            }),
+        }
+    }
+}
+
+/// A bitfield of per-message flags. Bits not listed here are reserved and must be zero.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Flags(pub u8);
+
+impl Flags {
+    /// No flags set.
+    pub const NONE: Flags = Flags(0);
+
+    /// A `Chunk` carries output written to stderr rather than stdout.
+    pub const STDERR: Flags = Flags(1 << 0);
+
+    /// Every flag known to this build.
+    pub const KNOWN: Flags = Flags(Self::STDERR.0);
+
+    /// Check whether all flags in `other` are set in `self`.
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Header {
+    pub version: u8,      // 1 byte
     pub _type: Type,      // 1 byte
     pub command: Command, // 1 byte
+    pub flags: Flags,     // 1 byte
+    pub request_id: u32,  // 4 bytes
     pub length: u64,      // 8 bytes
 }
 
 impl Header {
-    /// Create a new header
-    pub fn new(_type: Type, command: Command, length: u64) -> Self {
+    /// Create a new header of the newest protocol version this build speaks.
+    pub fn new(_type: Type, command: Command, request_id: u32, length: u64) -> Self {
         Header {
+            version: PROTOCOL_VERSION,
             _type,
             command,
+            flags: Flags::NONE,
+            request_id,
             length,
         }
     }
+
+    /// Encode the header into its fixed-width wire representation.
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self._type as u8;
+        bytes[6] = self.command as u8;
+        bytes[7] = self.flags.0;
+        bytes[8..12].copy_from_slice(&self.request_id.to_be_bytes());
+        bytes[12..20].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    /// Decode a header from its fixed-width wire representation.
+    /// - The magic bytes must match.
+    /// - Versions this build does not support are rejected before any other field is looked at,
+    ///   except for handshakes. Their layout never changes, so peers of any version can tell
+    ///   each other which versions they speak.
+    /// - Unknown types, commands and flags are rejected.
+    pub fn decode(bytes: &[u8; HEADER_SIZE]) -> Result<Self, SharedError> {
+        let magic: [u8; 4] = bytes[0..4].try_into().expect("slice of length 4");
+        if magic != MAGIC {
+            return Err(SharedError::InvalidMagic { magic });
+        }
+        let version = bytes[4];
+        let handshake = bytes[5] == Type::Handshake as u8;
+        if !handshake && !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(SharedError::UnsupportedVersion { version });
+        }
+        let flags = Flags(bytes[7]);
+        if !Flags::KNOWN.contains(flags) {
+            return Err(SharedError::InvalidHeaderField {
+                field: "flags",
+                value: flags.0,
+            });
+        }
+        Ok(Header {
+            version,
+            _type: Type::try_from(bytes[5])?,
+            command: Command::try_from(bytes[6])?,
+            flags,
+            request_id: u32::from_be_bytes(bytes[8..12].try_into().expect("slice of length 4")),
+            length: u64::from_be_bytes(bytes[12..20].try_into().expect("slice of length 8")),
+        })
+    }
+}
+
+/// A set of optional protocol features a peer supports. Capabilities are
+/// exchanged during the handshake and only the ones both peers support are used.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Responses are wrapped in a `Response<T>` envelope.
+    pub const RESPONSES: Capabilities = Capabilities(1 << 0);
+
+    /// Long running commands may answer with a stream of `Chunk` and `Progress`
+    /// messages terminated by an `End` message.
+    pub const STREAMING: Capabilities = Capabilities(1 << 1);
+
+    /// Requests on one connection are handled concurrently and their responses
+    /// may arrive in any order.
+    pub const MULTIPLEX: Capabilities = Capabilities(1 << 2);
+
+    /// Every capability supported by this build.
+    pub const SUPPORTED: Capabilities =
+        Capabilities(Self::RESPONSES.0 | Self::STREAMING.0 | Self::MULTIPLEX.0);
+
+    /// Named capabilities, used for display.
+    const NAMES: &'static [(Capabilities, &'static str)] = &[
+        (Self::RESPONSES, "responses"),
+        (Self::STREAMING, "streaming"),
+        (Self::MULTIPLEX, "multiplex"),
+    ];
+
+    /// Check whether all capabilities in `other` are present in `self`.
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities present in both `self` and `other`.
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        write!(f, "[{}]", names.join(", "))
+    }
+}
+
+/// The body of a `Handshake` message. Both peers send one when a connection
+/// is established.
+/// - `version` is the newest protocol version the peer speaks.
+/// - `min_version` is the oldest protocol version the peer can still talk to.
+/// - `capabilities` are the optional features the peer supports.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub struct Handshake {
+    pub version: u8,
+    pub min_version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl Handshake {
+    /// The handshake describing this build.
+    pub fn local() -> Self {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+
+    /// Negotiate the protocol with a remote peer. Returns the agreed upon version and
+    /// the capabilities both peers support, or an error if the peers are incompatible.
+    pub fn negotiate(&self, remote: &Handshake) -> Result<Handshake, SharedError> {
+        let version = self.version.min(remote.version);
+        let min_version = self.min_version.max(remote.min_version);
+        if version < min_version {
+            return Err(SharedError::IncompatibleProtocol {
+                local: self.version,
+                local_min: self.min_version,
+                remote: remote.version,
+                remote_min: remote.min_version,
+            });
+        }
+        Ok(Handshake {
+            version,
+            min_version,
+            capabilities: self.capabilities.intersection(remote.capabilities),
+        })
+    }
+}
+
+/// The envelope every response body is wrapped in. A response either carries
+/// the payload of the command that was executed or a structured error.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub enum Response<T> {
+    Success(T),
+    Error(ErrorResponse),
+}
+
+impl<T> From<Result<T, ErrorResponse>> for Response<T> {
+    fn from(result: Result<T, ErrorResponse>) -> Self {
+        match result {
+            Ok(payload) => Response::Success(payload),
+            Err(error) => Response::Error(error),
+        }
+    }
+}
+
+impl<T> From<Response<T>> for Result<T, ErrorResponse> {
+    fn from(response: Response<T>) -> Self {
+        match response {
+            Response::Success(payload) => Ok(payload),
+            Response::Error(error) => Err(error),
+        }
+    }
+}
+
+/// The class of an error reported by the daemon.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum ErrorCode {
+    Internal = 1,
+    InvalidRequest = 2,
+    NotFound = 3,
+    Unimplemented = 4,
+    PermissionDenied = 5,
+}
+
+impl ErrorCode {
+    /// A stable, script friendly name for the error code.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "internal",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Unimplemented => "unimplemented",
+            ErrorCode::PermissionDenied => "permission_denied",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A structured error sent back to the client.
+/// - `message` is the top level error message.
+/// - `causes` is the chain of underlying errors, outermost first.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+    pub causes: Vec<String>,
+}
+
+impl ErrorResponse {
+    /// Create a new error response without any causes.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorResponse {
+            code,
+            message: message.into(),
+            causes: Vec::new(),
+        }
+    }
+
+    /// Create an error response from an error, collecting its `source` chain.
+    pub fn from_error(code: ErrorCode, error: &dyn Error) -> Self {
+        let mut causes = Vec::new();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        ErrorResponse {
+            code,
+            message: error.to_string(),
+            causes,
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// The output stream a `Chunk` belongs to.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+pub enum Output {
+    Stdout,
+    Stderr,
+}
+
+impl Output {
+    /// The header flags marking a chunk of this output.
+    pub fn flags(&self) -> Flags {
+        match self {
+            Output::Stdout => Flags::NONE,
+            Output::Stderr => Flags::STDERR,
+        }
+    }
+
+    /// The output a chunk with the given header flags belongs to.
+    pub fn from_flags(flags: Flags) -> Self {
+        match flags.contains(Flags::STDERR) {
+            true => Output::Stderr,
+            false => Output::Stdout,
+        }
+    }
+}
+
+/// The body of a `Progress` message.
+/// - `id` identifies the unit of work, e.g. a layer digest.
+/// - `status` describes what is happening, e.g. `Downloading`.
+/// - `current` and `total` measure the progress. A `total` of `0` means the total is unknown.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct Progress {
+    pub id: String,
+    pub status: String,
+    pub current: u64,
+    pub total: u64,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.total {
+            0 => write!(f, "{}: {}", self.id, self.status),
+            total => write!(f, "{}: {} {}/{}", self.id, self.status, self.current, total),
+        }
+    }
+}
+
+/// Writes the response to a single request, either as one `Response` message or as a
+/// stream of `Chunk`, `Progress` and `Sample` messages terminated by an `End` message.
+/// - Every message carries the protocol version negotiated with the peer.
+/// - Every message is handed to the writer in a single `write_all` call.
+/// - The final status is sent as `End` if anything was streamed before it and as a plain
+///   `Response` otherwise.
+/// - If the peer did not negotiate `STREAMING`, chunks, progress and samples are dropped.
+pub struct StreamWriter<W: Write> {
+    writer: W,
+    version: u8,
+    command: Command,
+    request_id: u32,
+    streaming: bool,
+    started: bool,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Create a writer answering the request described by `request` with the `protocol`
+    /// negotiated with the peer.
+    pub fn new(writer: W, request: &Header, protocol: &Handshake) -> Self {
+        StreamWriter {
+            writer,
+            version: protocol.version,
+            command: request.command,
+            request_id: request.request_id,
+            streaming: protocol.capabilities.contains(Capabilities::STREAMING),
+            started: false,
+        }
+    }
+
+    /// The command being answered.
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// Whether messages other than the final status reach the peer.
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Send a chunk of output.
+    pub fn chunk(&mut self, output: Output, data: &[u8]) -> Result<(), SharedError> {
+        let mut header = Header::new(
+            Type::Chunk,
+            self.command,
+            self.request_id,
+            data.len() as u64,
+        );
+        header.version = self.version;
+        header.flags = output.flags();
+        self.stream(header.encode().to_vec(), data.to_vec())
+    }
+
+    /// Send a progress update.
+    pub fn progress(&mut self, progress: &Progress) -> Result<(), SharedError> {
+        let body = Protocol::write_body(self.version, progress)?;
+        let header = Protocol::write_header(
+            self.version,
+            Type::Progress,
+            self.command,
+            self.request_id,
+            body.len() as u64,
+        );
+        self.stream(header, body)
+    }
+
+    /// Send a sample of a command that reports a value repeatedly, e.g. the resource
+    /// usage of `stats`. Its type is given by the command.
+    pub fn sample<T: Serialize>(&mut self, sample: &T) -> Result<(), SharedError> {
+        let body = Protocol::write_body(self.version, sample)?;
+        let header = Protocol::write_header(
+            self.version,
+            Type::Sample,
+            self.command,
+            self.request_id,
+            body.len() as u64,
+        );
+        self.stream(header, body)
+    }
+
+    /// Send the final status of the command and finish the response.
+    pub fn end<T: Serialize>(mut self, response: Response<T>) -> Result<(), SharedError> {
+        let _type = match self.started {
+            true => Type::End,
+            false => Type::Response,
+        };
+        let body = Protocol::write_body(self.version, response)?;
+        let header = Protocol::write_header(
+            self.version,
+            _type,
+            self.command,
+            self.request_id,
+            body.len() as u64,
+        );
+        Protocol::send(&mut self.writer, &Protocol::write_message(header, body))
+    }
+
+    /// Send a message that is part of the stream, if the peer accepts streams.
+    fn stream(&mut self, header: Vec<u8>, body: Vec<u8>) -> Result<(), SharedError> {
+        if !self.streaming {
+            return Ok(());
+        }
+        self.started = true;
+        Protocol::send(&mut self.writer, &Protocol::write_message(header, body))
+    }
+}
+
+/// A `Write` handle to a connection that can be shared between threads.
+/// - Clones write to the same underlying writer.
+/// - The lock is held for a whole `write_all` call, so messages written by different
+///   threads never interleave on the wire.
+#[derive(Debug)]
+pub struct SharedWriter<W: Write>(Arc<Mutex<W>>);
+
+impl<W: Write> SharedWriter<W> {
+    /// Wrap a writer so it can be shared.
+    pub fn new(writer: W) -> Self {
+        SharedWriter(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Lock the underlying writer. A writer poisoned by a panicking thread is still
+    /// usable since every message is written by a single `write_all` call.
+    fn lock(&self) -> MutexGuard<'_, W> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<W: Write> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        SharedWriter(Arc::clone(&self.0))
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.lock().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+/// A raw message read from a connection. The body is left undecoded so it can be
+/// routed before it is deserialized.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub header: Header,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// Decode the body of the frame into a `T` type.
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T, SharedError> {
+        Protocol::decode_body(&self.header, &self.body)
+    }
+}
+
+/// How message bodies are serialized, see `architecture/protocol.md`.
+/// - `RustFr` is the encoding of version 2. Sequences of structs, tuples or sequences and
+///   some strings do not survive it, so the list payloads of `ps`, `images`, `rm` and
+///   `stats` cannot be sent with it.
+/// - `Json` is the encoding of version 3 on.
+/// - Handshakes are always serialized with `RustFr`, the encoding every version reads.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    RustFr,
+    Json,
+}
+
+impl Encoding {
+    /// The encoding of the bodies of protocol version `version`, other than handshakes.
+    pub fn of(version: u8) -> Self {
+        match version {
+            ..=2 => Encoding::RustFr,
+            _ => Encoding::Json,
+        }
+    }
+
+    /// The encoding of the body of the message starting with `header`.
+    pub fn of_header(header: &Header) -> Self {
+        match header._type {
+            Type::Handshake => Encoding::RustFr,
+            _ => Self::of(header.version),
+        }
+    }
+
+    /// Serialize a body.
+    pub fn serialize<T: Serialize>(&self, body: &T) -> Result<Vec<u8>, SharedError> {
+        let bytes = match self {
+            Encoding::RustFr => rust_fr::serializer::to_bytes(body).map_err(|e| e.to_string()),
+            Encoding::Json => serde_json::to_vec(body).map_err(|e| e.to_string()),
+        };
+        bytes.map_err(SharedError::MessageSerialize)
+    }
+
+    /// Deserialize a body into a `T` type.
+    /// - `rust-fr` may panic on malformed input. Such panics are caught and reported as
+    ///   `MessageDeserialize` errors so a bad message cannot take the process down.
+    pub fn deserialize<T: serde::de::DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<T, SharedError> {
+        match self {
+            Encoding::RustFr => {
+                match panic::catch_unwind(|| rust_fr::deserializer::from_bytes::<T>(bytes)) {
+                    Ok(result) => {
+                        result.map_err(|e| SharedError::MessageDeserialize(e.to_string()))
+                    }
+                    Err(_) => Err(SharedError::MessageDeserialize(
+                        "malformed message".to_string(),
+                    )),
+                }
+            }
+            Encoding::Json => serde_json::from_slice::<T>(bytes)
+                .map_err(|e| SharedError::MessageDeserialize(e.to_string())),
+        }
+    }
 }
 
 pub struct Protocol;
 
 impl Protocol {
-    /// Read a header from a connection.
-    /// - The serialized header is only `41` bytes long.
-    /// - The header itself is 16 bytes long.
-    /// - It contains the type, command, and body size.
-    pub fn read_header(socket_fd: RawFd, conn_fd: i32) -> Result<Header, SharedError> {
-        let mut header_buffer = [0u8; 41];
-        loop {
-            let bytes_read = read(conn_fd, &mut header_buffer).map_err(|e| {
-                SharedError::ReadSocketConnection {
-                    socket_fd: socket_fd,
-                    conn_fd: conn_fd,
-                    errno: e,
+    /// Fill `buffer` completely from a connection.
+    /// - Partial reads are accumulated until the buffer is full.
+    /// - Interrupted reads are retried.
+    /// - The connection closing before the buffer is full is reported as `UnexpectedEof`
+    ///   along with the number of bytes that were read.
+    pub fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), SharedError> {
+        let mut offset = 0;
+        while offset < buffer.len() {
+            match reader.read(&mut buffer[offset..]) {
+                Ok(0) => {
+                    return Err(SharedError::UnexpectedEof {
+                        expected: buffer.len(),
+                        read: offset,
+                    })
                 }
-            })?;
-            if bytes_read == 41 {
-                break;
+                Ok(bytes_read) => offset += bytes_read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(SharedError::ReadConnection { source: err }),
             }
         }
-        rust_fr::deserializer::from_bytes::<Header>(&header_buffer)
-            .map_err(|e| SharedError::MessageDeserialize(e.to_string()))
+        Ok(())
+    }
+
+    /// Read a header from a connection.
+    /// - The encoded header is always `20` bytes long.
+    /// - It contains the magic, version, type, command, flags, request id and body size.
+    /// - Headers from a protocol version this build does not support are rejected, unless
+    ///   they start a handshake.
+    /// - A connection closed cleanly before the header starts is reported as `ConnectionClosed`.
+    pub fn read_header<R: Read>(reader: &mut R) -> Result<Header, SharedError> {
+        let mut header_buffer = [0u8; HEADER_SIZE];
+        Self::read_exact(reader, &mut header_buffer).map_err(|err| match err {
+            SharedError::UnexpectedEof { read: 0, .. } => SharedError::ConnectionClosed,
+            err => err,
+        })?;
+        Header::decode(&header_buffer)
     }
 
-    /// Read a body from a connection.
+    /// Read the raw bytes of a body from a connection.
     /// - The body is `message_length` bytes long. This information is stored in the header.
-    /// - The body is deserialized into a `T` type (which implements `serde::de::Deserialize`).
-    pub fn read_body<T: serde::de::DeserializeOwned>(
-        socket_fd: RawFd,
-        conn_fd: i32,
+    /// - Bodies larger than `max_body_size` are rejected before anything is read or allocated.
+    pub fn read_body_bytes<R: Read>(
+        reader: &mut R,
         message_length: u64,
-    ) -> Result<T, SharedError> {
-        let mut body_buffer = vec![0u8; message_length as usize];
-        loop {
-            let bytes_read =
-                read(conn_fd, &mut body_buffer).map_err(|e| SharedError::ReadSocketConnection {
-                    socket_fd: socket_fd,
-                    conn_fd: conn_fd,
-                    errno: e,
-                })?;
-            if bytes_read == 0 {
-                break;
-            }
+        max_body_size: u64,
+    ) -> Result<Vec<u8>, SharedError> {
+        if message_length > max_body_size {
+            return Err(SharedError::BodyTooLarge {
+                length: message_length,
+                max: max_body_size,
+            });
         }
-        rust_fr::deserializer::from_bytes::<T>(&body_buffer)
-            .map_err(|e| SharedError::MessageDeserialize(e.to_string()))
+        let mut body_buffer = vec![0u8; message_length as usize];
+        Self::read_exact(reader, &mut body_buffer)?;
+        Ok(body_buffer)
     }
 
-    /// Write a header for a request/response.
+    /// Read the body of the message starting with `header` from a connection.
+    /// - The body is `header.length` bytes long.
+    /// - The body is deserialized into a `T` type (which implements `serde::de::Deserialize`)
+    ///   with the encoding of the message.
+    pub fn read_body<T: serde::de::DeserializeOwned, R: Read>(
+        reader: &mut R,
+        header: &Header,
+        max_body_size: u64,
+    ) -> Result<T, SharedError> {
+        let body_buffer = Self::read_body_bytes(reader, header.length, max_body_size)?;
+        Self::decode_body(header, &body_buffer)
+    }
+
+    /// Read a complete message (header and raw body) from a connection.
+    pub fn read_frame<R: Read>(reader: &mut R, max_body_size: u64) -> Result<Frame, SharedError> {
+        let header = Self::read_header(reader)?;
+        let body = Self::read_body_bytes(reader, header.length, max_body_size)?;
+        Ok(Frame { header, body })
+    }
+
+    /// Deserialize the body of the message starting with `header` into a `T` type.
+    pub fn decode_body<T: serde::de::DeserializeOwned>(
+        header: &Header,
+        bytes: &[u8],
+    ) -> Result<T, SharedError> {
+        Encoding::of_header(header).deserialize(bytes)
+    }
+
+    /// Write a complete message to a connection, retrying partial and interrupted writes.
+    pub fn send<W: Write>(writer: &mut W, message: &[u8]) -> Result<(), SharedError> {
+        writer
+            .write_all(message)
+            .and_then(|_| writer.flush())
+            .map_err(|err| SharedError::WriteConnection { source: err })
+    }
+
+    /// Write a header for a request/response of protocol version `version`.
     pub fn write_header(
+        version: u8,
         _type: Type,
         command: Command,
+        request_id: u32,
         body_size: u64,
-    ) -> Result<Vec<u8>, SharedError> {
-        let header = Header::new(_type, command, body_size);
-        let header_bytes = rust_fr::serializer::to_bytes(&header)
-            .map_err(|e| SharedError::MessageSerialize(e.to_string()))?;
-        Ok(header_bytes)
+    ) -> Vec<u8> {
+        let mut header = Header::new(_type, command, request_id, body_size);
+        header.version = version;
+        header.encode().to_vec()
     }
 
-    /// Write a body for a request/response.
-    pub fn write_body<T: serde::Serialize>(body: T) -> Result<Vec<u8>, SharedError> {
-        let body_bytes = rust_fr::serializer::to_bytes(&body)
-            .map_err(|e| SharedError::MessageSerialize(e.to_string()))?;
-        Ok(body_bytes)
+    /// Write a body for a request/response of protocol version `version`.
+    pub fn write_body<T: serde::Serialize>(version: u8, body: T) -> Result<Vec<u8>, SharedError> {
+        Encoding::of(version).serialize(&body)
     }
 
     /// Write a message from a header and a body.
     pub fn write_message(header: Vec<u8>, body: Vec<u8>) -> Vec<u8> {
         [header, body].concat()
     }
+
+    /// Write a handshake message carrying the given handshake. Handshakes always use request id `0`
+    /// and carry the newest version the sender speaks.
+    pub fn write_handshake(handshake: Handshake) -> Result<Vec<u8>, SharedError> {
+        let body = Encoding::RustFr.serialize(&handshake)?;
+        let header = Self::write_header(
+            handshake.version,
+            Type::Handshake,
+            Command::Handshake,
+            0,
+            body.len() as u64,
+        );
+        Ok(Self::write_message(header, body))
+    }
 }
@@ -0,0 +1,195 @@
+//! Image references like `alpine`, `localhost:5000/team/app:1.2` or
+//! `alpine@sha256:<hex>`. A reference names a repository of a registry and either a
+//! tag or a digest of an image in it. References are normalized like the ones of the
+//! Docker Hub, `alpine` is `docker.io/library/alpine:latest`.
+
+use std::{fmt, str::FromStr};
+
+/// The registry of references that do not name one.
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// The namespace of the official images of the default registry.
+const OFFICIAL_NAMESPACE: &str = "library";
+
+/// The tag of references that name neither a tag nor a digest.
+pub const DEFAULT_TAG: &str = "latest";
+
+/// The longest accepted repository name, registry included.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// The longest accepted tag.
+const MAX_TAG_LENGTH: usize = 128;
+
+/// A normalized image reference.
+/// - `registry` is a host name with an optional port, `docker.io` by default.
+/// - `repository` is the path of the repository, official images of the default
+///   registry are in `library/`.
+/// - `tag` is `latest` unless the reference names another tag or a digest.
+/// - `digest` is `sha256:<hex>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    /// The full name of the repository, e.g. `docker.io/library/alpine`.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+
+    /// The name of the repository as it is usually written, without the default
+    /// registry and the namespace of official images, e.g. `alpine`.
+    pub fn short_name(&self) -> String {
+        if self.registry != DEFAULT_REGISTRY {
+            return self.name();
+        }
+        let official = self
+            .repository
+            .strip_prefix(OFFICIAL_NAMESPACE)
+            .and_then(|name| name.strip_prefix('/'))
+            .filter(|name| !name.contains('/'));
+        official.unwrap_or(&self.repository).to_string()
+    }
+
+    /// The reference as it is usually written, e.g. `alpine:latest`.
+    pub fn short(&self) -> String {
+        format!("{}{}", self.short_name(), self.suffix())
+    }
+
+    /// The tag and digest parts of the reference.
+    fn suffix(&self) -> String {
+        let tag = self.tag.as_ref().map(|tag| format!(":{}", tag));
+        let digest = self.digest.as_ref().map(|digest| format!("@{}", digest));
+        format!("{}{}", tag.unwrap_or_default(), digest.unwrap_or_default())
+    }
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (rest, digest) = match value.split_once('@') {
+            Some((rest, digest)) if is_digest(digest) => (rest, Some(digest.to_string())),
+            Some(_) => return Err("the digest is not sha256:<64 hex digits>".to_string()),
+            None => (value, None),
+        };
+        // a colon after the last slash starts the tag, others belong to a registry port.
+        let (name, tag) = match rest.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (rest, None),
+        };
+        if let Some(tag) = tag {
+            if !is_tag(tag) {
+                return Err(
+                    "a tag is up to 128 letters, digits, '_', '.' or '-' and cannot start with '.' or '-'"
+                        .to_string(),
+                );
+            }
+        }
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path)) if is_host(host) => (host, path.to_string()),
+            _ => (DEFAULT_REGISTRY, name.to_string()),
+        };
+        let (registry, repository) = match registry {
+            "index.docker.io" | DEFAULT_REGISTRY if !repository.contains('/') => (
+                DEFAULT_REGISTRY,
+                format!("{}/{}", OFFICIAL_NAMESPACE, repository),
+            ),
+            "index.docker.io" => (DEFAULT_REGISTRY, repository),
+            _ => (registry, repository),
+        };
+        if !repository.split('/').all(is_path_component) {
+            return Err(
+                "repository names are lowercase letters and digits separated by '/', '.', '_' or '-'"
+                    .to_string(),
+            );
+        }
+        if registry.len() + 1 + repository.len() > MAX_NAME_LENGTH {
+            return Err("the repository name is too long".to_string());
+        }
+
+        let tag = match (tag, &digest) {
+            (Some(tag), _) => Some(tag.to_string()),
+            (None, Some(_)) => None,
+            (None, None) => Some(DEFAULT_TAG.to_string()),
+        };
+        Ok(Reference {
+            registry: registry.to_string(),
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.name(), self.suffix())
+    }
+}
+
+/// Whether `value` is a digest, `sha256:` followed by 64 lowercase hex digits.
+pub fn is_digest(value: &str) -> bool {
+    value.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64
+            && hex
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+/// Whether the first component of a name is a registry rather than part of the
+/// repository: `localhost` or a host name with a dot or a port.
+fn is_host(component: &str) -> bool {
+    let (host, port) = match component.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (component, None),
+    };
+    let valid_port =
+        port.is_none_or(|port| !port.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit()));
+    let valid_host = !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        });
+    valid_host && valid_port && (host == "localhost" || host.contains('.') || port.is_some())
+}
+
+/// Whether `component` is a component of a repository path: runs of lowercase letters
+/// and digits separated by a '.', one or two '_' or any number of '-'.
+fn is_path_component(component: &str) -> bool {
+    let alphanumeric = |byte: &u8| byte.is_ascii_lowercase() || byte.is_ascii_digit();
+    let bytes = component.as_bytes();
+    let (Some(first), Some(last)) = (bytes.first(), bytes.last()) else {
+        return false;
+    };
+    if !alphanumeric(first) || !alphanumeric(last) {
+        return false;
+    }
+    component
+        .split(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        .filter(|separator| !separator.is_empty())
+        .all(|separator| {
+            matches!(separator, "." | "_" | "__") || separator.bytes().all(|byte| byte == b'-')
+        })
+}
+
+/// Whether `tag` is a valid tag.
+fn is_tag(tag: &str) -> bool {
+    let word = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_';
+    let bytes = tag.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= MAX_TAG_LENGTH
+        && word(bytes[0])
+        && bytes
+            .iter()
+            .all(|byte| word(*byte) || *byte == b'.' || *byte == b'-')
+}
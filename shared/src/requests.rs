@@ -1,6 +1,482 @@
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PullRequest {
     pub image: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullResponse {
+    pub image: String,
+}
+
+/// Create a container from an image and start `command` in it.
+/// - `image` is a reference of the image, its digest or a unique prefix of its id.
+/// - `name` defaults to the short id of the container and must be unique.
+/// - `hostname` defaults to the short id of the container.
+/// - `env` holds `KEY=VALUE` pairs. `PATH` is set to a sensible default unless given.
+/// - `uid_map` and `gid_map` map the ids of the user namespace of the container to
+///   ids of the host. By default root in the container is the user running the daemon,
+///   followed by its subordinate ids from `/etc/subuid` and `/etc/subgid`.
+/// - `resources` limits what the container may consume.
+/// - `labels` are arbitrary `KEY=VALUE` metadata, e.g. to filter `ps` by.
+/// - `volumes` are absolute paths in the container that each get an empty volume
+///   mounted over them. Volumes outlive the container unless `rm` removes them too.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RunRequest {
+    pub image: String,
+    pub command: Vec<String>,
+    pub name: Option<String>,
+    pub hostname: Option<String>,
+    pub env: Vec<String>,
+    #[serde(default)]
+    pub uid_map: Vec<IdMapping>,
+    #[serde(default)]
+    pub gid_map: Vec<IdMapping>,
+    #[serde(default)]
+    pub resources: Resources,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub volumes: Vec<String>,
+}
+
+/// The resource limits of a container, enforced with cgroups. Unset limits are
+/// unlimited.
+/// - `memory` is the maximum memory in bytes.
+/// - `cpu_weight` is the share of CPU time relative to other containers, from 1 to
+///   10000 (100 by default).
+/// - `cpu_quota` is the CPU time in microseconds the container may use every
+///   `cpu_period` microseconds (100000 by default).
+/// - `pids` is the maximum number of processes.
+/// - `io_weight` is the share of IO relative to other containers, from 1 to 10000
+///   (100 by default).
+/// - `io_limits` limit the bandwidth and operations of block devices.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Resources {
+    pub memory: Option<u64>,
+    pub cpu_weight: Option<u64>,
+    pub cpu_quota: Option<u64>,
+    pub cpu_period: Option<u64>,
+    pub pids: Option<u64>,
+    pub io_weight: Option<u64>,
+    #[serde(default)]
+    pub io_limits: Vec<IoLimit>,
+}
+
+/// The limits of a block device, given by its path or as `MAJOR:MINOR`. Rates are
+/// per second.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct IoLimit {
+    pub device: String,
+    pub read_bps: Option<u64>,
+    pub write_bps: Option<u64>,
+    pub read_iops: Option<u64>,
+    pub write_iops: Option<u64>,
+}
+
+/// `size` consecutive ids starting at `container_id` in a container, which are the
+/// ids starting at `host_id` on the host. Written as `CONTAINER:HOST:SIZE`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IdMapping {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32,
+}
+
+impl FromStr for IdMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a CONTAINER:HOST:SIZE mapping", value);
+        let ids = value
+            .split(':')
+            .map(|id| id.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match ids[..] {
+            [container_id, host_id, size] => Ok(IdMapping {
+                container_id,
+                host_id,
+                size,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for IdMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.container_id, self.host_id, self.size)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunResponse {
+    pub id: String,
+}
+
+/// Stop a running container.
+/// - `container` is an id, a unique id prefix or a name.
+/// - `signal` is sent to the init of the container, e.g. `SIGTERM`, `TERM` or `15`.
+///   Defaults to the `stop_signal` of the daemon.
+/// - `timeout` is the number of seconds the container gets to exit before all of its
+///   processes are killed. Defaults to the `stop_timeout` of the daemon.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StopRequest {
+    pub container: String,
+    pub signal: Option<String>,
+    pub timeout: Option<u64>,
+}
+
+/// How a container exited. `killed` is set if it had to be killed after the timeout.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopResponse {
+    pub id: String,
+    pub code: i32,
+    pub killed: bool,
+}
+
+/// Remove containers along with their root filesystems, cgroups and logs.
+/// - `containers` are ids, unique id prefixes or names.
+/// - `force` stops running containers first like `stop` does, with the configured
+///   stop signal and timeout, otherwise they are not removed. Containers that are
+///   still being started are never removed.
+/// - `volumes` removes the volumes of the containers too, otherwise they are kept.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RmRequest {
+    pub containers: Vec<String>,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub volumes: bool,
+}
+
+/// The result of removing every requested container, in the order of the request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RmResponse {
+    pub results: Vec<RmResult>,
+}
+
+/// The removal of a container as named in the request. `id` is set if it was removed,
+/// `error` if it was not.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RmResult {
+    pub container: String,
+    pub id: Option<String>,
+    pub error: Option<String>,
+}
+
+/// List containers.
+/// - `all` lists exited containers too, otherwise only running ones are listed unless
+///   a `status` filter asks for others.
+/// - `filters` of the same kind match if any of them does, a container is listed if
+///   every kind of filter matches.
+/// - Containers are listed newest first unless `sort` names a field to sort by.
+///   `reverse` reverses the order.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PsRequest {
+    #[serde(default)]
+    pub all: bool,
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    pub sort: Option<SortKey>,
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+/// A filter of `ps`, written as `KIND=VALUE`.
+/// - `status` is `created`, `running` or `exited`.
+/// - `label` is `KEY` to match any value or `KEY=VALUE`.
+/// - `image` matches the image exactly.
+/// - `name` matches any part of the name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Status(String),
+    Label { key: String, value: Option<String> },
+    Image(String),
+    Name(String),
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, argument) = value
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not a KIND=VALUE filter", value))?;
+        match kind {
+            "status" => Ok(Filter::Status(argument.to_string())),
+            "label" => {
+                let (key, value) = label_filter(argument);
+                Ok(Filter::Label { key, value })
+            }
+            "image" => Ok(Filter::Image(argument.to_string())),
+            "name" => Ok(Filter::Name(argument.to_string())),
+            _ => Err(format!(
+                "unknown filter {}, expected status, label, image or name",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Status(status) => write!(f, "status={}", status),
+            Filter::Label { key, value: None } => write!(f, "label={}", key),
+            Filter::Label {
+                key,
+                value: Some(value),
+            } => write!(f, "label={}={}", key, value),
+            Filter::Image(image) => write!(f, "image={}", image),
+            Filter::Name(name) => write!(f, "name={}", name),
+        }
+    }
+}
+
+/// The argument of a `label` filter, `KEY` to match any value or `KEY=VALUE`.
+fn label_filter(argument: &str) -> (String, Option<String>) {
+    match argument.split_once('=') {
+        Some((key, value)) => (key.to_string(), Some(value.to_string())),
+        None => (argument.to_string(), None),
+    }
+}
+
+/// The fields `ps` can sort by, in ascending order.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Created,
+    Name,
+    Image,
+    Status,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "created" => Ok(SortKey::Created),
+            "name" => Ok(SortKey::Name),
+            "image" => Ok(SortKey::Image),
+            "status" => Ok(SortKey::Status),
+            _ => Err(format!(
+                "cannot sort by {}, expected created, name, image or status",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PsResponse {
+    pub containers: Vec<ContainerSummary>,
+}
+
+/// A container as listed by `ps`.
+/// - `created`, `started` and `finished` are milliseconds since the unix epoch.
+/// - `status` is `created`, `running` or `exited`, `exit_code` is set once it exited.
+/// - `pid` is the pid of the container init on the host while it runs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContainerSummary {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub command: Vec<String>,
+    pub created: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub pid: Option<i32>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// List the images of the store, newest first.
+/// - `filters` of the same kind match if any of them does, an image is listed if every
+///   kind of filter matches.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImagesRequest {
+    #[serde(default)]
+    pub filters: Vec<ImageFilter>,
+}
+
+/// A filter of `images`, written as `KIND=VALUE`.
+/// - `dangling` is `true` for images without tags or `false` for tagged ones.
+/// - `reference` is a glob matching `repository:tag` or the repository, where `*`
+///   matches any characters and `?` a single one.
+/// - `label` is `KEY` to match any value or `KEY=VALUE`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFilter {
+    Dangling(bool),
+    Reference(String),
+    Label { key: String, value: Option<String> },
+}
+
+impl FromStr for ImageFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, argument) = value
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not a KIND=VALUE filter", value))?;
+        match kind {
+            "dangling" => match argument {
+                "true" => Ok(ImageFilter::Dangling(true)),
+                "false" => Ok(ImageFilter::Dangling(false)),
+                _ => Err(format!("dangling is true or false, not {}", argument)),
+            },
+            "reference" => Ok(ImageFilter::Reference(argument.to_string())),
+            "label" => {
+                let (key, value) = label_filter(argument);
+                Ok(ImageFilter::Label { key, value })
+            }
+            _ => Err(format!(
+                "unknown filter {}, expected dangling, reference or label",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ImageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFilter::Dangling(dangling) => write!(f, "dangling={}", dangling),
+            ImageFilter::Reference(pattern) => write!(f, "reference={}", pattern),
+            ImageFilter::Label { key, value: None } => write!(f, "label={}", key),
+            ImageFilter::Label {
+                key,
+                value: Some(value),
+            } => write!(f, "label={}={}", key, value),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImagesResponse {
+    pub images: Vec<ImageSummary>,
+}
+
+/// An image as listed by `images`.
+/// - `digest` is `sha256:<hex>`, computed from the digests of the `layers`.
+/// - `tags` are `repository:tag` references, a dangling image has none.
+/// - `size` is the size of the files of all layers in bytes.
+/// - `created` is the time the image was unpacked, in milliseconds since the unix epoch.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageSummary {
+    pub digest: String,
+    pub tags: Vec<String>,
+    pub size: u64,
+    pub created: u64,
+    pub layers: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Tag an image with another reference.
+/// - `source` is a reference of the image, its digest or a unique prefix of its id.
+/// - `target` is the new reference, it is taken from any image it tagged before.
+///
+/// References are normalized, `alpine` is `docker.io/library/alpine:latest`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagRequest {
+    pub source: String,
+    pub target: String,
+}
+
+/// The image that was tagged and its new normalized reference.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagResponse {
+    pub digest: String,
+    pub reference: String,
+}
+
+/// Remove a tag from an image. The image is kept, without tags it is dangling.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UntagRequest {
+    pub reference: String,
+}
+
+/// The image that was untagged and the normalized reference it lost.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UntagResponse {
+    pub digest: String,
+    pub reference: String,
+}
+
+/// Read the output of a container, streamed as stdout and stderr chunks line by line.
+/// - `container` is an id, a unique id prefix or a name.
+/// - `follow` keeps streaming new lines until the container exited and its output
+///   was read.
+/// - `tail` only reads the last lines written before the request.
+/// - `since` and `until` bound the time lines were written at, as an RFC 3339 time,
+///   a unix timestamp in seconds or a duration before now like `10m`.
+/// - `timestamps` prefixes every line with the RFC 3339 time it was written at.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LogsRequest {
+    pub container: String,
+    #[serde(default)]
+    pub follow: bool,
+    pub tail: Option<usize>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
+/// Change the log level of the daemon, or only read it if `level` is `None`.
+/// - `level` is one of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LogLevelRequest {
+    pub level: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogLevelResponse {
+    pub level: String,
+}
+
+/// Read the resource usage of containers from their cgroups.
+/// - `containers` are ids, unique id prefixes or names. All running containers if empty.
+/// - `stream` keeps sampling every `interval` milliseconds (1000 by default) until the
+///   requested containers have exited, streaming every sample.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StatsRequest {
+    #[serde(default)]
+    pub containers: Vec<String>,
+    #[serde(default)]
+    pub stream: bool,
+    pub interval: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct StatsResponse {
+    pub containers: Vec<ContainerStats>,
+}
+
+/// The resource usage of a container. Counters the host does not provide are `None`.
+/// - `memory` is the memory in use in bytes, `memory_limit` its maximum if limited.
+/// - `cpu_usage`, `cpu_user` and `cpu_system` are the CPU time used in microseconds.
+/// - `io_read_bytes`, `io_write_bytes`, `io_reads` and `io_writes` add up all devices.
+/// - `pids` is the number of processes, `pids_limit` their maximum if limited.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ContainerStats {
+    pub id: String,
+    pub name: String,
+    pub memory: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub cpu_usage: Option<u64>,
+    pub cpu_user: Option<u64>,
+    pub cpu_system: Option<u64>,
+    pub io_read_bytes: Option<u64>,
+    pub io_write_bytes: Option<u64>,
+    pub io_reads: Option<u64>,
+    pub io_writes: Option<u64>,
+    pub pids: Option<u64>,
+    pub pids_limit: Option<u64>,
+}
@@ -1,8 +1,15 @@
 //! Utility functions for the shared library.
 
 use crate::error::SharedError;
+use nix::unistd::geteuid;
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::Path,
+};
 
 const HOME_VARIABLE: &str = "HOME";
+const RUNTIME_DIR_VARIABLE: &str = "XDG_RUNTIME_DIR";
 
 /// Get the home directory of the current user.
 pub fn get_home_dir() -> Result<String, SharedError> {
@@ -16,3 +23,45 @@ pub fn get_home_dir() -> Result<String, SharedError> {
 pub fn ensure_directory(directory: &str) -> Result<(), SharedError> {
     std::fs::create_dir_all(directory).map_err(|err| SharedError::IO { source: err })
 }
+
+/// Create a directory only the current user may write to, e.g. the one the socket and
+/// the pid file are in. An existing directory is refused unless it is a real directory
+/// owned by the effective user that neither its group nor others may write to, since
+/// whoever can write to it can replace the files in it. Its parents are not checked.
+pub fn ensure_private_directory(directory: &Path) -> Result<(), SharedError> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)
+        .map_err(|err| SharedError::IO { source: err })?;
+    let metadata =
+        fs::symlink_metadata(directory).map_err(|err| SharedError::IO { source: err })?;
+    let reason = if !metadata.file_type().is_dir() {
+        "it is not a directory"
+    } else if metadata.uid() != geteuid().as_raw() {
+        "it is owned by another user"
+    } else if metadata.mode() & 0o022 != 0 {
+        "its group or others may write to it"
+    } else {
+        return Ok(());
+    };
+    Err(SharedError::InsecureDirectory {
+        path: directory.display().to_string(),
+        reason,
+    })
+}
+
+/// Get the runtime directory of the current user. It is `$XDG_RUNTIME_DIR` and falls
+/// back to a per-user directory under `/tmp`, so every user on a host gets their own.
+/// Its name is predictable, so it goes through `ensure_private_directory` before use.
+pub fn runtime_dir() -> String {
+    match std::env::var(RUNTIME_DIR_VARIABLE) {
+        Ok(runtime_dir) if !runtime_dir.is_empty() => runtime_dir,
+        _ => format!("/tmp/j1407b-{}", nix::unistd::getuid()),
+    }
+}
+
+/// Get the default path of the daemon socket, in the runtime directory.
+pub fn default_socket_path() -> String {
+    format!("{}/j1407b.sock", runtime_dir())
+}
@@ -0,0 +1,114 @@
+//! Tests for the typed `Client`. A fake daemon on the other end of a socket pair
+//! streams events before its final response.
+
+use shared::{
+    client::{Client, Event},
+    error::{ClientError, SharedError},
+    protocol::{
+        Command, ErrorCode, ErrorResponse, Handshake, Output, Progress, Protocol, Response,
+        StreamWriter, Type, DEFAULT_MAX_BODY_SIZE,
+    },
+    requests::{PullRequest, PullResponse, StatsResponse},
+};
+use std::{os::unix::net::UnixStream, thread};
+
+/// Answer the handshake and the first request with the frames written by `respond`.
+fn serve<F>(mut daemon: UnixStream, respond: F) -> thread::JoinHandle<()>
+where
+    F: FnOnce(StreamWriter<&mut UnixStream>, PullRequest) + Send + 'static,
+{
+    thread::spawn(move || {
+        Protocol::read_frame(&mut daemon, DEFAULT_MAX_BODY_SIZE).unwrap();
+        let message = Protocol::write_handshake(Handshake::local()).unwrap();
+        Protocol::send(&mut daemon, &message).unwrap();
+
+        let frame = Protocol::read_frame(&mut daemon, DEFAULT_MAX_BODY_SIZE).unwrap();
+        assert_eq!(frame.header._type, Type::Request);
+        let request = frame.decode::<PullRequest>().unwrap();
+        respond(
+            StreamWriter::new(&mut daemon, &frame.header, &Handshake::local()),
+            request,
+        );
+    })
+}
+
+#[test]
+fn streamed_events_precede_the_response() {
+    let (client, daemon) = UnixStream::pair().unwrap();
+    let progress = Progress {
+        id: "layer".to_string(),
+        status: "Downloading".to_string(),
+        current: 2,
+        total: 10,
+    };
+    let server = serve(daemon, {
+        let progress = progress.clone();
+        move |mut writer, request| {
+            writer.progress(&progress).unwrap();
+            writer.chunk(Output::Stderr, b"warning\n").unwrap();
+            writer
+                .end(Response::Success(PullResponse {
+                    image: request.image,
+                }))
+                .unwrap();
+        }
+    });
+
+    let client = Client::from_stream(client).unwrap();
+    let mut events = vec![];
+    let response = client
+        .pull("alpine")
+        .unwrap()
+        .wait(|event| events.push(event))
+        .unwrap();
+    assert_eq!(response.image, "alpine");
+    assert_eq!(
+        events,
+        vec![
+            Event::Progress(progress),
+            Event::Output(Output::Stderr, b"warning\n".to_vec()),
+        ]
+    );
+    server.join().unwrap();
+}
+
+#[test]
+fn daemon_errors_are_typed() {
+    let (client, daemon) = UnixStream::pair().unwrap();
+    let server = serve(daemon, |writer, _| {
+        let error = ErrorResponse::new(ErrorCode::NotFound, "no such image");
+        writer.end::<PullResponse>(Response::Error(error)).unwrap();
+    });
+
+    let client = Client::from_stream(client).unwrap();
+    match client.pull("alpine").unwrap().finish() {
+        Err(ClientError::Daemon(error)) => {
+            assert_eq!(error.code, ErrorCode::NotFound);
+            assert_eq!(error.message, "no such image");
+        }
+        other => panic!("unexpected result: {:?}", other.map(|r| r.image)),
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn samples_are_only_accepted_from_stats() {
+    let (client, daemon) = UnixStream::pair().unwrap();
+    let server = serve(daemon, |mut writer, request| {
+        writer.sample(&StatsResponse::default()).unwrap();
+        writer
+            .end(Response::Success(PullResponse {
+                image: request.image,
+            }))
+            .unwrap();
+    });
+
+    let client = Client::from_stream(client).unwrap();
+    match client.pull("alpine").unwrap().finish() {
+        Err(ClientError::Shared(SharedError::UnexpectedMessage { _type, command })) => {
+            assert_eq!((_type, command), (Type::Sample, Command::Pull));
+        }
+        other => panic!("unexpected result: {:?}", other.map(|r| r.image)),
+    }
+    server.join().unwrap();
+}
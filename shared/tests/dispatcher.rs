@@ -0,0 +1,161 @@
+//! Tests for the client-side `Dispatcher`. A fake daemon on the other end of a socket
+//! pair answers requests out of order to make sure frames are routed by request id.
+
+use shared::{
+    dispatcher::Dispatcher,
+    error::SharedError,
+    protocol::{
+        Capabilities, Command, Handshake, Protocol, Response, Type, DEFAULT_MAX_BODY_SIZE,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    requests::{PullRequest, PullResponse},
+};
+use std::{os::unix::net::UnixStream, thread};
+
+/// Answer the client handshake with the local one.
+fn accept_handshake(stream: &mut UnixStream) {
+    answer_handshake(stream, Handshake::local());
+}
+
+/// Answer the client handshake with `handshake`.
+fn answer_handshake(stream: &mut UnixStream, handshake: Handshake) {
+    let frame = Protocol::read_frame(stream, DEFAULT_MAX_BODY_SIZE).unwrap();
+    assert_eq!(frame.header._type, Type::Handshake);
+    let message = Protocol::write_handshake(handshake).unwrap();
+    Protocol::send(stream, &message).unwrap();
+}
+
+/// Answer a pull request, echoing the requested image.
+fn respond(stream: &mut UnixStream, request_id: u32, image: String) {
+    respond_with(stream, PROTOCOL_VERSION, request_id, image);
+}
+
+/// Answer a pull request in protocol version `version`, echoing the requested image.
+fn respond_with(stream: &mut UnixStream, version: u8, request_id: u32, image: String) {
+    let body = Protocol::write_body(version, Response::Success(PullResponse { image })).unwrap();
+    let header = Protocol::write_header(
+        version,
+        Type::Response,
+        Command::Pull,
+        request_id,
+        body.len() as u64,
+    );
+    Protocol::send(stream, &Protocol::write_message(header, body)).unwrap();
+}
+
+fn pull(image: &str) -> PullRequest {
+    PullRequest {
+        image: image.to_string(),
+    }
+}
+
+#[test]
+fn interleaved_responses_are_routed_by_request_id() {
+    let (client, mut daemon) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || {
+        accept_handshake(&mut daemon);
+        let requests = (0..3)
+            .map(|_| Protocol::read_frame(&mut daemon, DEFAULT_MAX_BODY_SIZE).unwrap())
+            .collect::<Vec<_>>();
+        // answer the requests in reverse order.
+        for frame in requests.into_iter().rev() {
+            let request = frame.decode::<PullRequest>().unwrap();
+            respond(&mut daemon, frame.header.request_id, request.image);
+        }
+        daemon
+    });
+
+    let dispatcher = Dispatcher::new(client).unwrap();
+    let calls = ["alpine", "debian", "ubuntu"].map(|image| {
+        (
+            image,
+            dispatcher.request(Command::Pull, pull(image)).unwrap(),
+        )
+    });
+    for (image, call) in calls {
+        let frame = call.recv().unwrap();
+        assert_eq!(frame.header.request_id, call.request_id());
+        match frame.decode::<Response<PullResponse>>().unwrap() {
+            Response::Success(response) => assert_eq!(response.image, image),
+            Response::Error(error) => panic!("unexpected error: {}", error),
+        }
+    }
+    drop(server.join().unwrap());
+}
+
+#[test]
+fn calls_fail_when_the_connection_closes() {
+    let (client, mut daemon) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || {
+        accept_handshake(&mut daemon);
+        Protocol::read_frame(&mut daemon, DEFAULT_MAX_BODY_SIZE).unwrap();
+        // close the connection without answering.
+    });
+
+    let dispatcher = Dispatcher::new(client).unwrap();
+    let call = dispatcher.request(Command::Pull, pull("alpine")).unwrap();
+    server.join().unwrap();
+    assert!(matches!(call.recv(), Err(SharedError::ConnectionClosed)));
+    assert!(matches!(
+        dispatcher.request(Command::Pull, pull("alpine")),
+        Err(SharedError::ConnectionClosed)
+    ));
+}
+
+#[test]
+fn newer_daemons_are_reported_as_incompatible() {
+    let (client, mut daemon) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || {
+        Protocol::read_frame(&mut daemon, DEFAULT_MAX_BODY_SIZE).unwrap();
+        let newer = Handshake {
+            version: PROTOCOL_VERSION + 1,
+            min_version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        let message = Protocol::write_handshake(newer).unwrap();
+        Protocol::send(&mut daemon, &message).unwrap();
+    });
+
+    let newer = PROTOCOL_VERSION + 1;
+    match Dispatcher::new(client) {
+        Err(SharedError::IncompatibleProtocol {
+            remote, remote_min, ..
+        }) => assert_eq!((remote, remote_min), (newer, newer)),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("negotiated with an incompatible daemon"),
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn requests_carry_the_negotiated_version() {
+    let (client, mut daemon) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || {
+        let older = Handshake {
+            version: MIN_PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        answer_handshake(&mut daemon, older);
+        let frame = Protocol::read_frame(&mut daemon, DEFAULT_MAX_BODY_SIZE).unwrap();
+        assert_eq!(frame.header.version, MIN_PROTOCOL_VERSION);
+        let request = frame.decode::<PullRequest>().unwrap();
+        respond_with(
+            &mut daemon,
+            MIN_PROTOCOL_VERSION,
+            frame.header.request_id,
+            request.image,
+        );
+    });
+
+    let dispatcher = Dispatcher::new(client).unwrap();
+    assert_eq!(dispatcher.protocol().version, MIN_PROTOCOL_VERSION);
+    let call = dispatcher.request(Command::Pull, pull("alpine")).unwrap();
+    let frame = call.recv().unwrap();
+    assert_eq!(frame.header.version, MIN_PROTOCOL_VERSION);
+    match frame.decode::<Response<PullResponse>>().unwrap() {
+        Response::Success(response) => assert_eq!(response.image, "alpine"),
+        Response::Error(error) => panic!("unexpected error: {}", error),
+    }
+    server.join().unwrap();
+}
@@ -0,0 +1,161 @@
+//! Property and fuzz tests for the framing layer in `shared::protocol`. Messages are fed
+//! to the reader split at arbitrary points to make sure partial reads are accumulated,
+//! premature EOF is detected and oversized bodies are rejected.
+
+use proptest::prelude::*;
+use shared::{
+    error::SharedError,
+    protocol::{
+        Command, Encoding, Protocol, Type, DEFAULT_MAX_BODY_SIZE, HEADER_SIZE,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    requests::PullRequest,
+};
+use std::io::{self, Read};
+
+/// A reader that hands out its data in chunks of the given sizes. A chunk size of
+/// zero produces an `Interrupted` error instead of data.
+struct ChunkedReader {
+    data: Vec<u8>,
+    chunks: Vec<usize>,
+    position: usize,
+    reads: usize,
+}
+
+impl ChunkedReader {
+    fn new(data: Vec<u8>, chunks: Vec<usize>) -> Self {
+        ChunkedReader {
+            data,
+            chunks,
+            position: 0,
+            reads: 0,
+        }
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let chunk = match self.chunks.is_empty() {
+            true => usize::MAX,
+            false => self.chunks[self.reads % self.chunks.len()],
+        };
+        self.reads += 1;
+        if chunk == 0 {
+            return Err(io::Error::from(io::ErrorKind::Interrupted));
+        }
+        let remaining = self.data.len() - self.position;
+        let size = chunk.min(buf.len()).min(remaining);
+        buf[..size].copy_from_slice(&self.data[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+/// Build a complete request message for a pull.
+fn pull_message(image: &str) -> Vec<u8> {
+    let body = Protocol::write_body(
+        PROTOCOL_VERSION,
+        PullRequest {
+            image: image.to_string(),
+        },
+    )
+    .unwrap();
+    let header = Protocol::write_header(
+        PROTOCOL_VERSION,
+        Type::Request,
+        Command::Pull,
+        7,
+        body.len() as u64,
+    );
+    Protocol::write_message(header, body)
+}
+
+fn image() -> impl Strategy<Value = String> {
+    "\\PC{0,64}"
+}
+
+fn chunks() -> impl Strategy<Value = Vec<usize>> {
+    prop::collection::vec(0usize..=64, 1..16).prop_filter("needs a non-empty chunk", |chunks| {
+        chunks.iter().any(|chunk| *chunk > 0)
+    })
+}
+
+proptest! {
+    #[test]
+    fn frames_survive_arbitrary_split_points(image in image(), chunks in chunks()) {
+        let mut reader = ChunkedReader::new(pull_message(&image), chunks);
+        let frame = Protocol::read_frame(&mut reader, DEFAULT_MAX_BODY_SIZE).unwrap();
+        prop_assert_eq!(frame.header._type, Type::Request);
+        prop_assert_eq!(frame.header.command, Command::Pull);
+        prop_assert_eq!(frame.header.request_id, 7);
+        prop_assert_eq!(frame.header.length as usize, frame.body.len());
+        prop_assert_eq!(frame.decode::<PullRequest>().unwrap().image, image);
+    }
+
+    #[test]
+    fn consecutive_frames_are_not_merged(
+        first in image(),
+        second in image(),
+        chunks in chunks(),
+    ) {
+        let data = [pull_message(&first), pull_message(&second)].concat();
+        let mut reader = ChunkedReader::new(data, chunks);
+        for image in [first, second] {
+            let frame = Protocol::read_frame(&mut reader, DEFAULT_MAX_BODY_SIZE).unwrap();
+            prop_assert_eq!(frame.decode::<PullRequest>().unwrap().image, image);
+        }
+        prop_assert!(matches!(
+            Protocol::read_frame(&mut reader, DEFAULT_MAX_BODY_SIZE),
+            Err(SharedError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn truncated_frames_report_eof(image in image(), cut in any::<prop::sample::Index>(), chunks in chunks()) {
+        let message = pull_message(&image);
+        let cut = cut.index(message.len());
+        let mut reader = ChunkedReader::new(message[..cut].to_vec(), chunks);
+        match Protocol::read_frame(&mut reader, DEFAULT_MAX_BODY_SIZE) {
+            Err(SharedError::ConnectionClosed) => prop_assert_eq!(cut, 0),
+            Err(SharedError::UnexpectedEof { expected, read }) => {
+                prop_assert!(cut > 0);
+                prop_assert!(read < expected);
+            }
+            other => prop_assert!(false, "unexpected result for cut {}: {:?}", cut, other),
+        }
+    }
+
+    #[test]
+    fn oversized_bodies_are_rejected(image in image(), max in 0u64..8) {
+        let message = pull_message(&image);
+        let mut reader = ChunkedReader::new(message, vec![]);
+        match Protocol::read_frame(&mut reader, max) {
+            Err(SharedError::BodyTooLarge { length, max: limit }) => {
+                prop_assert!(length > limit);
+                // nothing past the header is consumed.
+                prop_assert_eq!(reader.position, HEADER_SIZE);
+            }
+            other => prop_assert!(false, "unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(data in prop::collection::vec(any::<u8>(), 0..256), chunks in chunks()) {
+        let mut reader = ChunkedReader::new(data.clone(), chunks);
+        let _ = Protocol::read_frame(&mut reader, 1024);
+        for version in [MIN_PROTOCOL_VERSION, PROTOCOL_VERSION] {
+            let _ = Encoding::of(version).deserialize::<PullRequest>(&data);
+        }
+    }
+}
+
+#[test]
+fn interrupted_reads_are_retried() {
+    let message = pull_message("alpine:latest");
+    let mut reader = ChunkedReader::new(message, vec![0, 1, 0, 7]);
+    let frame = Protocol::read_frame(&mut reader, DEFAULT_MAX_BODY_SIZE).unwrap();
+    assert_eq!(
+        frame.decode::<PullRequest>().unwrap().image,
+        "alpine:latest"
+    );
+}
@@ -0,0 +1,141 @@
+//! Tests for the fixed-width header encoding in `shared::protocol`. The golden bytes
+//! below are the reference for clients written against `architecture/protocol.md`.
+
+use proptest::prelude::*;
+use shared::{
+    error::SharedError,
+    protocol::{
+        Capabilities, Command, Encoding, Flags, Handshake, Header, Protocol, Type, HEADER_SIZE,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+};
+
+#[test]
+#[rustfmt::skip]
+fn header_matches_documented_layout() {
+    let header = Header::new(Type::Request, Command::Pull, 0x01020304, 0x1122334455667788);
+    assert_eq!(
+        header.encode(),
+        [
+            b'J', b'1', b'K', b'B', // magic
+            PROTOCOL_VERSION,       // version
+            0x01,                   // type
+            0x01,                   // command
+            0x00,                   // flags
+            0x01, 0x02, 0x03, 0x04, // request id
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // length
+        ]
+    );
+}
+
+#[test]
+fn header_rejects_bad_magic() {
+    let mut bytes = Header::new(Type::Request, Command::Ps, 1, 0).encode();
+    bytes[0] = b'X';
+    assert!(matches!(
+        Header::decode(&bytes),
+        Err(SharedError::InvalidMagic { .. })
+    ));
+}
+
+#[test]
+fn header_rejects_unsupported_version() {
+    let mut bytes = Header::new(Type::Request, Command::Ps, 1, 0).encode();
+    bytes[4] = PROTOCOL_VERSION + 1;
+    assert!(matches!(
+        Header::decode(&bytes),
+        Err(SharedError::UnsupportedVersion { .. })
+    ));
+}
+
+#[test]
+fn handshakes_of_any_version_are_decoded() {
+    for version in [0, MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1, u8::MAX] {
+        let mut header = Header::new(Type::Handshake, Command::Handshake, 0, 6);
+        header.version = version;
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+    }
+}
+
+#[test]
+fn version_2_bodies_are_serialized_with_rust_fr() {
+    assert_eq!(MIN_PROTOCOL_VERSION, 2);
+    let mut header = Header::new(Type::Request, Command::Pull, 1, 0);
+    header.version = 2;
+    assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+    assert_eq!(Encoding::of_header(&header), Encoding::RustFr);
+    header.version = 3;
+    assert_eq!(Encoding::of_header(&header), Encoding::Json);
+    // handshakes are read the same way whatever their version is.
+    header._type = Type::Handshake;
+    assert_eq!(Encoding::of_header(&header), Encoding::RustFr);
+
+    let remote = Handshake {
+        version: 2,
+        min_version: 2,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    assert_eq!(Handshake::local().negotiate(&remote).unwrap().version, 2);
+}
+
+#[test]
+fn handshakes_are_serialized_with_rust_fr() {
+    let message = Protocol::write_handshake(Handshake::local()).unwrap();
+    let body = &message[HEADER_SIZE..];
+    assert_eq!(
+        body,
+        rust_fr::serializer::to_bytes(&Handshake::local()).unwrap()
+    );
+    let mut reader = body;
+    let header = Header::decode(&message[..HEADER_SIZE].try_into().unwrap()).unwrap();
+    let handshake = Protocol::read_body::<Handshake, _>(&mut reader, &header, 1024).unwrap();
+    assert_eq!(handshake, Handshake::local());
+}
+
+#[test]
+fn header_rejects_unknown_fields() {
+    for (offset, field) in [(5, "type"), (6, "command"), (7, "flags")] {
+        let mut bytes = Header::new(Type::Request, Command::Ps, 1, 0).encode();
+        bytes[offset] = 0xff;
+        match Header::decode(&bytes) {
+            Err(SharedError::InvalidHeaderField { field: name, value }) => {
+                assert_eq!(name, field);
+                assert_eq!(value, 0xff);
+            }
+            other => panic!("unexpected result for {}: {:?}", field, other),
+        }
+    }
+}
+
+fn _type() -> impl Strategy<Value = Type> {
+    prop_oneof![
+        Just(Type::Request),
+        Just(Type::Response),
+        Just(Type::Handshake),
+        Just(Type::Chunk),
+        Just(Type::Progress),
+        Just(Type::End),
+        Just(Type::Sample)
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    (1u8..=13).prop_map(|value| Command::try_from(value).unwrap())
+}
+
+proptest! {
+    #[test]
+    fn header_roundtrips(
+        _type in _type(),
+        command in command(),
+        request_id in any::<u32>(),
+        length in any::<u64>(),
+    ) {
+        let header = Header::new(_type, command, request_id, length);
+        let bytes = header.encode();
+        prop_assert_eq!(bytes.len(), HEADER_SIZE);
+        let decoded = Header::decode(&bytes).unwrap();
+        prop_assert_eq!(decoded, header);
+        prop_assert_eq!(decoded.flags, Flags::NONE);
+    }
+}
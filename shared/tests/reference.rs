@@ -0,0 +1,79 @@
+//! Tests for parsing and normalizing image references.
+
+use shared::reference::Reference;
+
+#[test]
+fn references_are_normalized() {
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    let references = [
+        ("alpine", "docker.io/library/alpine:latest", "alpine:latest"),
+        (
+            "alpine:3.19",
+            "docker.io/library/alpine:3.19",
+            "alpine:3.19",
+        ),
+        (
+            "docker.io/alpine",
+            "docker.io/library/alpine:latest",
+            "alpine:latest",
+        ),
+        (
+            "index.docker.io/team/app:v1",
+            "docker.io/team/app:v1",
+            "team/app:v1",
+        ),
+        (
+            "localhost/app",
+            "localhost/app:latest",
+            "localhost/app:latest",
+        ),
+        (
+            "localhost:5000/team/app:1.2",
+            "localhost:5000/team/app:1.2",
+            "localhost:5000/team/app:1.2",
+        ),
+        (
+            "registry.local/a/b/c__d.e-f",
+            "registry.local/a/b/c__d.e-f:latest",
+            "registry.local/a/b/c__d.e-f:latest",
+        ),
+    ];
+    for (value, normalized, short) in references {
+        let reference = value.parse::<Reference>().unwrap();
+        assert_eq!(reference.to_string(), normalized);
+        assert_eq!(reference.short(), short);
+        assert_eq!(normalized.parse::<Reference>().unwrap(), reference);
+    }
+
+    let reference = format!("alpine@{}", digest).parse::<Reference>().unwrap();
+    assert_eq!(reference.tag, None);
+    assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+    assert_eq!(
+        reference.to_string(),
+        format!("docker.io/library/alpine@{}", digest)
+    );
+    let reference = format!("alpine:3@{}", digest).parse::<Reference>().unwrap();
+    assert_eq!(reference.tag.as_deref(), Some("3"));
+}
+
+#[test]
+fn malformed_references_are_rejected() {
+    for value in [
+        "",
+        "Alpine",
+        "alpine:",
+        "alpine:.hidden",
+        "alpine@sha256:1234",
+        "alpine@md5:abcd",
+        "team//app",
+        "-app",
+        "app-",
+        "a...b",
+        "a___b",
+        "http://registry/app",
+    ] {
+        assert!(value.parse::<Reference>().is_err(), "{}", value);
+    }
+    let long = format!("{}:1", "a".repeat(300));
+    assert!(long.parse::<Reference>().is_err());
+}
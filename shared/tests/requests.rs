@@ -0,0 +1,88 @@
+//! Tests for the request types shared by the cli and the daemon.
+
+use shared::requests::{Filter, IdMapping, ImageFilter, PsRequest, SortKey};
+
+#[test]
+fn id_mapping_parses_container_host_and_size() {
+    let mapping = "0:100000:65536".parse::<IdMapping>().unwrap();
+    assert_eq!(
+        mapping,
+        IdMapping {
+            container_id: 0,
+            host_id: 100000,
+            size: 65536,
+        }
+    );
+    assert_eq!(mapping.to_string(), "0:100000:65536");
+}
+
+#[test]
+fn id_mapping_rejects_malformed_values() {
+    for value in ["", "0:1", "0:1:2:3", "a:1:2", "-1:1:2", "0:1:4294967296"] {
+        assert!(value.parse::<IdMapping>().is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn filters_parse_kind_and_value() {
+    let label = |key: &str, value: Option<&str>| Filter::Label {
+        key: key.to_string(),
+        value: value.map(str::to_string),
+    };
+    let filters = [
+        ("status=exited", Filter::Status("exited".to_string())),
+        ("label=tier", label("tier", None)),
+        ("label=tier=front", label("tier", Some("front"))),
+        ("label=empty=", label("empty", Some(""))),
+        ("image=alpine", Filter::Image("alpine".to_string())),
+        ("name=web", Filter::Name("web".to_string())),
+    ];
+    for (value, filter) in filters {
+        assert_eq!(value.parse::<Filter>().unwrap(), filter);
+        assert_eq!(filter.to_string(), value);
+    }
+}
+
+#[test]
+fn filters_reject_unknown_kinds() {
+    for value in ["", "status", "color=red"] {
+        assert!(value.parse::<Filter>().is_err(), "{}", value);
+    }
+    assert_eq!("name".parse::<SortKey>().unwrap(), SortKey::Name);
+    assert!("size".parse::<SortKey>().is_err());
+}
+
+#[test]
+fn image_filters_parse_kind_and_value() {
+    let filters = [
+        ("dangling=true", ImageFilter::Dangling(true)),
+        ("dangling=false", ImageFilter::Dangling(false)),
+        (
+            "reference=library/*:3.?",
+            ImageFilter::Reference("library/*:3.?".to_string()),
+        ),
+        (
+            "label=team=build",
+            ImageFilter::Label {
+                key: "team".to_string(),
+                value: Some("build".to_string()),
+            },
+        ),
+    ];
+    for (value, filter) in filters {
+        assert_eq!(value.parse::<ImageFilter>().unwrap(), filter);
+        assert_eq!(filter.to_string(), value);
+    }
+    for value in ["dangling", "dangling=maybe", "status=exited"] {
+        assert!(value.parse::<ImageFilter>().is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn ps_requests_default_omitted_fields() {
+    let request = serde_json::from_str::<PsRequest>("{}").unwrap();
+    assert!(!request.all);
+    assert!(request.filters.is_empty());
+    assert!(request.sort.is_none());
+    assert!(!request.reverse);
+}
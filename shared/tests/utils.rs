@@ -0,0 +1,61 @@
+//! Tests for the directories the daemon keeps its socket and pid file in.
+
+use nix::unistd::{chown, geteuid, Uid};
+use shared::{error::SharedError, utils::ensure_private_directory};
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+};
+
+/// Whether `ensure_private_directory` refuses the directory.
+fn refused(directory: &std::path::Path) -> bool {
+    matches!(
+        ensure_private_directory(directory),
+        Err(SharedError::InsecureDirectory { .. })
+    )
+}
+
+#[test]
+fn missing_directories_are_created_private() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = dir.path().join("run/j1407b");
+    ensure_private_directory(&run).unwrap();
+    assert_eq!(fs::metadata(&run).unwrap().mode() & 0o777, 0o700);
+
+    // an existing private directory is fine.
+    ensure_private_directory(&run).unwrap();
+}
+
+#[test]
+fn directories_others_may_write_to_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    for mode in [0o720, 0o702, 0o777] {
+        fs::set_permissions(dir.path(), Permissions::from_mode(mode)).unwrap();
+        assert!(refused(dir.path()), "mode {:o}", mode);
+    }
+
+    // read access for others does not let them replace the socket.
+    fs::set_permissions(dir.path(), Permissions::from_mode(0o755)).unwrap();
+    ensure_private_directory(dir.path()).unwrap();
+}
+
+#[test]
+fn symlinks_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("target");
+    fs::create_dir(&target).unwrap();
+    let link = dir.path().join("link");
+    symlink(&target, &link).unwrap();
+    assert!(refused(&link));
+}
+
+#[test]
+fn directories_of_other_users_are_refused() {
+    if !geteuid().is_root() {
+        eprintln!("skipping, changing the owner of a directory requires root");
+        return;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    chown(dir.path(), Some(Uid::from_raw(65534)), None).unwrap();
+    assert!(refused(dir.path()));
+}